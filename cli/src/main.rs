@@ -0,0 +1,261 @@
+use std::{error::Error, fs, path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use epub_cfi::{
+    epub::{Epub, Resolved},
+    resolve::{Document, Location},
+    syntax::Fragment,
+    validate::{validate, Outcome, Validation},
+};
+use serde_json::{json, Value};
+
+/// Inspect EPUB canonical fragment identifiers.
+#[derive(Parser)]
+#[command(name = "epubcfi", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the file, node and surrounding text that a CFI points to.
+    Resolve {
+        /// The `.epub` file.
+        epub: PathBuf,
+        /// The CFI, e.g. `epubcfi(/6/4!/4/10/3:12)`.
+        cfi: String,
+        /// Characters of text to show on either side of the location.
+        #[arg(long, default_value_t = 40)]
+        context: usize,
+    },
+    /// Print the text covered by a range CFI.
+    Extract {
+        /// The `.epub` file.
+        epub: PathBuf,
+        /// The range CFI, e.g. `epubcfi(/6/4!/4/10,/3:12,/3:40)`.
+        cfi: String,
+    },
+    /// Print the CFI of the first occurrence of some text in a content document.
+    Generate {
+        /// The `.epub` file.
+        epub: PathBuf,
+        /// The content document, relative to the package document, e.g. `chapter3.xhtml`.
+        #[arg(long)]
+        href: String,
+        /// The text to search for.
+        #[arg(long)]
+        find: String,
+        /// Print a point CFI at the start of the text instead of a range covering it.
+        #[arg(long)]
+        point: bool,
+    },
+    /// Check every CFI in a JSONL file of annotations and report what each one points to.
+    Validate {
+        /// The `.epub` file.
+        epub: PathBuf,
+        /// The JSONL file. Each line is either a JSON string or an object containing the CFI.
+        annotations: PathBuf,
+        /// The name of the field holding the CFI when lines are objects.
+        #[arg(long, default_value = "cfi")]
+        field: String,
+        /// Print the report as JSONL, ending with a summary object.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("epubcfi: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    match cli.command {
+        Command::Resolve { epub, cfi, context } => {
+            let resolved = resolve(&epub, &cfi)?;
+            println!("file:  {}", resolved.path);
+            println!("start: {}", describe(&resolved.start));
+            if let Some(end) = &resolved.end {
+                println!("end:   {}", describe(end));
+            }
+            println!(
+                "text:  {}[{}]{}",
+                collapse(resolved.text_before(context)),
+                collapse(resolved.covered_text()),
+                collapse(resolved.text_after(context)),
+            );
+        }
+        Command::Extract { epub, cfi } => {
+            let resolved = resolve(&epub, &cfi)?;
+            if resolved.end.is_none() {
+                return Err("extract requires a range CFI".into());
+            }
+            println!("{}", resolved.covered_text());
+        }
+        Command::Generate {
+            epub,
+            href,
+            find,
+            point,
+        } => {
+            let mut epub = Epub::open(epub)?;
+            let source = epub.read_href(&href)?;
+            let document = Document::parse(&source)?;
+            // search the body only, not the title in the head
+            let (body, end) = document
+                .body_span()
+                .unwrap_or((0, document.text().chars().count()));
+            let text = document.text_between(body, end);
+            let start = match text.find(&find) {
+                Some(byte) => body + text[..byte].chars().count(),
+                None => return Err(format!("{:?} not found in {}", find, href).into()),
+            };
+            let end = (!point).then(|| start + find.chars().count());
+            println!("{}", epub.fragment(&href, start, end)?);
+        }
+        Command::Validate {
+            epub,
+            annotations,
+            field,
+            json,
+        } => {
+            let mut lines = vec![];
+            let mut cfis = vec![];
+            for (index, line) in fs::read_to_string(annotations)?.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let value: Value = match serde_json::from_str(line) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        eprintln!("epubcfi: skipping line {}: {}", index + 1, e);
+                        continue;
+                    }
+                };
+                match value.as_str().or_else(|| value[&field].as_str()) {
+                    Some(cfi) => {
+                        lines.push(index + 1);
+                        cfis.push(cfi.to_string());
+                    }
+                    None => eprintln!("epubcfi: skipping line {}: no {:?} field", index + 1, field),
+                }
+            }
+            let report = validate(&mut Epub::open(epub)?, &cfis);
+            for (line, validation) in lines.iter().zip(&report.validations) {
+                if json {
+                    println!("{}", validation_json(*line, validation));
+                } else {
+                    println!("line {}: {}", line, describe_validation(validation));
+                }
+            }
+            let summary = report.summary();
+            if json {
+                println!(
+                    "{}",
+                    json!({ "summary": {
+                        "total": summary.total,
+                        "valid": summary.valid,
+                        "mismatched": summary.mismatched,
+                        "unresolved": summary.unresolved,
+                        "invalid": summary.invalid,
+                    }})
+                );
+            } else {
+                println!(
+                    "total {}: {} valid, {} mismatched, {} unresolved, {} invalid",
+                    summary.total,
+                    summary.valid,
+                    summary.mismatched,
+                    summary.unresolved,
+                    summary.invalid
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn resolve(epub: &PathBuf, cfi: &str) -> Result<Resolved, Box<dyn Error>> {
+    let fragment: Fragment = cfi.trim().parse()?;
+    Ok(Epub::open(epub)?.resolve(&fragment)?)
+}
+
+fn describe_validation(validation: &Validation) -> String {
+    match &validation.outcome {
+        Outcome::Invalid(e) => format!("invalid: {}", e),
+        Outcome::Unresolved(e) => format!("unresolved: {}", e),
+        Outcome::Resolved { text, mismatches } if mismatches.is_empty() => {
+            format!("valid: {:?}", collapse(text))
+        }
+        Outcome::Resolved { text, mismatches } => {
+            let mismatches: Vec<_> = mismatches.iter().map(|m| m.to_string()).collect();
+            format!(
+                "mismatched: {}: {:?}",
+                mismatches.join("; "),
+                collapse(text)
+            )
+        }
+    }
+}
+
+fn validation_json(line: usize, validation: &Validation) -> Value {
+    let mut value = json!({ "line": line, "cfi": validation.cfi });
+    match &validation.outcome {
+        Outcome::Invalid(e) => {
+            value["status"] = "invalid".into();
+            value["error"] = e.to_string().into();
+        }
+        Outcome::Unresolved(e) => {
+            value["status"] = "unresolved".into();
+            value["error"] = e.to_string().into();
+        }
+        Outcome::Resolved { text, mismatches } => {
+            value["status"] = if mismatches.is_empty() {
+                "valid"
+            } else {
+                "mismatched"
+            }
+            .into();
+            value["text"] = text.as_str().into();
+            value["mismatches"] = mismatches
+                .iter()
+                .map(|m| json!({ "node": m.node_path, "expected": m.expected, "actual": m.actual }))
+                .collect();
+        }
+    }
+    value
+}
+
+fn describe(location: &Location) -> String {
+    match location.offset {
+        Some(offset) => format!("{} offset {}", location.node_path, offset),
+        None => location.node_path.clone(),
+    }
+}
+
+/// Collapses runs of whitespace, including newlines, into single spaces.
+fn collapse(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut whitespace = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            whitespace = true;
+            continue;
+        }
+        if whitespace {
+            collapsed.push(' ');
+            whitespace = false;
+        }
+        collapsed.push(c);
+    }
+    if whitespace {
+        collapsed.push(' ');
+    }
+    collapsed
+}
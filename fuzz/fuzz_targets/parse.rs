@@ -0,0 +1,18 @@
+//! Parsing arbitrary input must return a result, never panic. A lenient parse must produce a
+//! fragment that serializes to a conformant CFI.
+
+#![no_main]
+
+use epub_cfi::{syntax::Fragment, ParseOptions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(input) = std::str::from_utf8(data) {
+        let _ = Fragment::parse(input);
+        let options = ParseOptions { lenient: true };
+        if let Ok((fragment, _)) = Fragment::parse_with_options(input, &options) {
+            let serialized = fragment.to_string();
+            assert_eq!(Fragment::parse(&serialized).unwrap(), fragment);
+        }
+    }
+});
@@ -0,0 +1,54 @@
+//! Resolving any parsed CFI against a fixed content document must return a result, never panic.
+
+#![no_main]
+
+use epub_cfi::{
+    resolve::{Document, Side},
+    syntax::{Fragment, Path, Step},
+};
+use libfuzzer_sys::fuzz_target;
+
+const SAMPLE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>Chapter 1</title></head>
+<body id="body01">
+<h1>Chapter 1</h1>
+<p id="para01">It was a <em>dark</em> and stormy night.</p>
+<p>The rain fell in torrents<br/>except at occasional intervals.</p>
+<img src="figure.png" alt=""/>
+<p>Ünïcödé and emoji 📚 text.</p>
+</body>
+</html>"#;
+
+fuzz_target!(|data: &[u8]| {
+    let document = Document::parse(SAMPLE).unwrap();
+    let text_length = document.text().chars().count();
+    let _ = document.locate(data.len() % (text_length + 2), Side::Start);
+    let _ = document.locate(data.len() % (text_length + 2), Side::End);
+
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(fragment) = Fragment::parse(input) else {
+        return;
+    };
+    for path in [fragment.start(), fragment.end()].into_iter().flatten() {
+        let mut steps = vec![];
+        collect_steps(&path, &mut steps);
+        let _ = document.resolve(steps.iter().copied(), path.offset());
+        let _ = document.verify(steps.iter().copied());
+    }
+});
+
+/// Collects the steps of a path, continuing across redirections.
+fn collect_steps<'a>(path: &'a Path<'a>, steps: &mut Vec<&'a Step<'a>>) {
+    steps.push(&path.step);
+    steps.extend(&path.local_path.steps);
+    if let Some(path) = path
+        .local_path
+        .redirected_path()
+        .and_then(|redirected_path| redirected_path.path())
+    {
+        collect_steps(path, steps);
+    }
+}
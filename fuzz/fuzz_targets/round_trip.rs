@@ -0,0 +1,21 @@
+//! Every parsed fragment must serialize to a CFI that parses back to an equal fragment, and
+//! survive the binary encoding unchanged.
+
+#![no_main]
+
+use epub_cfi::{binary, syntax::Fragment};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(fragment) = Fragment::parse(input) else {
+        return;
+    };
+    let serialized = fragment.to_string();
+    let reparsed = Fragment::parse(&serialized)
+        .unwrap_or_else(|e| panic!("{:?} serialized to {:?}: {}", input, serialized, e));
+    assert_eq!(fragment, reparsed, "{:?} serialized to {:?}", input, serialized);
+    assert_eq!(binary::decode(&binary::encode(&fragment)), Ok(fragment));
+});
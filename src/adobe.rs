@@ -0,0 +1,271 @@
+//! Conversion between CFIs and the RMSDK points of Adobe Digital Editions bookmarks.
+//!
+//! Adobe Digital Editions stores bookmarks and highlights in `.annot` files, with positions such
+//! as `<fragment start="OEBPS/chapter1.xhtml#point(/1/4/2/3:12)"/>`. A point names a content
+//! document by its path within the container, and walks that document with steps indexed as in
+//! a CFI, except that the first step, `/1`, selects the document element. The final `:12` is a
+//! character offset.
+
+use std::{
+    fmt,
+    io::{Read, Seek},
+    str::FromStr,
+};
+
+use crate::{
+    epub::{self, Epub},
+    syntax::{Fragment, Path, Step},
+};
+
+/// A position in a content document, e.g. `OEBPS/chapter1.xhtml#point(/1/4/2/3:12)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Point {
+    /// The path of the content document within the container.
+    pub path: String,
+    /// The steps from the document element, which is `/1` and not included.
+    pub steps: Vec<u32>,
+    pub offset: Option<u32>,
+}
+
+impl Point {
+    pub fn parse(input: &str) -> Result<Self, Error> {
+        let syntax = || Error::Syntax(input.to_string());
+        let (path, point) = input.split_once('#').ok_or_else(syntax)?;
+        let point = point
+            .strip_prefix("point(")
+            .and_then(|point| point.strip_suffix(')'))
+            .ok_or_else(syntax)?;
+        let (point, offset) = match point.split_once(':') {
+            Some((point, offset)) => (point, Some(number(offset).ok_or_else(syntax)?)),
+            None => (point, None),
+        };
+        let mut steps = point
+            .strip_prefix('/')
+            .ok_or_else(syntax)?
+            .split('/')
+            .map(number);
+        if steps.next() != Some(Some(1)) {
+            return Err(syntax());
+        }
+        let steps = steps
+            .collect::<Option<Vec<_>>>()
+            .filter(|steps| steps.iter().all(|&step| step > 0))
+            .ok_or_else(syntax)?;
+        Ok(Self {
+            path: path.to_string(),
+            steps,
+            offset,
+        })
+    }
+}
+
+impl FromStr for Point {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Point::parse(s)
+    }
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#point(/1", self.path)?;
+        for step in &self.steps {
+            write!(f, "/{}", step)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, ":{}", offset)?;
+        }
+        write!(f, ")")
+    }
+}
+
+fn number(s: &str) -> Option<u32> {
+    (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+        .then(|| s.parse().ok())
+        .flatten()
+}
+
+/// Converts a fragment to the RMSDK point of its start.
+pub fn to_point<R: Read + Seek>(epub: &mut Epub<R>, fragment: &Fragment) -> Result<Point, Error> {
+    let (start, _) = epub::endpoints(fragment)?;
+    path_to_point(epub, &start)
+}
+
+/// Converts a fragment to the RMSDK points of its start and end, as the `start` and `end` of an
+/// Adobe Digital Editions highlight. Both are the same for a point.
+pub fn to_point_range<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    fragment: &Fragment,
+) -> Result<(Point, Point), Error> {
+    let (start, end) = epub::endpoints(fragment)?;
+    Ok((path_to_point(epub, &start)?, path_to_point(epub, &end)?))
+}
+
+/// Converts an RMSDK point to a CFI.
+pub fn from_point<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    point: &Point,
+) -> Result<Fragment<'static>, Error> {
+    Ok(Fragment::new(point_to_path(epub, point)?))
+}
+
+/// Converts the RMSDK points of the start and end of a highlight to a CFI range.
+pub fn from_point_range<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    start: &Point,
+    end: &Point,
+) -> Result<Fragment<'static>, Error> {
+    let start = point_to_path(epub, start)?;
+    let end = point_to_path(epub, end)?;
+    Ok(epub.range(&start, &end)?)
+}
+
+fn path_to_point<R: Read + Seek>(epub: &mut Epub<R>, path: &Path) -> Result<Point, Error> {
+    let resolved = epub.resolve(&Fragment::new(path.clone()))?;
+    let (steps, offset) = epub::content_point(path)?;
+    Ok(Point {
+        path: resolved.path,
+        steps: steps.iter().map(|step| step.size).collect(),
+        offset,
+    })
+}
+
+fn point_to_path<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    point: &Point,
+) -> Result<Path<'static>, Error> {
+    let href = epub
+        .href_for_path(&point.path)
+        .ok_or_else(|| epub::Error::NotInSpine(point.path.clone()))?;
+    let steps = point
+        .steps
+        .iter()
+        .map(|&size| Step::new(size, None))
+        .collect();
+    let path = epub.content_path(&href, steps, point.offset)?;
+    // check that the point selects a node of the content document
+    epub.resolve(&Fragment::new(path.clone()))?;
+    Ok(path)
+}
+
+/// Errors that can occur when converting between CFIs and RMSDK points.
+#[derive(Debug)]
+pub enum Error {
+    Epub(epub::Error),
+    /// The point is not of the form `path#point(/1/...)`.
+    Syntax(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Epub(e) => write!(f, "{}", e),
+            Error::Syntax(point) => write!(f, "invalid RMSDK point: {}", point),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Epub(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<epub::Error> for Error {
+    fn from(e: epub::Error) -> Self {
+        Error::Epub(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::tests::sample;
+
+    #[test]
+    fn test_parse() {
+        let point: Point = "OEBPS/chapter1.xhtml#point(/1/4/2/3:12)".parse().unwrap();
+        assert_eq!(
+            point,
+            Point {
+                path: "OEBPS/chapter1.xhtml".to_string(),
+                steps: vec![4, 2, 3],
+                offset: Some(12),
+            }
+        );
+        assert_eq!(point.to_string(), "OEBPS/chapter1.xhtml#point(/1/4/2/3:12)");
+        assert_eq!(
+            Point::parse("c.xhtml#point(/1/4)").unwrap().to_string(),
+            "c.xhtml#point(/1/4)"
+        );
+        for invalid in [
+            "OEBPS/chapter1.xhtml",
+            "c.xhtml#point(/4/2)",
+            "c.xhtml#point(/1/4/0)",
+            "c.xhtml#point(/1/4:)",
+            "c.xhtml#point(1/4)",
+            "c.xhtml#epubcfi(/1/4)",
+        ] {
+            assert!(
+                matches!(Point::parse(invalid), Err(Error::Syntax(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut epub = sample();
+        for (point, cfi) in [
+            (
+                "OEBPS/text/chapter02.xhtml#point(/1/4/4/1:4)",
+                "epubcfi(/6/4!/4/4/1:4)",
+            ),
+            (
+                "OEBPS/text/chapter01.xhtml#point(/1/4/2)",
+                "epubcfi(/6/2!/4/2)",
+            ),
+        ] {
+            let fragment = from_point(&mut epub, &point.parse().unwrap()).unwrap();
+            assert_eq!(fragment.to_string(), cfi);
+            assert_eq!(to_point(&mut epub, &fragment).unwrap().to_string(), point);
+        }
+
+        let start = "OEBPS/text/chapter02.xhtml#point(/1/4/4/1:4)"
+            .parse()
+            .unwrap();
+        let end = "OEBPS/text/chapter02.xhtml#point(/1/4/4/2/1:3)"
+            .parse()
+            .unwrap();
+        let fragment = from_point_range(&mut epub, &start, &end).unwrap();
+        assert_eq!(fragment.to_string(), "epubcfi(/6/4!/4/4,/1:4,/2/1:3)");
+        assert_eq!(to_point_range(&mut epub, &fragment).unwrap(), (start, end));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut epub = sample();
+        let from = |epub: &mut Epub<_>, point: &str| from_point(epub, &point.parse().unwrap());
+        assert!(matches!(
+            from(&mut epub, "OEBPS/text/missing.xhtml#point(/1/4)"),
+            Err(Error::Epub(epub::Error::NotInSpine(_)))
+        ));
+        assert!(matches!(
+            from(&mut epub, "OEBPS/text/chapter01.xhtml#point(/1/4/8)"),
+            Err(Error::Epub(epub::Error::Resolve(_)))
+        ));
+        assert!(matches!(
+            from_point_range(
+                &mut epub,
+                &"OEBPS/text/chapter01.xhtml#point(/1/4/2)".parse().unwrap(),
+                &"OEBPS/text/chapter02.xhtml#point(/1/4/2)".parse().unwrap(),
+            ),
+            Err(Error::Epub(epub::Error::RangeAcrossDocuments))
+        ));
+    }
+}
@@ -0,0 +1,438 @@
+//! Conversion between CFIs and the selectors of the W3C Web Annotation data model.
+//!
+//! An annotation's [`Target`] names a resource and describes a segment of it with one or more
+//! selectors, each of which should select the same segment. [`to_target`] describes a fragment
+//! with a `FragmentSelector` holding the CFI, a `TextQuoteSelector` and a
+//! `TextPositionSelector`, as annotation stores such as Hypothesis expect. [`from_target`] reads
+//! a fragment back from whichever selectors still match the publication.
+//!
+//! With the `serde` feature, the types serialize to and from the Web Annotation JSON-LD form,
+//! e.g. `{"type": "TextPositionSelector", "start": 412, "end": 795}`.
+
+use std::{
+    fmt,
+    io::{Read, Seek},
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    epub::{self, Epub},
+    progress::measured,
+    resolve::{char_slice, find_quote, Document},
+    syntax::Fragment,
+};
+
+/// The `conformsTo` of a `FragmentSelector` holding an EPUB CFI.
+pub const CFI_SPECIFICATION: &str = "http://www.idpf.org/epub/linking/cfi/epub-cfi.html";
+
+/// Characters of text quoted on either side of the selected text in a `TextQuoteSelector`.
+const QUOTE_CONTEXT: usize = 32;
+
+/// The target of an annotation: a resource and the selectors of a segment of it.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Target {
+    /// The path of the content document within the container, or a URL ending with it.
+    pub source: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub selector: Vec<Selector>,
+}
+
+/// A selector of a segment of a resource.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(tag = "type", rename_all_fields = "camelCase")
+)]
+pub enum Selector {
+    /// A fragment identifier, e.g. a CFI when `conforms_to` is [`CFI_SPECIFICATION`].
+    FragmentSelector {
+        value: String,
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        conforms_to: Option<String>,
+    },
+    /// The selected text, with some of the text before and after it to tell repeated text apart.
+    TextQuoteSelector {
+        exact: String,
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        prefix: Option<String>,
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        suffix: Option<String>,
+    },
+    /// The character positions of the start and end of the selected text, counted from the start
+    /// of the text of the body of the resource.
+    TextPositionSelector { start: usize, end: usize },
+    /// The segment from the start of one selection to the end of another.
+    RangeSelector {
+        start_selector: Box<Selector>,
+        end_selector: Box<Selector>,
+    },
+}
+
+/// Describes a fragment with a CFI, a quote and a position selector. The quote selector is left
+/// out for a point, which covers no text.
+pub fn to_target<R: Read + Seek>(epub: &mut Epub<R>, fragment: &Fragment) -> Result<Target, Error> {
+    let resolved = epub.resolve(fragment)?;
+    let source = epub.read_href(&resolved.href)?;
+    let (body, body_end) = measured(&Document::parse(&source).map_err(epub::Error::from)?);
+    let start = resolved.start.position.clamp(body, body_end);
+    let end = resolved.end.as_ref().unwrap_or(&resolved.start).position;
+    let end = end.clamp(start, body_end);
+
+    let mut selector = vec![Selector::FragmentSelector {
+        value: fragment.to_string(),
+        conforms_to: Some(CFI_SPECIFICATION.to_string()),
+    }];
+    if end > start {
+        let text = &resolved.text;
+        let quote = |start, end| {
+            let text = char_slice(text, start, end);
+            (!text.is_empty()).then(|| text.to_string())
+        };
+        selector.push(Selector::TextQuoteSelector {
+            exact: char_slice(text, start, end).to_string(),
+            prefix: quote(start.saturating_sub(QUOTE_CONTEXT).max(body), start),
+            suffix: quote(end, (end + QUOTE_CONTEXT).min(body_end)),
+        });
+    }
+    selector.push(Selector::TextPositionSelector {
+        start: start - body,
+        end: end - body,
+    });
+    Ok(Target {
+        source: resolved.path.clone(),
+        selector,
+    })
+}
+
+/// Reads a fragment from the selectors of a target. The first of these that matches the
+/// publication is used:
+///
+/// 1. a `FragmentSelector` holding a CFI that resolves;
+/// 2. a `RangeSelector` whose start and end selectors match;
+/// 3. a `TextPositionSelector`, unless it does not select the text of a `TextQuoteSelector`;
+/// 4. a `TextQuoteSelector`, at the occurrence that best matches its prefix and suffix.
+pub fn from_target<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    target: &Target,
+) -> Result<Fragment<'static>, Error> {
+    for selector in &target.selector {
+        if let Some(fragment) = cfi(selector) {
+            if epub.resolve(&fragment).is_ok() {
+                return Ok(fragment);
+            }
+        }
+    }
+
+    let href = epub
+        .href_for_path(&target.source)
+        .ok_or_else(|| epub::Error::NotInSpine(target.source.clone()))?;
+    let source = epub.read_href(&href)?;
+    let document = Document::parse(&source).map_err(epub::Error::from)?;
+    // selectors select text in the body, not the title in the head
+    let (body, body_end) = measured(&document);
+    let text = char_slice(document.text(), body, body_end);
+    let quote = target.selector.iter().find_map(|selector| match selector {
+        Selector::TextQuoteSelector {
+            exact,
+            prefix,
+            suffix,
+        } => Some((exact, prefix, suffix)),
+        _ => None,
+    });
+
+    let mut positions = None;
+    for selector in &target.selector {
+        positions = match selector {
+            Selector::RangeSelector {
+                start_selector,
+                end_selector,
+            } => {
+                let start = position(epub, text, body, start_selector);
+                let end = position(epub, text, body, end_selector);
+                start.zip(end).map(|((start, _), (_, end))| (start, end))
+            }
+            Selector::TextPositionSelector { start, end } => {
+                let length = text.chars().count();
+                let valid = start <= end && *end <= length;
+                let quoted = quote
+                    .is_none_or(|(exact, _, _)| char_slice(text, *start, *end) == exact.as_str());
+                (valid && quoted).then_some((*start, *end))
+            }
+            _ => None,
+        };
+        if positions.is_some() {
+            break;
+        }
+    }
+    let (start, end) = positions
+        .or_else(|| {
+            let (exact, prefix, suffix) = quote?;
+            let prefix = prefix.as_deref().unwrap_or_default();
+            let suffix = suffix.as_deref().unwrap_or_default();
+            find_quote(text, exact, prefix, suffix)
+        })
+        .ok_or(Error::NoMatch)?;
+    let end = (end > start).then_some(body + end);
+    Ok(epub.fragment(&href, body + start, end)?)
+}
+
+/// The fragment of a `FragmentSelector` holding a CFI.
+fn cfi(selector: &Selector) -> Option<Fragment<'static>> {
+    match selector {
+        Selector::FragmentSelector { value, conforms_to }
+            if conforms_to
+                .as_deref()
+                .is_none_or(|c| c == CFI_SPECIFICATION) =>
+        {
+            value.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+/// The character positions in the body `text` of the segment selected by the start or end
+/// selector of a `RangeSelector`. The body starts at position `body` of the document's text.
+fn position<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    text: &str,
+    body: usize,
+    selector: &Selector,
+) -> Option<(usize, usize)> {
+    match selector {
+        Selector::FragmentSelector { .. } => {
+            let resolved = epub.resolve(&cfi(selector)?).ok()?;
+            let end = resolved.end.as_ref().unwrap_or(&resolved.start);
+            let relative = |position: usize| position.saturating_sub(body);
+            Some((relative(resolved.start.position), relative(end.position)))
+        }
+        Selector::TextPositionSelector { start, end } => Some((*start, *end)),
+        Selector::TextQuoteSelector {
+            exact,
+            prefix,
+            suffix,
+        } => find_quote(
+            text,
+            exact,
+            prefix.as_deref().unwrap_or_default(),
+            suffix.as_deref().unwrap_or_default(),
+        ),
+        Selector::RangeSelector { .. } => None,
+    }
+}
+
+/// Errors that can occur when converting between CFIs and annotation targets.
+#[derive(Debug)]
+pub enum Error {
+    Epub(epub::Error),
+    /// None of the target's selectors match the publication.
+    NoMatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Epub(e) => write!(f, "{}", e),
+            Error::NoMatch => write!(f, "no selector matches the publication"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Epub(e) => Some(e),
+            Error::NoMatch => None,
+        }
+    }
+}
+
+impl From<epub::Error> for Error {
+    fn from(e: epub::Error) -> Self {
+        Error::Epub(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::tests::sample;
+
+    const SOURCE: &str = "OEBPS/text/chapter02.xhtml";
+
+    fn target(selector: Vec<Selector>) -> Target {
+        Target {
+            source: SOURCE.to_string(),
+            selector,
+        }
+    }
+
+    fn covered(epub: &mut Epub<impl Read + Seek>, target: &Target) -> String {
+        let fragment = from_target(epub, target).unwrap();
+        epub.resolve(&fragment).unwrap().covered_text().to_string()
+    }
+
+    #[test]
+    fn test_to_target() {
+        let mut epub = sample();
+        let fragment = "epubcfi(/6/4!/4/4/2/1,:0,:8)".parse().unwrap();
+        let target = to_target(&mut epub, &fragment).unwrap();
+        assert_eq!(target.source, SOURCE);
+        assert_eq!(
+            target.selector[0],
+            Selector::FragmentSelector {
+                value: "epubcfi(/6/4!/4/4/2/1,:0,:8)".to_string(),
+                conforms_to: Some(CFI_SPECIFICATION.to_string()),
+            }
+        );
+        let Selector::TextQuoteSelector {
+            exact,
+            prefix,
+            suffix,
+        } = &target.selector[1]
+        else {
+            panic!("expected a quote selector");
+        };
+        assert_eq!(exact, "torrents");
+        assert!(prefix.as_ref().unwrap().ends_with("The rain fell in "));
+        assert!(suffix.as_ref().unwrap().starts_with(" all night."));
+        let Selector::TextPositionSelector { start, end } = target.selector[2] else {
+            panic!("expected a position selector");
+        };
+        assert_eq!(end - start, "torrents".len());
+        assert_eq!(from_target(&mut epub, &target).unwrap(), fragment);
+    }
+
+    #[test]
+    fn test_from_target_fallbacks() {
+        let mut epub = sample();
+        let fragment = "epubcfi(/6/4!/4/4/2/1,:0,:8)".parse().unwrap();
+        let Target { selector, .. } = to_target(&mut epub, &fragment).unwrap();
+        let (quote, position) = (selector[1].clone(), selector[2].clone());
+        let stale = Selector::FragmentSelector {
+            value: "epubcfi(/6/4!/4/40/1:0)".to_string(),
+            conforms_to: Some(CFI_SPECIFICATION.to_string()),
+        };
+
+        // a CFI that no longer resolves falls back to the position
+        let fallback = target(vec![stale.clone(), quote.clone(), position.clone()]);
+        assert_eq!(from_target(&mut epub, &fallback).unwrap(), fragment);
+
+        // a position that no longer selects the quote falls back to the quote
+        let moved = Selector::TextPositionSelector { start: 0, end: 8 };
+        assert_eq!(
+            covered(
+                &mut epub,
+                &target(vec![stale, moved.clone(), quote.clone()])
+            ),
+            "torrents"
+        );
+        assert_eq!(covered(&mut epub, &target(vec![moved])).len(), 8);
+
+        let quote = |exact: &str, suffix: &str| Selector::TextQuoteSelector {
+            exact: exact.to_string(),
+            prefix: None,
+            suffix: Some(suffix.to_string()),
+        };
+        let range = Selector::RangeSelector {
+            start_selector: Box::new(quote("rain", " fell")),
+            end_selector: Box::new(quote("all", " night")),
+        };
+        assert_eq!(
+            covered(&mut epub, &target(vec![range])),
+            "rain fell in torrents all"
+        );
+
+        assert!(matches!(
+            from_target(&mut epub, &target(vec![quote("snow", "")])),
+            Err(Error::NoMatch)
+        ));
+        let missing = Target {
+            source: "OEBPS/text/missing.xhtml".to_string(),
+            selector: vec![],
+        };
+        assert!(matches!(
+            from_target(&mut epub, &missing),
+            Err(Error::Epub(epub::Error::NotInSpine(_)))
+        ));
+    }
+
+    #[test]
+    fn test_body_text() {
+        // "Chapter 2" is both the title in the head and the heading in the body
+        let mut epub = sample();
+        let heading = "epubcfi(/6/4!/4/2/1,:0,:9)".parse().unwrap();
+        let exported = to_target(&mut epub, &heading).unwrap();
+        assert_eq!(
+            exported.selector[1],
+            Selector::TextQuoteSelector {
+                exact: "Chapter 2".to_string(),
+                prefix: None,
+                suffix: Some("The rain fell in torrents all ni".to_string()),
+            }
+        );
+        assert_eq!(
+            exported.selector[2],
+            Selector::TextPositionSelector { start: 0, end: 9 }
+        );
+
+        let quote = Selector::TextQuoteSelector {
+            exact: "Chapter 2".to_string(),
+            prefix: None,
+            suffix: None,
+        };
+        for selector in [quote, exported.selector[2].clone()] {
+            assert_eq!(
+                from_target(&mut epub, &target(vec![selector])).unwrap(),
+                heading
+            );
+        }
+
+        // a point covers no text to quote
+        let point = "epubcfi(/6/4!/4/2/1:0)".parse().unwrap();
+        let exported = to_target(&mut epub, &point).unwrap();
+        assert_eq!(
+            exported.selector[1..],
+            [Selector::TextPositionSelector { start: 0, end: 0 }]
+        );
+        assert_eq!(from_target(&mut epub, &exported).unwrap(), point);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json() {
+        let mut epub = sample();
+        let fragment = "epubcfi(/6/4!/4/4/2/1,:0,:8)".parse().unwrap();
+        let target = to_target(&mut epub, &fragment).unwrap();
+        let json = serde_json::to_value(&target).unwrap();
+        assert_eq!(json["selector"][0]["type"], "FragmentSelector");
+        assert_eq!(json["selector"][0]["conformsTo"], CFI_SPECIFICATION);
+        assert_eq!(json["selector"][1]["exact"], "torrents");
+        assert_eq!(json["selector"][2]["type"], "TextPositionSelector");
+
+        let target: Target = serde_json::from_str(
+            r#"{
+                "source": "https://example.com/book/OEBPS/text/chapter02.xhtml",
+                "selector": [{
+                    "type": "RangeSelector",
+                    "startSelector": { "type": "TextQuoteSelector", "exact": "torrents" },
+                    "endSelector": { "type": "TextQuoteSelector", "exact": "torrents" }
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(from_target(&mut epub, &target).unwrap(), fragment);
+    }
+}
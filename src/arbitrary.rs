@@ -0,0 +1,547 @@
+//! Generators of random, valid CFIs for property-based tests.
+//!
+//! With the `arbitrary` feature, the syntax types implement [`arbitrary::Arbitrary`]. With the
+//! `proptest` feature, this module provides a strategy for each type, and the types implement
+//! [`proptest::arbitrary::Arbitrary`]. Every generated value serializes to a CFI that parses back
+//! to an equal value.
+//!
+//! Generated numbers stay within ranges that survive serialization exactly: spatial and temporal
+//! values are non-negative with at most two decimal places.
+
+use crate::{
+    syntax::{Offset, Step},
+    tokens::Token,
+};
+
+/// The longest run of steps between redirections.
+const MAX_STEPS: usize = 4;
+/// The most redirections in a path.
+const MAX_REDIRECTS: usize = 2;
+/// The most parameters in an assertion.
+const MAX_PARAMETERS: usize = 3;
+
+/// Joins runs of steps with redirections and ends them with an optional offset, placed directly
+/// after a redirection when `redirect_offset` is set.
+fn local_tokens(
+    first: Vec<Step<'static>>,
+    redirected: Vec<Vec<Step<'static>>>,
+    redirect_offset: bool,
+    offset: Option<Offset<'static>>,
+) -> Vec<Token<'static>> {
+    let mut tokens: Vec<_> = first.into_iter().map(Token::Step).collect();
+    for steps in redirected {
+        tokens.push(Token::Redirect);
+        tokens.extend(steps.into_iter().map(Token::Step));
+    }
+    if let Some(offset) = offset {
+        if redirect_offset {
+            tokens.push(Token::Redirect);
+        }
+        tokens.push(Token::Offset(offset));
+    }
+    tokens
+}
+
+/// Converts hundredths to a number that serializes exactly.
+fn hundredths(value: u32) -> f32 {
+    value as f32 / 100.0
+}
+
+#[cfg(any(test, feature = "arbitrary"))]
+mod impls {
+    use std::borrow::Cow;
+
+    use arbitrary::{Arbitrary, Result, Unstructured};
+
+    use super::*;
+    use crate::{
+        syntax::{
+            Assertion, CharacterOffset, Fragment, LocalPath, Path, Range, RedirectedPath,
+            SpatialOffset, TemporalOffset,
+        },
+        tokens,
+    };
+
+    const KEY: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789^[](),;=";
+    const VALUE: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789^[](),;= ";
+
+    fn text(u: &mut Unstructured, characters: &[u8]) -> Result<Cow<'static, str>> {
+        let length = u.int_in_range(1..=8)?;
+        let mut string = String::with_capacity(length);
+        for _ in 0..length {
+            string.push(*u.choose(characters)? as char);
+        }
+        Ok(Cow::Owned(string))
+    }
+
+    fn number(u: &mut Unstructured) -> Result<f32> {
+        Ok(hundredths(u.int_in_range(0..=1_000_000)?))
+    }
+
+    fn steps(u: &mut Unstructured, min: usize) -> Result<Vec<Step<'static>>> {
+        (0..u.int_in_range(min..=MAX_STEPS)?)
+            .map(|_| u.arbitrary())
+            .collect()
+    }
+
+    /// Tokens for a local path, with at least `min_steps` steps before any redirection.
+    fn local(u: &mut Unstructured, min_steps: usize, offset: bool) -> Result<Vec<Token<'static>>> {
+        let first = steps(u, min_steps)?;
+        let redirected = (0..u.int_in_range(0..=MAX_REDIRECTS)?)
+            .map(|_| steps(u, 1))
+            .collect::<Result<_>>()?;
+        let offset = match offset && u.arbitrary()? {
+            true => Some(u.arbitrary()?),
+            false => None,
+        };
+        Ok(local_tokens(first, redirected, u.arbitrary()?, offset))
+    }
+
+    fn path(u: &mut Unstructured, offset: bool) -> Result<Path<'static>> {
+        tokens::path(local(u, 1, offset)?).ok_or(arbitrary::Error::IncorrectFormat)
+    }
+
+    impl<'a> Arbitrary<'a> for Assertion<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let parameters = |u: &mut Unstructured, min| {
+                (0..u.int_in_range(min..=MAX_PARAMETERS)?)
+                    .map(|_| Ok((text(u, KEY)?, text(u, VALUE)?)))
+                    .collect::<Result<Vec<_>>>()
+            };
+            match u.int_in_range(0..=2)? {
+                0 => Ok(Assertion::from_cow(None, Some(text(u, VALUE)?))),
+                1 => {
+                    let value = match u.arbitrary()? {
+                        true => Some(text(u, VALUE)?),
+                        false => None,
+                    };
+                    let after = match value.is_none() || u.arbitrary()? {
+                        true => Some(text(u, VALUE)?),
+                        false => None,
+                    };
+                    let parameters = Some(parameters(u, 0)?).filter(|p| !p.is_empty());
+                    let mut assertion = Assertion::from_cow(parameters, value);
+                    assertion.after = after;
+                    Ok(assertion)
+                }
+                _ => Ok(Assertion::from_cow(Some(parameters(u, 1)?), None)),
+            }
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for Step<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(Step::new(u.arbitrary()?, u.arbitrary()?))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for CharacterOffset<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(CharacterOffset::new(u.arbitrary()?, u.arbitrary()?))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for SpatialOffset<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let end = match u.arbitrary()? {
+                true => Some(number(u)?),
+                false => None,
+            };
+            Ok(SpatialOffset::new(number(u)?, end, u.arbitrary()?))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for TemporalOffset<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let range = match u.arbitrary()? {
+                true => Some((number(u)?, number(u)?)),
+                false => None,
+            };
+            Ok(TemporalOffset::new(number(u)?, range, u.arbitrary()?))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for Offset<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(match u.int_in_range(0..=2)? {
+                0 => Offset::Character(u.arbitrary()?),
+                1 => Offset::Spatial(u.arbitrary()?),
+                _ => Offset::Temporal(u.arbitrary()?),
+            })
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for Path<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            path(u, true)
+        }
+    }
+
+    /// A local path as used for either end of a range, which is never empty.
+    impl<'a> Arbitrary<'a> for LocalPath<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let mut tokens = local(u, 0, true)?;
+            if tokens.is_empty() {
+                tokens.push(Token::Step(u.arbitrary()?));
+            }
+            tokens::local_path(tokens).ok_or(arbitrary::Error::IncorrectFormat)
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for RedirectedPath<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(match u.arbitrary()? {
+                true => RedirectedPath::Offset(u.arbitrary()?),
+                false => RedirectedPath::Path(Box::new(u.arbitrary()?)),
+            })
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for Range<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(Range::new(u.arbitrary()?, u.arbitrary()?))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for Fragment<'static> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(match u.arbitrary()? {
+                true => Fragment::new_with_range(path(u, false)?, u.arbitrary()?),
+                false => Fragment::new(path(u, true)?),
+            })
+        }
+    }
+}
+
+#[cfg(any(test, feature = "proptest"))]
+pub use self::strategies::*;
+
+#[cfg(any(test, feature = "proptest"))]
+mod strategies {
+    use std::borrow::Cow;
+
+    use proptest::{
+        arbitrary::Arbitrary,
+        collection::vec,
+        option,
+        prelude::*,
+        strategy::{BoxedStrategy, Strategy},
+    };
+
+    use super::*;
+    use crate::{
+        syntax::{
+            Assertion, CharacterOffset, Fragment, LocalPath, Path, Range, RedirectedPath,
+            SpatialOffset, TemporalOffset,
+        },
+        tokens,
+    };
+
+    fn number() -> impl Strategy<Value = f32> {
+        (0..=1_000_000u32).prop_map(hundredths)
+    }
+
+    fn steps(min: usize) -> impl Strategy<Value = Vec<Step<'static>>> {
+        vec(step(), min..=MAX_STEPS)
+    }
+
+    /// Tokens for a local path, with at least `min_steps` steps before any redirection.
+    fn local(min_steps: usize, offset: bool) -> impl Strategy<Value = Vec<Token<'static>>> {
+        let offset = if offset {
+            option::of(self::offset()).boxed()
+        } else {
+            Just(None).boxed()
+        };
+        (
+            steps(min_steps),
+            vec(steps(1), 0..=MAX_REDIRECTS),
+            any::<bool>(),
+            offset,
+        )
+            .prop_map(|(first, redirected, redirect_offset, offset)| {
+                local_tokens(first, redirected, redirect_offset, offset)
+            })
+    }
+
+    fn path_with(offset: bool) -> impl Strategy<Value = Path<'static>> {
+        local(1, offset).prop_filter_map("invalid path", tokens::path)
+    }
+
+    const KEY: &str = r"[a-zA-Z0-9^\[\](),;=]{1,8}";
+    const VALUE: &str = r"[a-zA-Z0-9^\[\](),;= ]{1,8}";
+
+    /// An assertion holding an id value, a text location or parameters. Values include the
+    /// characters that must be escaped.
+    pub fn assertion() -> impl Strategy<Value = Assertion<'static>> {
+        let parameters = |min| vec((KEY, VALUE), min..=MAX_PARAMETERS);
+        prop_oneof![
+            VALUE.prop_map(|value| Assertion::new(None, Some(value))),
+            (option::of(VALUE), option::of(VALUE), VALUE, parameters(0)).prop_map(
+                |(value, after, fallback, parameters)| {
+                    let after = after.or(value.is_none().then_some(fallback));
+                    let parameters = Some(parameters).filter(|p| !p.is_empty());
+                    let mut assertion = Assertion::new(parameters, value);
+                    assertion.after = after.map(Cow::Owned);
+                    assertion
+                }
+            ),
+            parameters(1).prop_map(|parameters| Assertion::new(Some(parameters), None)),
+        ]
+    }
+
+    pub fn step() -> impl Strategy<Value = Step<'static>> {
+        (any::<u32>(), option::of(assertion()))
+            .prop_map(|(size, assertion)| Step::new(size, assertion))
+    }
+
+    pub fn character_offset() -> impl Strategy<Value = CharacterOffset<'static>> {
+        (any::<u32>(), option::of(assertion()))
+            .prop_map(|(offset, assertion)| CharacterOffset::new(offset, assertion))
+    }
+
+    pub fn spatial_offset() -> impl Strategy<Value = SpatialOffset<'static>> {
+        (number(), option::of(number()), option::of(assertion()))
+            .prop_map(|(start, end, assertion)| SpatialOffset::new(start, end, assertion))
+    }
+
+    pub fn temporal_offset() -> impl Strategy<Value = TemporalOffset<'static>> {
+        (
+            number(),
+            option::of((number(), number())),
+            option::of(assertion()),
+        )
+            .prop_map(|(start, range, assertion)| TemporalOffset::new(start, range, assertion))
+    }
+
+    pub fn offset() -> impl Strategy<Value = Offset<'static>> {
+        prop_oneof![
+            character_offset().prop_map(Offset::Character),
+            spatial_offset().prop_map(Offset::Spatial),
+            temporal_offset().prop_map(Offset::Temporal),
+        ]
+    }
+
+    /// A path, which may end with an offset.
+    pub fn path() -> impl Strategy<Value = Path<'static>> {
+        path_with(true)
+    }
+
+    /// A local path as used for either end of a range, which is never empty.
+    pub fn local_path() -> impl Strategy<Value = LocalPath<'static>> {
+        local(0, true)
+            .prop_filter("empty local path", |tokens| !tokens.is_empty())
+            .prop_filter_map("invalid local path", tokens::local_path)
+    }
+
+    pub fn redirected_path() -> impl Strategy<Value = RedirectedPath<'static>> {
+        prop_oneof![
+            offset().prop_map(RedirectedPath::Offset),
+            path().prop_map(|path| RedirectedPath::Path(Box::new(path))),
+        ]
+    }
+
+    pub fn range() -> impl Strategy<Value = Range<'static>> {
+        (local_path(), local_path()).prop_map(|(start, end)| Range::new(start, end))
+    }
+
+    /// A point, or a range whose shared path does not end with an offset.
+    pub fn fragment() -> impl Strategy<Value = Fragment<'static>> {
+        prop_oneof![
+            path().prop_map(Fragment::new),
+            (path_with(false), range())
+                .prop_map(|(path, range)| Fragment::new_with_range(path, range)),
+        ]
+    }
+
+    macro_rules! impl_arbitrary {
+        ($($type:ident => $strategy:ident),* $(,)?) => {
+            $(
+                impl Arbitrary for $type<'static> {
+                    type Parameters = ();
+                    type Strategy = BoxedStrategy<Self>;
+
+                    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+                        $strategy().boxed()
+                    }
+                }
+            )*
+        };
+    }
+
+    impl_arbitrary! {
+        Assertion => assertion,
+        Step => step,
+        CharacterOffset => character_offset,
+        SpatialOffset => spatial_offset,
+        TemporalOffset => temporal_offset,
+        Offset => offset,
+        Path => path,
+        LocalPath => local_path,
+        RedirectedPath => redirected_path,
+        Range => range,
+        Fragment => fragment,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::arbitrary::{Arbitrary, Unstructured};
+    use proptest::prelude::*;
+
+    use std::cmp::Ordering;
+
+    use super::*;
+    use crate::{
+        binary,
+        syntax::{CharacterOffset, Fragment, Offset, Step, ToOffset},
+        tokens::{self, flatten, Token},
+    };
+
+    /// CFI ordering, compared token by token: a path that ends comes before the same path with
+    /// an offset, a redirection, then further steps, and a point before a range that starts at the
+    /// same location.
+    fn compare(a: &Fragment, b: &Fragment) -> Ordering {
+        fn key<'a>(fragment: &Fragment<'a>) -> (Vec<Token<'a>>, Option<Vec<Token<'a>>>) {
+            let start = flatten(&fragment.start().unwrap());
+            let end = fragment.range().map(|_| flatten(&fragment.end().unwrap()));
+            (start, end)
+        }
+        let ((a_start, a_end), (b_start, b_end)) = (key(a), key(b));
+        compare_tokens(&a_start, &b_start).then_with(|| match (a_end, b_end) {
+            (Some(a), Some(b)) => compare_tokens(&a, &b),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        })
+    }
+
+    fn compare_tokens(a: &[Token], b: &[Token]) -> Ordering {
+        let rank = |token: Option<&Token>| match token {
+            None => 0,
+            Some(Token::Offset(Offset::Character(_))) => 1,
+            Some(Token::Offset(Offset::Spatial(_))) => 2,
+            Some(Token::Offset(Offset::Temporal(_))) => 3,
+            Some(Token::Redirect) => 4,
+            Some(Token::Step(_)) => 5,
+        };
+        for index in 0.. {
+            let (x, y) = (a.get(index), b.get(index));
+            let ordering = rank(x).cmp(&rank(y)).then_with(|| match (x, y) {
+                (Some(Token::Step(x)), Some(Token::Step(y))) => x.size.cmp(&y.size),
+                (
+                    Some(Token::Offset(Offset::Character(x))),
+                    Some(Token::Offset(Offset::Character(y))),
+                ) => x.start_at_point.cmp(&y.start_at_point),
+                // y before x, top to bottom and then left to right
+                (
+                    Some(Token::Offset(Offset::Spatial(x))),
+                    Some(Token::Offset(Offset::Spatial(y))),
+                ) => compare_floats(x.end_at_point, y.end_at_point)
+                    .then_with(|| x.start_at_point.total_cmp(&y.start_at_point)),
+                (
+                    Some(Token::Offset(Offset::Temporal(x))),
+                    Some(Token::Offset(Offset::Temporal(y))),
+                ) => x.start_at.total_cmp(&y.start_at).then_with(|| {
+                    let swap = |range: Option<(f32, f32)>| range.map(|(x, y)| (y, x));
+                    match (swap(x.spatial_range), swap(y.spatial_range)) {
+                        (Some(a), Some(b)) => a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)),
+                        (a, b) => a.is_some().cmp(&b.is_some()),
+                    }
+                }),
+                _ => Ordering::Equal,
+            });
+            if ordering != Ordering::Equal || x.is_none() {
+                return ordering;
+            }
+        }
+        unreachable!()
+    }
+
+    fn compare_floats(a: Option<f32>, b: Option<f32>) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(fragment in fragment()) {
+            let serialized = fragment.to_string();
+            prop_assert_eq!(Fragment::parse(&serialized), Ok(fragment.clone()), "{}", serialized);
+            prop_assert_eq!(binary::decode(&binary::encode(&fragment)), Ok(fragment));
+        }
+
+        #[test]
+        fn test_ordering(a in fragment(), b in fragment(), steps in 1..8usize, index in 0..8u32) {
+            // fragments related to `a` share a prefix with it and each other, and so are ordered
+            // by the tokens where they differ
+            let start = a.start().unwrap();
+            let related = [
+                Some(start.clone()),
+                Some(start.without_offset()),
+                start.parent(),
+                start.truncate(steps),
+                start.previous_sibling(),
+                start.next_sibling(),
+                Some(start.child(index)),
+            ];
+            // and so are fragments with a different offset, or a redirection or step in place
+            // of the offset
+            let mut tokens = flatten(&start);
+            if let Some(Token::Offset(_)) = tokens.last() {
+                tokens.pop();
+            }
+            let edited = [
+                vec![Token::Offset(CharacterOffset::new(index, None).to_offset())],
+                vec![Token::Redirect, Token::Step(Step::new(index, None))],
+                vec![Token::Step(Step::new(index, None))],
+            ]
+            .map(|tail| tokens::path([tokens.clone(), tail].concat()));
+            let fragments: Vec<_> = related
+                .into_iter()
+                .chain(edited)
+                .flatten()
+                .map(Fragment::new)
+                .chain([a, b])
+                .collect();
+            for x in &fragments {
+                for y in &fragments {
+                    // the metadata orders fragments that identify the same location
+                    let ordering = compare(x, y);
+                    if ordering != Ordering::Equal {
+                        let encoded = binary::encode(x).cmp(&binary::encode(y));
+                        prop_assert_eq!(encoded, ordering, "{} {}", x, y);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn test_point_before_range(fragment in fragment()) {
+            let start = Fragment::new(fragment.start().unwrap());
+            prop_assert!(binary::encode(&start) <= binary::encode(&fragment));
+        }
+
+        #[test]
+        fn test_range_algebra(fragment in fragment()) {
+            let (start, end) = (fragment.start().unwrap(), fragment.end().unwrap());
+            let ancestor = start.common_ancestor(&end).unwrap();
+            prop_assert!(start.relative_to(&ancestor).is_some());
+            prop_assert!(end.relative_to(&ancestor).is_some());
+
+            if fragment.range.is_some() {
+                let range = Fragment::from_range(&start, &end).unwrap();
+                prop_assert_eq!(range.start(), Some(start));
+                prop_assert_eq!(range.end(), Some(end));
+            }
+        }
+
+        #[test]
+        fn test_arbitrary_round_trip(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let mut u = Unstructured::new(&data);
+            if let Ok(fragment) = Fragment::arbitrary(&mut u) {
+                let serialized = fragment.to_string();
+                prop_assert_eq!(Fragment::parse(&serialized), Ok(fragment));
+            }
+        }
+    }
+}
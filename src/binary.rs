@@ -0,0 +1,500 @@
+//! A compact binary encoding of fragments whose byte order matches CFI ordering.
+//!
+//! Encoded fragments can be stored in a B-tree and range-scanned: for any two fragments `a` and
+//! `b`, `encode(a) < encode(b)` when `a` identifies an earlier location than `b`.
+//!
+//! # Layout
+//!
+//! ```plaintext
+//! encoding = key , ( terminator | key ) , metadata ;
+//! key      = { token } , terminator ;
+//! ```
+//!
+//! The first key is the full path to the start of the fragment, and the second is the full path
+//! to the end of a range. A point has a terminator in place of the second key, so it sorts before
+//! any range that starts at the same location. Each key is a sequence of tokens:
+//!
+//! | Token             | Encoding                                                    |
+//! |-------------------|-------------------------------------------------------------|
+//! | terminator        | `0x00`                                                      |
+//! | character offset  | `0x01`, varint                                              |
+//! | spatial offset    | `0x02`, (`0x00` \| `0x01`, float), float                    |
+//! | temporal offset   | `0x03`, float, (`0x00` \| `0x01`, float, float)             |
+//! | redirection (`!`) | `0x04`                                                      |
+//! | step              | varint, whose first byte is always `0x08` or greater        |
+//!
+//! A path that ends sorts before the same path with an offset, which sorts before the same path
+//! with a redirection, which sorts before the same path with further steps. This places an element
+//! before its contents, in document order. Spatial offsets and ranges store the y coordinate before
+//! the x coordinate, so that points sort top to bottom and then left to right.
+//!
+//! Integers use an order-preserving varint: values below 232 are a single byte `value + 8`, and
+//! larger values are a byte `0xF0 + (n - 1)` followed by `value - 232` in `n` big-endian bytes.
+//! Floats are stored as their big-endian bits, with the sign bit flipped for positive numbers and
+//! every bit flipped for negative numbers.
+//!
+//! The metadata holds what does not affect ordering: where a range's shared path ends and the
+//! steps' and offsets' assertions. It is only compared when two fragments identify the same
+//! location.
+
+use std::{borrow::Cow, fmt};
+
+use crate::{
+    syntax::{
+        Assertion, CharacterOffset, Fragment, Offset, Range, SpatialOffset, Step, TemporalOffset,
+    },
+    tokens::{local_path, path, push_local_path, push_path, Token},
+};
+
+const TERMINATOR: u8 = 0x00;
+const CHARACTER: u8 = 0x01;
+const SPATIAL: u8 = 0x02;
+const TEMPORAL: u8 = 0x03;
+const REDIRECT: u8 = 0x04;
+const STEP_BASE: u8 = 0x08;
+const VARINT_SINGLE_LIMIT: u32 = 0xF0 - STEP_BASE as u32;
+const VARINT_MULTI: u8 = 0xF0;
+
+/// Encodes a fragment so that byte order matches CFI ordering.
+pub fn encode(fragment: &Fragment) -> Vec<u8> {
+    let mut prefix = vec![];
+    push_path(&mut prefix, fragment.path());
+    let (start, end) = match fragment.range() {
+        Some(range) => {
+            let mut start = prefix.clone();
+            push_local_path(&mut start, range.start_point());
+            let mut end = prefix.clone();
+            push_local_path(&mut end, range.end_point());
+            (start, Some(end))
+        }
+        None => (prefix.clone(), None),
+    };
+
+    let mut bytes = vec![];
+    write_key(&mut bytes, &start);
+    match &end {
+        Some(end) => write_key(&mut bytes, end),
+        None => bytes.push(TERMINATOR),
+    }
+
+    // metadata
+    if end.is_some() {
+        write_varint(&mut bytes, prefix.len() as u32);
+    }
+    let tokens = start
+        .iter()
+        .chain(end.iter().flat_map(|end| &end[prefix.len()..]));
+    for (index, token) in tokens.enumerate() {
+        if let Some(assertion) = token.assertion() {
+            write_varint(&mut bytes, index as u32 + 1);
+            write_assertion(&mut bytes, assertion);
+        }
+    }
+    bytes
+}
+
+/// Decodes a fragment produced by [`encode`].
+pub fn decode(bytes: &[u8]) -> Result<Fragment<'static>, DecodeError> {
+    let mut reader = Reader { bytes, position: 0 };
+    let mut start = reader.key()?;
+    let mut end = match reader.peek()? {
+        TERMINATOR => {
+            reader.position += 1;
+            None
+        }
+        _ => Some(reader.key()?),
+    };
+    let prefix_length = match end {
+        Some(_) => reader.varint()? as usize,
+        None => start.len(),
+    };
+    if prefix_length > start.len() || end.as_ref().is_some_and(|end| prefix_length > end.len()) {
+        return Err(reader.error());
+    }
+
+    // Both keys repeat the shared prefix, so assertions are only stored once for it.
+    let end_length = end.as_ref().map_or(0, |end| end.len() - prefix_length);
+    while reader.position < bytes.len() {
+        let index = reader.varint()? as usize;
+        let assertion = reader.assertion()?;
+        let token = match index.checked_sub(1) {
+            Some(index) if index < start.len() => &mut start[index],
+            Some(index) if index < start.len() + end_length => {
+                let end = end.as_mut().ok_or(reader.error())?;
+                &mut end[prefix_length + index - start.len()]
+            }
+            _ => return Err(reader.error()),
+        };
+        token.set_assertion(assertion).ok_or(reader.error())?;
+    }
+
+    let invalid = DecodeError {
+        position: bytes.len(),
+    };
+    let start_point = start.split_off(prefix_length);
+    let path = path(start).ok_or(invalid.clone())?;
+    match end.as_mut() {
+        Some(end) => {
+            let end_point = end.split_off(prefix_length);
+            let range = Range::new(
+                local_path(start_point).ok_or(invalid.clone())?,
+                local_path(end_point).ok_or(invalid)?,
+            );
+            Ok(Fragment::new_with_range(path, range))
+        }
+        None => Ok(Fragment::new(path)),
+    }
+}
+
+/// The error returned when bytes are not a valid encoded fragment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    position: usize,
+}
+
+impl DecodeError {
+    /// The byte position at which decoding failed.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid encoded CFI at byte {}", self.position)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn write_key(bytes: &mut Vec<u8>, tokens: &[Token]) {
+    for token in tokens {
+        match token {
+            Token::Step(step) => write_varint(bytes, step.size),
+            Token::Redirect => bytes.push(REDIRECT),
+            Token::Offset(Offset::Character(offset)) => {
+                bytes.push(CHARACTER);
+                write_varint(bytes, offset.start_at_point);
+            }
+            Token::Offset(Offset::Spatial(offset)) => {
+                bytes.push(SPATIAL);
+                match offset.end_at_point {
+                    Some(end) => {
+                        bytes.push(1);
+                        write_float(bytes, end);
+                    }
+                    None => bytes.push(0),
+                }
+                write_float(bytes, offset.start_at_point);
+            }
+            Token::Offset(Offset::Temporal(offset)) => {
+                bytes.push(TEMPORAL);
+                write_float(bytes, offset.start_at);
+                match offset.spatial_range {
+                    Some((start, end)) => {
+                        bytes.push(1);
+                        write_float(bytes, end);
+                        write_float(bytes, start);
+                    }
+                    None => bytes.push(0),
+                }
+            }
+        }
+    }
+    bytes.push(TERMINATOR);
+}
+
+fn write_varint(bytes: &mut Vec<u8>, value: u32) {
+    if value < VARINT_SINGLE_LIMIT {
+        bytes.push(value as u8 + STEP_BASE);
+        return;
+    }
+    let value = (value - VARINT_SINGLE_LIMIT).to_be_bytes();
+    let skip = value.iter().take_while(|&&byte| byte == 0).count().min(3);
+    bytes.push(VARINT_MULTI + (3 - skip) as u8);
+    bytes.extend(&value[skip..]);
+}
+
+fn write_float(bytes: &mut Vec<u8>, value: f32) {
+    let bits = value.to_bits();
+    let bits = if bits >> 31 == 1 {
+        !bits
+    } else {
+        bits | 1 << 31
+    };
+    bytes.extend(bits.to_be_bytes());
+}
+
+fn write_string(bytes: &mut Vec<u8>, value: &str) {
+    write_varint(bytes, value.len() as u32);
+    bytes.extend(value.as_bytes());
+}
+
+fn write_assertion(bytes: &mut Vec<u8>, assertion: &Assertion) {
+    let flags = assertion.value().is_some() as u8
+        | (assertion.parameters().is_some() as u8) << 1
+        | (assertion.after().is_some() as u8) << 2;
+    bytes.push(flags);
+    if let Some(value) = assertion.value() {
+        write_string(bytes, value);
+    }
+    if let Some(after) = assertion.after() {
+        write_string(bytes, after);
+    }
+    if let Some(parameters) = assertion.parameters() {
+        write_varint(bytes, parameters.len() as u32);
+        for (key, value) in parameters {
+            write_string(bytes, key);
+            write_string(bytes, value);
+        }
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn error(&self) -> DecodeError {
+        DecodeError {
+            position: self.position,
+        }
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.bytes.get(self.position).copied().ok_or(self.error())
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = self.peek()?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, length: usize) -> Result<&[u8], DecodeError> {
+        let end = self.position.checked_add(length).ok_or(self.error())?;
+        let bytes = self.bytes.get(self.position..end).ok_or(self.error())?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn key(&mut self) -> Result<Vec<Token<'static>>, DecodeError> {
+        let mut tokens = vec![];
+        loop {
+            let token = match self.peek()? {
+                TERMINATOR => {
+                    self.position += 1;
+                    return Ok(tokens);
+                }
+                CHARACTER => {
+                    self.position += 1;
+                    Offset::Character(CharacterOffset::new(self.varint()?, None))
+                }
+                SPATIAL => {
+                    self.position += 1;
+                    let end = self.flag()?.then(|| self.float()).transpose()?;
+                    let start = self.float()?;
+                    Offset::Spatial(SpatialOffset::new(start, end, None))
+                }
+                TEMPORAL => {
+                    self.position += 1;
+                    let start = self.float()?;
+                    let range = match self.flag()? {
+                        true => {
+                            let (end, start) = (self.float()?, self.float()?);
+                            Some((start, end))
+                        }
+                        false => None,
+                    };
+                    Offset::Temporal(TemporalOffset::new(start, range, None))
+                }
+                REDIRECT => {
+                    self.position += 1;
+                    tokens.push(Token::Redirect);
+                    continue;
+                }
+                _ => {
+                    tokens.push(Token::Step(Step::new(self.varint()?, None)));
+                    continue;
+                }
+            };
+            tokens.push(Token::Offset(token));
+        }
+    }
+
+    fn flag(&mut self) -> Result<bool, DecodeError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError {
+                position: self.position - 1,
+            }),
+        }
+    }
+
+    fn varint(&mut self) -> Result<u32, DecodeError> {
+        let start = self.position;
+        let invalid = DecodeError { position: start };
+        match self.byte()? {
+            byte @ STEP_BASE..VARINT_MULTI => Ok((byte - STEP_BASE) as u32),
+            byte @ VARINT_MULTI..=0xF3 => {
+                let length = (byte - VARINT_MULTI) as usize + 1;
+                let mut value = [0; 4];
+                value[4 - length..].copy_from_slice(self.take(length)?);
+                // reject non-minimal encodings, which would break ordering
+                if length > 1 && value[4 - length] == 0 {
+                    return Err(invalid);
+                }
+                u32::from_be_bytes(value)
+                    .checked_add(VARINT_SINGLE_LIMIT)
+                    .ok_or(invalid)
+            }
+            _ => Err(invalid),
+        }
+    }
+
+    fn float(&mut self) -> Result<f32, DecodeError> {
+        let mut bits = [0; 4];
+        bits.copy_from_slice(self.take(4)?);
+        let bits = u32::from_be_bytes(bits);
+        let bits = if bits >> 31 == 1 {
+            bits & !(1 << 31)
+        } else {
+            !bits
+        };
+        Ok(f32::from_bits(bits))
+    }
+
+    fn string(&mut self) -> Result<Cow<'static, str>, DecodeError> {
+        let start = self.position;
+        let length = self.varint()? as usize;
+        let bytes = self.take(length)?.to_vec();
+        String::from_utf8(bytes)
+            .map(Cow::Owned)
+            .map_err(|_| DecodeError { position: start })
+    }
+
+    fn assertion(&mut self) -> Result<Assertion<'static>, DecodeError> {
+        let flags = self.byte()?;
+        if flags > 0b111 {
+            return Err(DecodeError {
+                position: self.position - 1,
+            });
+        }
+        let value = (flags & 1 == 1).then(|| self.string()).transpose()?;
+        let after = (flags & 0b100 == 0b100)
+            .then(|| self.string())
+            .transpose()?;
+        let parameters = match flags & 0b10 == 0b10 {
+            true => {
+                let count = self.varint()?;
+                let mut parameters = vec![];
+                for _ in 0..count {
+                    parameters.push((self.string()?, self.string()?));
+                }
+                Some(parameters)
+            }
+            false => None,
+        };
+        let mut assertion = Assertion::from_cow(parameters, value);
+        assertion.after = after;
+        Ok(assertion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(cfi: &str) -> Vec<u8> {
+        let fragment: Fragment = cfi.parse().unwrap();
+        let bytes = encode(&fragment);
+        assert_eq!(decode(&bytes).unwrap(), fragment, "{}", cfi);
+        bytes
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for cfi in [
+            "epubcfi(/6/2)",
+            "epubcfi(/6/2[2])",
+            "epubcfi(/6/4!/4/1:5)",
+            "epubcfi(/6/4[7]!/4/10[1],/2/1:1[lang=en],/3:4)",
+            "epubcfi(/6/4!/4/10/3,:1,:5)",
+            "epubcfi(/6/4,!/2/1:8,!/4/2/1:7)",
+            "epubcfi(/6/4!/4/2@3.5:7.2)",
+            "epubcfi(/6/4!/4/2@3.5:)",
+            "epubcfi(/6/4!/4/2~2.7@0.5:1.5[type=note;id=note1])",
+            "epubcfi(/6/300!/4/100000/1:70000)",
+            "epubcfi(/6/4[chap01ref]!/4[body01]/10[para^]05]/3:10[yyy,0123;s=b])",
+        ] {
+            round_trip(cfi);
+        }
+    }
+
+    #[test]
+    fn test_compact() {
+        assert_eq!(
+            round_trip("epubcfi(/6/4!/4/10/3:12)"),
+            vec![14, 12, 4, 12, 18, 11, 1, 20, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_ordering() {
+        let sorted = [
+            "epubcfi(/6/2!/4/2/1:3)",
+            "epubcfi(/6/4)",
+            "epubcfi(/6/4!/4)",
+            "epubcfi(/6/4!/4/2)",
+            "epubcfi(/6/4!/4/2:0)",
+            "epubcfi(/6/4!/4/2/1:0)",
+            "epubcfi(/6/4!/4/2/1:9)",
+            "epubcfi(/6/4!/4/2,/1:9,/3:1)",
+            "epubcfi(/6/4!/4/2,/1:9,/3:5)",
+            "epubcfi(/6/4!/4/2/1:10)",
+            "epubcfi(/6/4!/4/2/1:231)",
+            "epubcfi(/6/4!/4/2/1:232)",
+            "epubcfi(/6/4!/4/2/1:300)",
+            "epubcfi(/6/4!/4/2/1:70000)",
+            "epubcfi(/6/4!/4/2/3@50:10)",
+            "epubcfi(/6/4!/4/2/3@10:20)",
+            "epubcfi(/6/4!/4/2/3@30:20)",
+            "epubcfi(/6/4!/4/2/3~1.5)",
+            "epubcfi(/6/4!/4/2/3~1.5@50:10)",
+            "epubcfi(/6/4!/4/2/3~1.5@10:20)",
+            "epubcfi(/6/4!/4/2/3~10)",
+            "epubcfi(/6/4!/4/10)",
+            "epubcfi(/6/4!/4/232)",
+            "epubcfi(/6/10!/2)",
+        ];
+        let encoded: Vec<_> = sorted.iter().map(|cfi| round_trip(cfi)).collect();
+        for (i, pair) in encoded.windows(2).enumerate() {
+            assert!(pair[0] < pair[1], "{} < {}", sorted[i], sorted[i + 1]);
+        }
+    }
+
+    #[test]
+    fn test_float_ordering() {
+        let floats = [-10.5, -1.0, -0.5, 0.0, 0.25, 1.0, 2.7, 1000.0];
+        let encoded: Vec<_> = floats
+            .iter()
+            .map(|&float| {
+                let mut bytes = vec![];
+                write_float(&mut bytes, float);
+                bytes
+            })
+            .collect();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode(&[]).unwrap_err().position(), 0);
+        assert_eq!(decode(&[14, 0xFF]).unwrap_err().position(), 1);
+        assert_eq!(decode(&[14, 0xF1, 0, 1, 0, 0]).unwrap_err().position(), 1);
+        // an assertion for a token that does not exist
+        assert!(decode(&[14, 12, 0, 0, 20, 0]).is_err());
+    }
+}
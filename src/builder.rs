@@ -0,0 +1,317 @@
+//! A fluent builder for constructing fragments programmatically.
+//!
+//! ```rust
+//! use epub_cfi::builder::CfiBuilder;
+//!
+//! let fragment = CfiBuilder::new()
+//!     .step(6)
+//!     .step_with_id(4, "chap01ref")
+//!     .redirect()
+//!     .step(4)
+//!     .step(10)
+//!     .char_offset(3)
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(fragment.to_string(), "epubcfi(/6/4[chap01ref]!/4/10:3)");
+//!
+//! let range = CfiBuilder::new()
+//!     .step(6)
+//!     .step(4)
+//!     .redirect()
+//!     .step(4)
+//!     .range_start()
+//!     .step(2)
+//!     .char_offset(1)
+//!     .range_end()
+//!     .step(4)
+//!     .char_offset(5)
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(range.to_string(), "epubcfi(/6/4!/4,/2:1,/4:5)");
+//! ```
+
+use std::fmt;
+
+use crate::{
+    syntax::{
+        Assertion, CharacterOffset, Fragment, Offset, Range, SpatialOffset, Step, TemporalOffset,
+    },
+    tokens::{local_path, path, Token},
+};
+
+/// Builds a [`Fragment`] one step at a time.
+///
+/// Grammar rules are checked as the fragment is built, and the first violation is returned by
+/// [`build`](CfiBuilder::build).
+#[derive(Clone, Debug, Default)]
+pub struct CfiBuilder {
+    path: Vec<Token<'static>>,
+    start: Option<Vec<Token<'static>>>,
+    end: Option<Vec<Token<'static>>>,
+    error: Option<BuildError>,
+}
+
+impl CfiBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a step, e.g. `/4`.
+    pub fn step(self, index: u32) -> Self {
+        self.push(Token::Step(Step::new(index, None)))
+    }
+
+    /// Appends a step with an id assertion, e.g. `/4[chap01ref]`.
+    pub fn step_with_id(self, index: u32, id: impl Into<String>) -> Self {
+        let assertion = Assertion::new(None, Some(id.into()));
+        self.push(Token::Step(Step::new(index, Some(assertion))))
+    }
+
+    /// Appends a redirection, `!`. It must be followed by a step or an offset.
+    pub fn redirect(self) -> Self {
+        self.push(Token::Redirect)
+    }
+
+    /// Appends a character offset, e.g. `:10`. Nothing may follow an offset.
+    pub fn char_offset(self, offset: u32) -> Self {
+        self.push(Token::Offset(Offset::Character(CharacterOffset::new(
+            offset, None,
+        ))))
+    }
+
+    /// Appends a spatial offset, e.g. `@50:50`. Nothing may follow an offset.
+    pub fn spatial_offset(self, x: f32, y: f32) -> Self {
+        self.push(Token::Offset(Offset::Spatial(SpatialOffset::new(
+            x,
+            Some(y),
+            None,
+        ))))
+    }
+
+    /// Appends a temporal offset in seconds, e.g. `~23.5`. Nothing may follow an offset.
+    pub fn temporal_offset(self, seconds: f32) -> Self {
+        self.push(Token::Offset(Offset::Temporal(TemporalOffset::new(
+            seconds, None, None,
+        ))))
+    }
+
+    /// Ends the path shared by both ends of a range; what follows is the range's start.
+    pub fn range_start(mut self) -> Self {
+        if self.error.is_none() {
+            self.error = match (&self.start, self.path.last()) {
+                (Some(_), _) => Some(BuildError::MisplacedRange),
+                // the start and end must continue the shared path
+                (None, Some(Token::Offset(_))) => Some(BuildError::AfterOffset),
+                (None, _) => check_path(&self.path).err(),
+            };
+            self.start = Some(vec![]);
+        }
+        self
+    }
+
+    /// Ends the range's start; what follows is the range's end.
+    pub fn range_end(mut self) -> Self {
+        if self.error.is_none() {
+            self.error = match (&self.start, &self.end) {
+                (Some(start), None) => check_local_path(start).err(),
+                _ => Some(BuildError::MisplacedRange),
+            };
+            self.end = Some(vec![]);
+        }
+        self
+    }
+
+    /// Builds the fragment, or returns the first grammar rule that was broken.
+    pub fn build(self) -> Result<Fragment<'static>, BuildError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        check_path(&self.path)?;
+        let path = path(self.path).ok_or(BuildError::MissingStep)?;
+        match (self.start, self.end) {
+            (None, None) => Ok(Fragment::new(path)),
+            (Some(start), Some(end)) => {
+                check_local_path(&end)?;
+                let start = local_path(start).ok_or(BuildError::EmptyRange)?;
+                let end = local_path(end).ok_or(BuildError::EmptyRange)?;
+                Ok(Fragment::new_with_range(path, Range::new(start, end)))
+            }
+            _ => Err(BuildError::MisplacedRange),
+        }
+    }
+
+    fn push(mut self, token: Token<'static>) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        let (tokens, in_path) = match (&mut self.end, &mut self.start) {
+            (Some(end), _) => (end, false),
+            (None, Some(start)) => (start, false),
+            (None, None) => (&mut self.path, true),
+        };
+        self.error = match (tokens.last(), &token) {
+            (Some(Token::Offset(_)), _) => Some(BuildError::AfterOffset),
+            (None, Token::Redirect | Token::Offset(_)) if in_path => Some(BuildError::MissingStep),
+            (Some(Token::Redirect), Token::Redirect) => Some(BuildError::IncompleteRedirect),
+            _ => None,
+        };
+        tokens.push(token);
+        self
+    }
+}
+
+/// Checks that a path starts with a step and is complete.
+fn check_path(tokens: &[Token]) -> Result<(), BuildError> {
+    match tokens.first() {
+        Some(Token::Step(_)) => check_local_path(tokens),
+        _ => Err(BuildError::MissingStep),
+    }
+}
+
+/// Checks that a local path is not empty and does not end with a redirection.
+fn check_local_path(tokens: &[Token]) -> Result<(), BuildError> {
+    match tokens.last() {
+        None => Err(BuildError::EmptyRange),
+        Some(Token::Redirect) => Err(BuildError::IncompleteRedirect),
+        Some(_) => Ok(()),
+    }
+}
+
+/// A grammar rule broken while building a fragment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// The path does not start with a step.
+    MissingStep,
+    /// Something follows an offset.
+    AfterOffset,
+    /// A redirection is not followed by a step or an offset.
+    IncompleteRedirect,
+    /// The start or end of a range is empty.
+    EmptyRange,
+    /// `range_start` and `range_end` were not each called once, in order.
+    MisplacedRange,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingStep => write!(f, "a path must start with a step"),
+            BuildError::AfterOffset => write!(f, "nothing may follow an offset"),
+            BuildError::IncompleteRedirect => {
+                write!(f, "a redirection must be followed by a step or an offset")
+            }
+            BuildError::EmptyRange => write!(f, "the start and end of a range must not be empty"),
+            BuildError::MisplacedRange => {
+                write!(f, "a range needs one range_start followed by one range_end")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_point() {
+        let fragment = CfiBuilder::new()
+            .step(6)
+            .step(4)
+            .redirect()
+            .step(4)
+            .step(10)
+            .step(3)
+            .temporal_offset(2.5)
+            .build()
+            .unwrap();
+        assert_eq!(fragment, "epubcfi(/6/4!/4/10/3~2.5)".parse().unwrap());
+
+        let fragment = CfiBuilder::new()
+            .step(6)
+            .step(4)
+            .redirect()
+            .spatial_offset(10.0, 20.5)
+            .build()
+            .unwrap();
+        assert_eq!(fragment.to_string(), "epubcfi(/6/4!@10:20.5)");
+    }
+
+    #[test]
+    fn test_build_range() {
+        let fragment = CfiBuilder::new()
+            .step(6)
+            .step(4)
+            .range_start()
+            .redirect()
+            .step(4)
+            .char_offset(1)
+            .range_end()
+            .redirect()
+            .step(6)
+            .build()
+            .unwrap();
+        assert_eq!(fragment, "epubcfi(/6/4,!/4:1,!/6)".parse().unwrap());
+
+        let fragment = CfiBuilder::new()
+            .step(6)
+            .range_start()
+            .char_offset(1)
+            .range_end()
+            .char_offset(5)
+            .build()
+            .unwrap();
+        assert_eq!(fragment, "epubcfi(/6,:1,:5)".parse().unwrap());
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let error = |builder: CfiBuilder| builder.build().unwrap_err();
+        assert_eq!(error(CfiBuilder::new()), BuildError::MissingStep);
+        assert_eq!(
+            error(CfiBuilder::new().redirect().step(4)),
+            BuildError::MissingStep
+        );
+        assert_eq!(
+            error(CfiBuilder::new().step(6).char_offset(1).redirect()),
+            BuildError::AfterOffset
+        );
+        assert_eq!(
+            error(CfiBuilder::new().step(6).char_offset(1).step(2)),
+            BuildError::AfterOffset
+        );
+        assert_eq!(
+            error(
+                CfiBuilder::new()
+                    .step(6)
+                    .char_offset(1)
+                    .range_start()
+                    .step(2)
+                    .range_end()
+                    .step(4)
+            ),
+            BuildError::AfterOffset
+        );
+        assert_eq!(
+            error(CfiBuilder::new().step(6).redirect()),
+            BuildError::IncompleteRedirect
+        );
+        assert_eq!(
+            error(CfiBuilder::new().step(6).redirect().redirect().step(2)),
+            BuildError::IncompleteRedirect
+        );
+        assert_eq!(
+            error(CfiBuilder::new().step(6).range_start().range_end().step(2)),
+            BuildError::EmptyRange
+        );
+        assert_eq!(
+            error(CfiBuilder::new().step(6).range_start().step(2)),
+            BuildError::MisplacedRange
+        );
+        assert_eq!(
+            error(CfiBuilder::new().step(6).range_end()),
+            BuildError::MisplacedRange
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Normalization of CFIs produced by reading systems that do not follow the specification.
+//!
+//! Reading systems built on a browser engine count in DOM terms rather than in the terms of the
+//! specification. epub.js, e.g. in `epubcfi(/6/14[xchapter_001]!/4/2/1:0)`, numbers a text step
+//! by the index of the text node among the text node children of its parent only, so that
+//! adjacent elements with no text between them do not take up an odd index, and a comment
+//! splits the text around it into two steps. Both epub.js and Apple Books count character
+//! offsets in UTF-16 code units, where the specification counts characters.
+//!
+//! [`normalize`] rewrites such a CFI against the content document it points into, and reports
+//! whether the rewrite selects a different location than the CFI read as written.
+
+use std::io::{Read, Seek};
+
+use roxmltree::Node;
+
+use crate::{
+    epub::{self, Epub, Error},
+    resolve::{self, Document, Target},
+    syntax::{Fragment, Offset, Path},
+    tokens::{self, Token},
+};
+
+/// A reading system whose CFIs can be normalized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    /// epub.js, as used by many web readers: text steps index text nodes, and offsets are in
+    /// UTF-16 code units within a single text node.
+    EpubJs,
+    /// Apple Books: steps are indexed as specified, and offsets are in UTF-16 code units.
+    AppleBooks,
+}
+
+/// The result of [`normalize`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rewrite {
+    /// The fragment, as the specification reads it.
+    pub fragment: Fragment<'static>,
+    /// Whether the fragment selects a different location than the original, or the original
+    /// does not resolve at all.
+    pub changed: bool,
+}
+
+/// Rewrites a CFI produced by a reading system of the given dialect into a specification
+/// conforming one. Package document steps and assertions are kept as they are.
+pub fn normalize<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    fragment: &Fragment,
+    dialect: Dialect,
+) -> Result<Rewrite, Error> {
+    let (start, end) = epub::endpoints(fragment)?;
+    let start = normalize_path(epub, &start, dialect)?;
+    let normalized = match fragment.range() {
+        Some(_) => {
+            let end = normalize_path(epub, &end, dialect)?;
+            Fragment::from_range(&start, &end).ok_or(Error::RangeAcrossDocuments)?
+        }
+        None => Fragment::new(start),
+    };
+
+    let resolved = epub.resolve(&normalized)?;
+    let changed = match epub.resolve(fragment) {
+        Ok(original) => (original.start, original.end) != (resolved.start, resolved.end),
+        Err(_) => true,
+    };
+    Ok(Rewrite {
+        fragment: normalized,
+        changed,
+    })
+}
+
+fn normalize_path<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    path: &Path,
+    dialect: Dialect,
+) -> Result<Path<'static>, Error> {
+    let path = path.clone().into_owned();
+    let (steps, offset) = epub::content_point(&path)?;
+    let Some(last) = steps.last() else {
+        return Ok(path);
+    };
+    let href = epub.content_href(&path)?;
+    let source = epub.read_href(&href)?;
+    let document = Document::parse(&source)?;
+
+    let (size, offset) = match dialect {
+        Dialect::EpubJs if last.size % 2 == 1 => {
+            let missing = resolve::Error::MissingChild {
+                depth: steps.len() - 1,
+                index: last.size,
+            };
+            let Target::Element(parent) = document.walk(&steps[..steps.len() - 1])? else {
+                return Err(missing.into());
+            };
+            let node = parent
+                .children()
+                .filter(Node::is_text)
+                .nth((last.size / 2) as usize)
+                .ok_or(missing)?;
+            let run = node
+                .prev_siblings()
+                .skip(1)
+                .filter(Node::is_element)
+                .count();
+            let before: usize = node
+                .prev_siblings()
+                .skip(1)
+                .take_while(|sibling| !sibling.is_element())
+                .filter(Node::is_text)
+                .filter_map(|sibling| sibling.text())
+                .map(|text| text.chars().count())
+                .sum();
+            let offset = match offset {
+                Some(offset) => Some(before as u32 + chars(node.text().unwrap_or(""), offset)?),
+                None => None,
+            };
+            (2 * run as u32 + 1, offset)
+        }
+        Dialect::EpubJs => (last.size, offset),
+        Dialect::AppleBooks => {
+            let offset = match offset {
+                Some(offset) => Some(chars(
+                    &target_text(&document, document.walk(&steps)?),
+                    offset,
+                )?),
+                None => None,
+            };
+            (last.size, offset)
+        }
+    };
+
+    let mut tokens = tokens::flatten(&path);
+    for token in tokens.iter_mut().rev() {
+        match token {
+            Token::Offset(Offset::Character(character)) => {
+                character.start_at_point = offset.unwrap_or(character.start_at_point);
+            }
+            Token::Step(step) => {
+                step.size = size;
+                break;
+            }
+            _ => {}
+        }
+    }
+    tokens::path(tokens).ok_or(Error::NoContentDocument)
+}
+
+/// The text covered by a target, which a character offset into it indexes.
+fn target_text(document: &Document, target: Target) -> String {
+    match target {
+        Target::Element(element) => {
+            let (start, end) = document.span(element);
+            document.text_between(start, end).to_string()
+        }
+        Target::Text { parent, index } => {
+            let mut elements = 0;
+            let mut text = String::new();
+            for node in parent.children() {
+                if node.is_element() {
+                    elements += 1;
+                } else if elements == index && node.is_text() {
+                    text.extend(node.text());
+                }
+            }
+            text
+        }
+    }
+}
+
+/// Converts an offset in UTF-16 code units into `text` to one in characters.
+fn chars(text: &str, units: u32) -> Result<u32, resolve::Error> {
+    let mut counted = 0;
+    for (index, c) in text.chars().enumerate() {
+        if counted >= units {
+            return match counted == units {
+                true => Ok(index as u32),
+                false => Err(out_of_range(text, units)),
+            };
+        }
+        counted += c.len_utf16() as u32;
+    }
+    match counted == units {
+        true => Ok(text.chars().count() as u32),
+        false => Err(out_of_range(text, units)),
+    }
+}
+
+fn out_of_range(text: &str, units: u32) -> resolve::Error {
+    resolve::Error::OffsetOutOfRange {
+        offset: units,
+        length: text.encode_utf16().count(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::tests::{with_chapters, CHAPTER01};
+
+    const CHAPTER02: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>Chapter 2</title></head>
+<body><p><b>Note:</b><i>x</i> café 😀 rain<!--c-->more rain</p></body>
+</html>"#;
+
+    fn rewrite(cfi: &str, dialect: Dialect) -> (String, bool) {
+        let mut epub = with_chapters(CHAPTER01, CHAPTER02);
+        let rewrite = normalize(&mut epub, &cfi.parse().unwrap(), dialect).unwrap();
+        (rewrite.fragment.to_string(), rewrite.changed)
+    }
+
+    #[test]
+    fn test_epub_js() {
+        for (cfi, expected, changed) in [
+            // the first text node follows two elements, and the text after the comment is part
+            // of the same run of character data
+            ("epubcfi(/6/4!/4/2/1:8)", "epubcfi(/6/4!/4/2/5:7)", true),
+            (
+                "epubcfi(/6/4[chap02ref]!/4/2/3:2)",
+                "epubcfi(/6/4[chap02ref]!/4/2/5:14)",
+                true,
+            ),
+            (
+                "epubcfi(/6/4!/4/2/4/1:1)",
+                "epubcfi(/6/4!/4/2/4/1:1)",
+                false,
+            ),
+            ("epubcfi(/6/2!/4/4/1:3)", "epubcfi(/6/2!/4/4/1:3)", false),
+            ("epubcfi(/6/4!/4/2/2)", "epubcfi(/6/4!/4/2/2)", false),
+            (
+                "epubcfi(/6/4!/4/2,/1:1,/3:4)",
+                "epubcfi(/6/4!/4/2/5,:1,:16)",
+                true,
+            ),
+        ] {
+            assert_eq!(
+                rewrite(cfi, Dialect::EpubJs),
+                (expected.to_string(), changed),
+                "{}",
+                cfi
+            );
+        }
+    }
+
+    #[test]
+    fn test_apple_books() {
+        for (cfi, expected, changed) in [
+            ("epubcfi(/6/4!/4/2/5:8)", "epubcfi(/6/4!/4/2/5:7)", true),
+            ("epubcfi(/6/4!/4/2/5:5)", "epubcfi(/6/4!/4/2/5:5)", false),
+            ("epubcfi(/6/4!/4/2:14)", "epubcfi(/6/4!/4/2:13)", true),
+        ] {
+            assert_eq!(
+                rewrite(cfi, Dialect::AppleBooks),
+                (expected.to_string(), changed),
+                "{}",
+                cfi
+            );
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut epub = with_chapters(CHAPTER01, CHAPTER02);
+        let mut normalize =
+            |cfi: &str| normalize(&mut epub, &cfi.parse().unwrap(), Dialect::EpubJs).unwrap_err();
+        assert!(matches!(
+            normalize("epubcfi(/6/4!/4/2/5)"),
+            Error::Resolve(resolve::Error::MissingChild { depth: 2, index: 5 })
+        ));
+        // the offset falls between the two halves of the emoji
+        assert!(matches!(
+            normalize("epubcfi(/6/4!/4/2/1:7)"),
+            Error::Resolve(resolve::Error::OffsetOutOfRange { .. })
+        ));
+        assert!(matches!(
+            normalize("epubcfi(/6/8!/4)"),
+            Error::Resolve(resolve::Error::MissingChild { .. })
+        ));
+    }
+}
@@ -0,0 +1,899 @@
+//! Reading EPUB publications and resolving CFIs against them.
+//!
+//! A book-level CFI first walks the package document to an `itemref` in the spine, e.g.
+//! `/6/4[chap01ref]`, then redirects (`!`) into the content document that the `itemref`
+//! references and walks that document to the final location.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{self, Read, Seek},
+};
+
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{
+    resolve::{self, char_slice, AssertionMismatch, Document, Location, Side, Target},
+    syntax::{CharacterOffset, Fragment, LocalPath, Offset, Path, RedirectedPath, Step, ToOffset},
+    tokens::{self, Token},
+};
+
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+
+/// An EPUB publication read from a ZIP container.
+pub struct Epub<R> {
+    archive: ZipArchive<R>,
+    package_path: String,
+    package: Package,
+}
+
+impl Epub<File> {
+    pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self, Error> {
+        Self::from_reader(File::open(path)?)
+    }
+}
+
+impl<R: Read + Seek> Epub<R> {
+    pub fn from_reader(reader: R) -> Result<Self, Error> {
+        let mut archive = ZipArchive::new(reader)?;
+        let container = read_to_string(&mut archive, CONTAINER_PATH)?;
+        let package_path = Document::parse(&container)?
+            .root_element()
+            .descendants()
+            .find(|node| node.has_tag_name("rootfile"))
+            .and_then(|node| node.attribute("full-path"))
+            .ok_or(Error::InvalidContainer)?
+            .to_string();
+        let package = Package::parse(read_to_string(&mut archive, &package_path)?)?;
+        Ok(Self {
+            archive,
+            package_path,
+            package,
+        })
+    }
+
+    pub fn package(&self) -> &Package {
+        &self.package
+    }
+
+    /// The path of the package document within the container, e.g. `OEBPS/content.opf`.
+    pub fn package_path(&self) -> &str {
+        &self.package_path
+    }
+
+    /// Reads a file by its path within the container.
+    pub fn read(&mut self, path: &str) -> Result<String, Error> {
+        read_to_string(&mut self.archive, path)
+    }
+
+    /// Reads a file by its `href` relative to the package document.
+    pub fn read_href(&mut self, href: &str) -> Result<String, Error> {
+        let path = join(&self.package_path, href);
+        self.read(&path)
+    }
+
+    /// The `href`, relative to the package document, of the manifest item at `path`. The path
+    /// may be relative to the package document or to the container, with or without a leading
+    /// slash or a fragment identifier, or a URL that ends with the path within the container.
+    pub fn href_for_path(&self, path: &str) -> Option<String> {
+        let path = path.split('#').next().unwrap_or_default();
+        let path = path.trim_start_matches('/');
+        self.package
+            .manifest
+            .iter()
+            .find(|item| {
+                let full = join(&self.package_path, &item.href);
+                item.href == path || full == path || path.ends_with(&format!("/{}", full))
+            })
+            .map(|item| item.href.clone())
+    }
+
+    /// The `href`, relative to the package document, of the manifest item that `href` links to
+    /// from the file at `base`, which is itself relative to the package document.
+    pub(crate) fn link_href(&self, base: &str, href: &str) -> Option<String> {
+        self.href_for_path(&join(&join(&self.package_path, base), href))
+    }
+
+    /// Resolves a fragment to a location in one of the publication's content documents.
+    pub fn resolve(&mut self, fragment: &Fragment) -> Result<Resolved, Error> {
+        let package = Document::parse(&self.package.source)?;
+        let (href, mismatches) = self.fragment_href(&package, fragment)?;
+        let path = join(&self.package_path, &href);
+        let source = read_to_string(&mut self.archive, &path)?;
+        let document = Document::parse(&source)?;
+        resolve_in(&document, fragment, href, path, mismatches)
+    }
+
+    /// Resolves a batch of fragments like [`Epub::resolve`], parsing the package document once,
+    /// and each content document once however many of the fragments point into it.
+    pub(crate) fn resolve_all(&mut self, fragments: &[&Fragment]) -> Vec<Result<Resolved, Error>> {
+        let hrefs: Vec<_> = match Document::parse(&self.package.source) {
+            Ok(package) => fragments
+                .iter()
+                .map(|fragment| self.fragment_href(&package, fragment))
+                .collect(),
+            Err(_) => return fragments.iter().map(|f| self.resolve(f)).collect(),
+        };
+        let mut results: Vec<_> = fragments.iter().map(|_| None).collect();
+        let mut documents: BTreeMap<String, Vec<_>> = BTreeMap::new();
+        for (index, href) in hrefs.into_iter().enumerate() {
+            match href {
+                Ok((href, mismatches)) => {
+                    documents.entry(href).or_default().push((index, mismatches))
+                }
+                Err(e) => results[index] = Some(Err(e)),
+            }
+        }
+        for (href, fragments_in) in documents {
+            let path = join(&self.package_path, &href);
+            let source = read_to_string(&mut self.archive, &path);
+            let document = source.as_deref().map(Document::parse);
+            for (index, mismatches) in fragments_in {
+                results[index] = Some(match &document {
+                    Ok(Ok(document)) => resolve_in(
+                        document,
+                        fragments[index],
+                        href.clone(),
+                        path.clone(),
+                        mismatches,
+                    ),
+                    // resolve again for an error of this fragment's own
+                    _ => self.resolve(fragments[index]),
+                });
+            }
+        }
+        results.into_iter().flatten().collect()
+    }
+
+    /// Builds a fragment for character positions in the text of the content document at `href`,
+    /// as indexed by [`Resolved::text`]. When `end` is given the fragment is a range.
+    pub fn fragment(
+        &mut self,
+        href: &str,
+        start: usize,
+        end: Option<usize>,
+    ) -> Result<Fragment<'static>, Error> {
+        let package_steps = self.spine_steps(href)?;
+        let source = self.read_href(href)?;
+        let document = Document::parse(&source)?;
+        let point = |(steps, offset)| {
+            let content = path_from(steps, Some(offset));
+            let mut package_steps = package_steps.clone();
+            let package_step = package_steps.remove(0);
+            Path::new(
+                package_step,
+                LocalPath::new_with_redirected_path(package_steps, redirect(content)),
+            )
+        };
+        let start = point(document.locate(start, Side::Start)?);
+        let fragment = match end {
+            Some(end) => {
+                let end = point(document.locate(end, Side::End)?);
+                // both ends share the spine item's steps
+                Fragment::from_range(&start, &end).ok_or(Error::RangeAcrossDocuments)?
+            }
+            None => Fragment::new(start),
+        };
+        Ok(fragment)
+    }
+
+    /// Builds a range from the full paths to its start and end, which must be in the same content
+    /// document.
+    pub(crate) fn range(
+        &mut self,
+        start: &Path<'static>,
+        end: &Path<'static>,
+    ) -> Result<Fragment<'static>, Error> {
+        let fragment = Fragment::from_range(start, end).ok_or(Error::RangeAcrossDocuments)?;
+        // the ends may share their spine item's steps, and still be in different documents
+        self.resolve(&fragment)?;
+        Ok(fragment)
+    }
+
+    /// The path to a location in the content document at `href`, given by steps from its
+    /// document element and an optional character offset.
+    pub(crate) fn content_path(
+        &self,
+        href: &str,
+        steps: Vec<Step<'static>>,
+        offset: Option<u32>,
+    ) -> Result<Path<'static>, Error> {
+        let mut tokens: Vec<Token> = self
+            .spine_steps(href)?
+            .into_iter()
+            .map(Token::Step)
+            .collect();
+        tokens.push(Token::Redirect);
+        tokens.extend(steps.into_iter().map(Token::Step));
+        if let Some(offset) = offset {
+            tokens.push(Token::Offset(
+                CharacterOffset::new(offset, None).to_offset(),
+            ));
+        }
+        tokens::path(tokens).ok_or(Error::NoContentDocument)
+    }
+
+    /// The `href` of the content document that a path redirects into.
+    pub(crate) fn content_href(&self, path: &Path) -> Result<String, Error> {
+        let segments = segments(path, None)?;
+        let package = Document::parse(&self.package.source)?;
+        self.spine_href(&package, &segments[0], &mut vec![])
+    }
+
+    /// The package document steps that select the spine `itemref` for `href`.
+    pub(crate) fn spine_steps(&self, href: &str) -> Result<Vec<Step<'static>>, Error> {
+        let path = join(&self.package_path, href);
+        let index = self
+            .package
+            .spine
+            .iter()
+            .position(|itemref| {
+                self.package
+                    .item(&itemref.idref)
+                    .is_some_and(|item| join(&self.package_path, &item.href) == path)
+            })
+            .ok_or_else(|| Error::NotInSpine(href.to_string()))?;
+        let document = Document::parse(&self.package.source)?;
+        let itemref = document
+            .root_element()
+            .descendants()
+            .filter(|node| node.has_tag_name("itemref"))
+            .nth(index)
+            .ok_or_else(|| Error::NotInSpine(href.to_string()))?;
+        Ok(document.steps(Target::Element(itemref)))
+    }
+
+    /// The `href` of the content document that a fragment points into, and the assertions of
+    /// its package document steps that do not hold. Both ends of a range must point into the
+    /// same content document.
+    fn fragment_href(
+        &self,
+        package: &Document,
+        fragment: &Fragment,
+    ) -> Result<(String, Vec<AssertionMismatch>), Error> {
+        let (start, end) = fragment_segments(fragment)?;
+        let mut mismatches = vec![];
+        let href = self.spine_href(package, &start[0], &mut mismatches)?;
+        if let Some(end) = &end {
+            if self.spine_href(package, &end[0], &mut mismatches)? != href {
+                return Err(Error::RangeAcrossDocuments);
+            }
+        }
+        Ok((href, mismatches))
+    }
+
+    /// The `href` of the manifest item that a package document segment points to.
+    fn spine_href(
+        &self,
+        document: &Document,
+        segment: &Segment,
+        mismatches: &mut Vec<AssertionMismatch>,
+    ) -> Result<String, Error> {
+        push_unique(mismatches, document.verify(segment.steps.iter().copied())?);
+        let itemref = match document.walk(segment.steps.iter().copied())? {
+            Target::Element(node) if node.has_tag_name("itemref") && segment.offset.is_none() => {
+                node
+            }
+            _ => return Err(Error::NotASpineItem),
+        };
+        let idref = itemref.attribute("idref").unwrap_or_default();
+        self.package
+            .item(idref)
+            .map(|item| item.href.clone())
+            .ok_or_else(|| Error::UnknownItem(idref.to_string()))
+    }
+}
+
+/// A parsed EPUB package document (the `.opf` file).
+pub struct Package {
+    source: String,
+    manifest: Vec<ManifestItem>,
+    spine: Vec<SpineItem>,
+}
+
+impl Package {
+    pub fn parse(source: String) -> Result<Self, Error> {
+        let (manifest, spine) = {
+            let document = Document::parse(&source)?;
+            let root = document.root_element();
+            let manifest = root
+                .descendants()
+                .filter(|node| node.has_tag_name("item"))
+                .map(|node| ManifestItem {
+                    id: node.attribute("id").unwrap_or_default().to_string(),
+                    href: node.attribute("href").unwrap_or_default().to_string(),
+                    media_type: node.attribute("media-type").unwrap_or_default().to_string(),
+                    properties: node
+                        .attribute("properties")
+                        .unwrap_or_default()
+                        .split_whitespace()
+                        .map(str::to_string)
+                        .collect(),
+                })
+                .collect();
+            let spine = root
+                .descendants()
+                .filter(|node| node.has_tag_name("itemref"))
+                .map(|node| SpineItem {
+                    idref: node.attribute("idref").unwrap_or_default().to_string(),
+                    id: node.attribute("id").map(str::to_string),
+                    linear: node.attribute("linear") != Some("no"),
+                })
+                .collect();
+            (manifest, spine)
+        };
+        Ok(Self {
+            source,
+            manifest,
+            spine,
+        })
+    }
+
+    /// The package document's source.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn manifest(&self) -> &[ManifestItem] {
+        &self.manifest
+    }
+
+    pub fn spine(&self) -> &[SpineItem] {
+        &self.spine
+    }
+
+    /// Looks up a manifest item by its `id`.
+    pub fn item(&self, id: &str) -> Option<&ManifestItem> {
+        self.manifest.iter().find(|item| item.id == id)
+    }
+
+    /// The manifest item of the EPUB 3 navigation document.
+    pub fn nav(&self) -> Option<&ManifestItem> {
+        self.manifest
+            .iter()
+            .find(|item| item.properties.iter().any(|property| property == "nav"))
+    }
+
+    /// The manifest item of the EPUB 2 NCX document, `toc.ncx`.
+    pub fn ncx(&self) -> Option<&ManifestItem> {
+        self.manifest
+            .iter()
+            .find(|item| item.media_type == "application/x-dtbncx+xml")
+    }
+}
+
+/// An `item` of the package manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestItem {
+    pub id: String,
+    /// The location of the resource, relative to the package document.
+    pub href: String,
+    pub media_type: String,
+    /// The `properties` of the item, e.g. `nav` for the navigation document.
+    pub properties: Vec<String>,
+}
+
+/// An `itemref` of the package spine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpineItem {
+    pub idref: String,
+    pub id: Option<String>,
+    /// `false` when the item is marked `linear="no"`.
+    pub linear: bool,
+}
+
+/// A fragment resolved against a content document of an [`Epub`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolved {
+    /// The `href` of the content document, relative to the package document.
+    pub href: String,
+    /// The path of the content document within the container.
+    pub path: String,
+    /// The text of the content document. Positions of [`Location`]s index into it.
+    pub text: String,
+    pub start: Location,
+    /// The end of the range, for range fragments.
+    pub end: Option<Location>,
+    /// The id and text location assertions of the fragment that do not hold.
+    pub mismatches: Vec<AssertionMismatch>,
+}
+
+impl Resolved {
+    /// The text covered by a range fragment, or an empty string for a point.
+    pub fn covered_text(&self) -> &str {
+        let end = self.end.as_ref().unwrap_or(&self.start);
+        char_slice(&self.text, self.start.position, end.position)
+    }
+
+    /// Up to `length` characters of text before the start of the location.
+    pub fn text_before(&self, length: usize) -> &str {
+        let position = self.start.position;
+        char_slice(&self.text, position.saturating_sub(length), position)
+    }
+
+    /// Up to `length` characters of text after the end of the location.
+    pub fn text_after(&self, length: usize) -> &str {
+        let position = self.end.as_ref().unwrap_or(&self.start).position;
+        char_slice(&self.text, position, position + length)
+    }
+}
+
+/// Errors that can occur when reading an [`Epub`] or resolving a CFI against it.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Zip(ZipError),
+    /// A file is missing from the container.
+    MissingFile(String),
+    /// `META-INF/container.xml` does not reference a package document.
+    InvalidContainer,
+    Resolve(resolve::Error),
+    /// The package document part of the CFI does not select a spine `itemref`.
+    NotASpineItem,
+    /// A spine `itemref` references a manifest item that does not exist.
+    UnknownItem(String),
+    /// The CFI does not redirect into a content document.
+    NoContentDocument,
+    /// No spine item references the content document.
+    NotInSpine(String),
+    /// The start and end of a range are in different content documents.
+    RangeAcrossDocuments,
+    /// The CFI is valid but uses a feature that cannot be resolved.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Zip(e) => write!(f, "invalid EPUB container: {}", e),
+            Error::MissingFile(path) => write!(f, "missing file in EPUB container: {}", path),
+            Error::InvalidContainer => write!(f, "container.xml has no rootfile"),
+            Error::Resolve(e) => write!(f, "{}", e),
+            Error::NotASpineItem => write!(f, "CFI does not select a spine item"),
+            Error::UnknownItem(id) => write!(f, "spine references unknown manifest item {}", id),
+            Error::NoContentDocument => write!(f, "CFI does not redirect into a content document"),
+            Error::NotInSpine(href) => write!(f, "{} is not in the spine", href),
+            Error::RangeAcrossDocuments => write!(f, "range spans multiple content documents"),
+            Error::Unsupported(feature) => write!(f, "unsupported CFI: {}", feature),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Zip(e) => Some(e),
+            Error::Resolve(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ZipError> for Error {
+    fn from(e: ZipError) -> Self {
+        Error::Zip(e)
+    }
+}
+
+impl From<resolve::Error> for Error {
+    fn from(e: resolve::Error) -> Self {
+        Error::Resolve(e)
+    }
+}
+
+/// The steps and terminating offset of a path between two redirections.
+#[derive(Default)]
+struct Segment<'a> {
+    steps: Vec<&'a Step<'a>>,
+    offset: Option<&'a Offset<'a>>,
+}
+
+/// Resolves a fragment in the content document at `href` that it points into, given the
+/// assertions of its package document steps that do not hold.
+fn resolve_in(
+    document: &Document,
+    fragment: &Fragment,
+    href: String,
+    path: String,
+    mut mismatches: Vec<AssertionMismatch>,
+) -> Result<Resolved, Error> {
+    let (start, end) = fragment_segments(fragment)?;
+    let mut resolve = |segments: &[Segment]| -> Result<Location, Error> {
+        match segments {
+            [_, content] => {
+                let steps = content.steps.iter().copied();
+                push_unique(&mut mismatches, document.verify(steps.clone())?);
+                let location = document.resolve(steps, content.offset)?;
+                if let Some(Offset::Character(offset)) = content.offset {
+                    push_unique(&mut mismatches, document.verify_text(&location, offset));
+                }
+                Ok(location)
+            }
+            [_] => Err(Error::NoContentDocument),
+            _ => Err(Error::Unsupported("redirection out of a content document")),
+        }
+    };
+    let start = resolve(&start)?;
+    let end = end.as_deref().map(&mut resolve).transpose()?;
+    Ok(Resolved {
+        href,
+        path,
+        text: document.text().to_string(),
+        start,
+        end,
+        mismatches,
+    })
+}
+
+/// The segments of a fragment's path, continued by the start of its range, and by the end.
+fn fragment_segments<'a>(
+    fragment: &'a Fragment,
+) -> Result<(Vec<Segment<'a>>, Option<Vec<Segment<'a>>>), Error> {
+    let start = segments(fragment.path(), fragment.range().map(|r| r.start_point()))?;
+    let end = match fragment.range() {
+        Some(range) => Some(segments(fragment.path(), Some(range.end_point()))?),
+        None => None,
+    };
+    Ok((start, end))
+}
+
+/// Splits a path, optionally continued by one end of a range, at its redirections.
+fn segments<'a>(path: &'a Path, range: Option<&'a LocalPath>) -> Result<Vec<Segment<'a>>, Error> {
+    let mut segments = vec![Segment {
+        steps: vec![&path.step],
+        offset: None,
+    }];
+    push_local_path(&mut segments, &path.local_path);
+    if let Some(local_path) = range {
+        if segments
+            .last()
+            .is_some_and(|segment| segment.offset.is_some())
+        {
+            return Err(Error::Unsupported("range following an offset"));
+        }
+        push_local_path(&mut segments, local_path);
+    }
+    Ok(segments)
+}
+
+fn push_local_path<'a>(segments: &mut Vec<Segment<'a>>, local_path: &'a LocalPath) {
+    if let Some(segment) = segments.last_mut() {
+        segment.steps.extend(&local_path.steps);
+        if let Some(offset) = local_path.offset() {
+            segment.offset = Some(offset);
+        }
+    }
+    if let Some(redirected_path) = local_path.redirected_path() {
+        segments.push(Segment {
+            steps: vec![],
+            offset: redirected_path.offset(),
+        });
+        if let Some(path) = redirected_path.path() {
+            if let Some(segment) = segments.last_mut() {
+                segment.steps.push(&path.step);
+            }
+            push_local_path(segments, &path.local_path);
+        }
+    }
+}
+
+/// The full paths to the start and end of a fragment, which are the same for a point.
+pub(crate) fn endpoints<'a>(fragment: &Fragment<'a>) -> Result<(Path<'a>, Path<'a>), Error> {
+    fragment
+        .start()
+        .zip(fragment.end())
+        .ok_or(Error::Unsupported("invalid range"))
+}
+
+/// The steps of a path after its redirection into a content document, and its character offset.
+pub(crate) fn content_point<'a>(path: &Path<'a>) -> Result<(Vec<Step<'a>>, Option<u32>), Error> {
+    let mut tokens = tokens::flatten(path);
+    let redirect = tokens
+        .iter()
+        .position(|token| matches!(token, Token::Redirect))
+        .ok_or(Error::NoContentDocument)?;
+    let mut steps = vec![];
+    let mut offset = None;
+    for token in tokens.split_off(redirect + 1) {
+        match token {
+            Token::Step(step) => steps.push(step),
+            Token::Offset(Offset::Character(character)) => offset = Some(character.start_at_point),
+            Token::Offset(_) => return Err(Error::Unsupported("spatial or temporal offset")),
+            Token::Redirect => {
+                return Err(Error::Unsupported("redirection out of a content document"))
+            }
+        }
+    }
+    Ok((steps, offset))
+}
+
+/// Appends the mismatches not already reported, as both ends of a range share a path.
+fn push_unique(mismatches: &mut Vec<AssertionMismatch>, new: Vec<AssertionMismatch>) {
+    for mismatch in new {
+        if !mismatches.contains(&mismatch) {
+            mismatches.push(mismatch);
+        }
+    }
+}
+
+fn local_path(steps: Vec<Step>, offset: Option<u32>) -> LocalPath {
+    let offset = offset.map(|offset| CharacterOffset::new(offset, None).to_offset());
+    LocalPath::new_with_offset(steps, offset)
+}
+
+/// Builds a path from `steps`, which must not be empty, and an optional character offset.
+fn path_from(mut steps: Vec<Step>, offset: Option<u32>) -> Path {
+    let local_steps = steps.split_off(1);
+    Path::new(steps.remove(0), local_path(local_steps, offset))
+}
+
+fn redirect(path: Path) -> RedirectedPath {
+    RedirectedPath::Path(Box::new(path))
+}
+
+fn read_to_string<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    path: &str,
+) -> Result<String, Error> {
+    let mut file = match archive.by_name(path) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => return Err(Error::MissingFile(path.to_string())),
+        Err(e) => return Err(e.into()),
+    };
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Resolves `href` against the directory of the file at `base`, dropping any fragment.
+fn join(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let mut segments: Vec<&str> = base.split('/').collect();
+    segments.pop();
+    for segment in href.split('/') {
+        match segment {
+            "." | "" => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+    use crate::resolve::AssertionKind;
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+    pub(crate) const PACKAGE: &str = r#"<?xml version="1.0"?>
+<package version="3.0" unique-identifier="uid" xmlns="http://www.idpf.org/2007/opf">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:sample</dc:identifier>
+    <dc:title>Sample</dc:title>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="chap01" href="text/chapter01.xhtml" media-type="application/xhtml+xml"/>
+    <item id="chap02" href="text/chapter02.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="chap01" id="chap01ref"/>
+    <itemref idref="chap02" id="chap02ref"/>
+  </spine>
+</package>"#;
+
+    pub(crate) const NAV: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>Contents</title></head>
+<body>
+<nav epub:type="toc"><ol>
+<li><a href="text/chapter01.xhtml">Chapter 1</a></li>
+<li><a href="text/chapter02.xhtml#c2">Chapter 2</a></li>
+</ol></nav>
+<nav epub:type="page-list" hidden=""><ol>
+<li><a href="text/chapter01.xhtml">xi</a></li>
+<li><a href="text/chapter02.xhtml#c2">1</a></li>
+</ol></nav>
+</body>
+</html>"#;
+
+    pub(crate) const CHAPTER01: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>Chapter 1</title></head>
+<body><h1>Chapter 1</h1><p>It was a dark and stormy night.</p></body>
+</html>"#;
+
+    pub(crate) const CHAPTER02: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>Chapter 2</title></head>
+<body><h1 id="c2">Chapter 2</h1><p>The rain fell in <em>torrents</em> all night.</p></body>
+</html>"#;
+
+    /// A small two chapter publication for tests.
+    pub(crate) fn sample() -> Epub<Cursor<Vec<u8>>> {
+        with_chapters(CHAPTER01, CHAPTER02)
+    }
+
+    /// The sample publication with the given content documents.
+    pub(crate) fn with_chapters(chapter01: &str, chapter02: &str) -> Epub<Cursor<Vec<u8>>> {
+        with_package(PACKAGE, chapter01, chapter02)
+    }
+
+    /// The sample publication with the given package and content documents.
+    pub(crate) fn with_package(
+        package: &str,
+        chapter01: &str,
+        chapter02: &str,
+    ) -> Epub<Cursor<Vec<u8>>> {
+        with_files(&[
+            ("OEBPS/content.opf", package),
+            ("OEBPS/nav.xhtml", NAV),
+            ("OEBPS/text/chapter01.xhtml", chapter01),
+            ("OEBPS/text/chapter02.xhtml", chapter02),
+        ])
+    }
+
+    /// A publication with the package document at `OEBPS/content.opf` and the given files.
+    pub(crate) fn with_files(files: &[(&str, &str)]) -> Epub<Cursor<Vec<u8>>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let container = [
+            ("mimetype", "application/epub+zip"),
+            (CONTAINER_PATH, CONTAINER),
+        ];
+        for (path, content) in container.iter().chain(files) {
+            writer.start_file(*path, options).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        Epub::from_reader(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_package() {
+        let epub = sample();
+        assert_eq!(epub.package_path(), "OEBPS/content.opf");
+        assert_eq!(epub.package().manifest().len(), 3);
+        assert_eq!(
+            epub.package().spine()[1],
+            SpineItem {
+                idref: "chap02".to_string(),
+                id: Some("chap02ref".to_string()),
+                linear: true,
+            }
+        );
+        assert_eq!(
+            epub.package().item("chap01").unwrap().href,
+            "text/chapter01.xhtml"
+        );
+    }
+
+    #[test]
+    fn test_resolve_point() {
+        let mut epub = sample();
+        let resolved = epub
+            .resolve(&"epubcfi(/6/4!/4/4/1:8)".parse().unwrap())
+            .unwrap();
+        assert_eq!(resolved.href, "text/chapter02.xhtml");
+        assert_eq!(resolved.path, "OEBPS/text/chapter02.xhtml");
+        assert_eq!(resolved.start.node_path, "/html/body/p/text()[1]");
+        assert_eq!(resolved.covered_text(), "");
+        assert_eq!(resolved.text_before(4), "rain");
+        assert_eq!(resolved.text_after(8), " fell in");
+        assert_eq!(resolved.mismatches, vec![]);
+
+        let resolved = epub
+            .resolve(&"epubcfi(/6/4[2]!/4/2[1]/1:0)".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            resolved.mismatches,
+            vec![
+                AssertionMismatch {
+                    kind: AssertionKind::Id,
+                    node_path: "/package/spine/itemref[2]".to_string(),
+                    expected: "2".to_string(),
+                    actual: Some("chap02ref".to_string()),
+                },
+                AssertionMismatch {
+                    kind: AssertionKind::Id,
+                    node_path: "/html/body/h1".to_string(),
+                    expected: "1".to_string(),
+                    actual: Some("c2".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        let mut epub = sample();
+        let resolved = epub
+            .resolve(&"epubcfi(/6/4!/4/4,/1:4,/3:4)".parse().unwrap())
+            .unwrap();
+        assert_eq!(resolved.covered_text(), "rain fell in torrents all");
+        assert_eq!(resolved.end.unwrap().node_path, "/html/body/p/text()[2]");
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let mut epub = sample();
+        let mut resolve = |cfi: &str| epub.resolve(&cfi.parse().unwrap()).unwrap_err();
+        assert!(matches!(
+            resolve("epubcfi(/6/6!/4/2)"),
+            Error::Resolve(resolve::Error::MissingChild { depth: 1, index: 6 })
+        ));
+        assert!(matches!(
+            resolve("epubcfi(/4/2!/4/2)"),
+            Error::NotASpineItem
+        ));
+        assert!(matches!(resolve("epubcfi(/6/2)"), Error::NoContentDocument));
+    }
+
+    #[test]
+    fn test_fragment() {
+        let mut epub = sample();
+        let href = "text/chapter02.xhtml";
+        let fragment = |epub: &mut Epub<_>, text: &str| {
+            let resolved = epub.resolve(&"epubcfi(/6/4!/4)".parse().unwrap()).unwrap();
+            let start = resolved.text[..resolved.text.find(text).unwrap()]
+                .chars()
+                .count();
+            let end = start + text.chars().count();
+            let fragment = epub.fragment(href, start, Some(end)).unwrap();
+            assert_eq!(epub.resolve(&fragment).unwrap().covered_text(), text);
+            fragment.to_string()
+        };
+        assert_eq!(fragment(&mut epub, "rain"), "epubcfi(/6/4!/4/4/1,:4,:8)");
+        assert_eq!(
+            fragment(&mut epub, "rain fell in torrents"),
+            "epubcfi(/6/4!/4/4,/1:4,/2/1:8)"
+        );
+        assert_eq!(
+            fragment(&mut epub, "2The rain"),
+            "epubcfi(/6/4!/4,/2/1:8,/4/1:8)"
+        );
+        assert_eq!(
+            fragment(&mut epub, "2\nChapter"),
+            "epubcfi(/6/4,!/2/2/1:8,!/4/2/1:7)"
+        );
+        assert_eq!(
+            epub.fragment(href, 12, None).unwrap().to_string(),
+            "epubcfi(/6/4!/4/2/1:1)"
+        );
+        assert!(matches!(
+            epub.fragment("missing.xhtml", 0, None),
+            Err(Error::NotInSpine(_))
+        ));
+    }
+
+    #[test]
+    fn test_join() {
+        assert_eq!(
+            join("OEBPS/content.opf", "text/ch1.xhtml#p1"),
+            "OEBPS/text/ch1.xhtml"
+        );
+        assert_eq!(join("OEBPS/content.opf", "../ch1.xhtml"), "ch1.xhtml");
+        assert_eq!(join("content.opf", "./ch1.xhtml"), "ch1.xhtml");
+    }
+}
@@ -0,0 +1,320 @@
+//! Lenient parsing of the non-conformant CFIs that reading systems emit.
+//!
+//! Each deviation from the specification is fixed in turn, and the result is parsed as a normal
+//! CFI:
+//!
+//! 1. percent-encoded characters are decoded, e.g. `epubcfi(%2F6%2F4)`;
+//! 2. whitespace around the CFI and outside its assertions is removed;
+//! 3. a leading or trailing `#` is removed;
+//! 4. a bare path, e.g. `/6/4!/4/10/3:12`, is wrapped in `epubcfi(...)`;
+//! 5. brackets and parentheses inside assertions are escaped, e.g. `[para]05]`;
+//! 6. steps into the `kobo.N.N` spans that Kobo adds to its edition of a publication are undone
+//!    where possible.
+
+use std::fmt;
+
+use percent_encoding::percent_decode_str;
+
+use crate::{
+    syntax::{Fragment, Path},
+    tokens::{self, Token},
+    ParseError,
+};
+
+/// Options for [`Fragment::parse_with_options`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Accept and fix common deviations from the specification, reporting a [`ParseWarning`] for
+    /// each fix applied.
+    pub lenient: bool,
+}
+
+/// A deviation from the specification fixed by lenient parsing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseWarning {
+    /// The CFI was percent-encoded.
+    PercentEncoded,
+    /// Whitespace around or inside the CFI was removed.
+    Whitespace,
+    /// A `#` before or after the CFI was removed.
+    Hash,
+    /// The CFI was not wrapped in `epubcfi(...)`.
+    MissingWrapper,
+    /// A bracket or parenthesis inside an assertion was not escaped.
+    UnescapedBracket,
+    /// A step selects a Kobo span with the given id, which only exists in Kobo's edition of the
+    /// publication. A step to the first span of an element and the step into its text are
+    /// replaced by a step to the element's text. A step to any other span cannot be undone, and
+    /// is kept with its assertion so that resolving it against the original edition reports the
+    /// mismatch.
+    KoboSpan(String),
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseWarning::PercentEncoded => write!(f, "decoded percent-encoded characters"),
+            ParseWarning::Whitespace => write!(f, "removed whitespace"),
+            ParseWarning::Hash => write!(f, "removed '#'"),
+            ParseWarning::MissingWrapper => write!(f, "added the missing 'epubcfi(...)' wrapper"),
+            ParseWarning::UnescapedBracket => write!(f, "escaped a bracket inside an assertion"),
+            ParseWarning::KoboSpan(id) => write!(f, "found a step to the Kobo span {:?}", id),
+        }
+    }
+}
+
+impl Fragment<'_> {
+    /// Parses a CFI with the given options, returning the fragment and the warnings for each
+    /// deviation from the specification that was fixed. Without [`ParseOptions::lenient`], this
+    /// is [`Fragment::parse`] and there are no warnings.
+    ///
+    /// In lenient mode, the position of a [`ParseError`] is in the input after the fixes.
+    pub fn parse_with_options(
+        input: &str,
+        options: &ParseOptions,
+    ) -> Result<(Fragment<'static>, Vec<ParseWarning>), ParseError> {
+        if !options.lenient {
+            return Ok((Fragment::parse(input)?.into_owned(), vec![]));
+        }
+        let mut warnings = vec![];
+        let input = normalize(input, &mut warnings);
+        let fragment = Fragment::parse(&input)?.into_owned();
+        Ok((undo_kobo_spans(fragment, &mut warnings), warnings))
+    }
+}
+
+fn normalize(input: &str, warnings: &mut Vec<ParseWarning>) -> String {
+    let mut input = input.to_string();
+    if let Ok(decoded) = percent_decode_str(&input).decode_utf8() {
+        if decoded != input {
+            warnings.push(ParseWarning::PercentEncoded);
+            input = decoded.into_owned();
+        }
+    }
+
+    let trimmed = input.trim();
+    if trimmed.len() != input.len() {
+        warnings.push(ParseWarning::Whitespace);
+    }
+    let unhashed = trimmed.trim_matches('#');
+    if unhashed.len() != trimmed.len() {
+        warnings.push(ParseWarning::Hash);
+    }
+    let mut input = unhashed.to_string();
+    if input.starts_with('/') {
+        warnings.push(ParseWarning::MissingWrapper);
+        input = format!("epubcfi({})", input);
+    }
+
+    let mut output = String::with_capacity(input.len());
+    let mut in_assertion = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '^' if in_assertion => {
+                output.push(c);
+                output.extend(chars.next());
+                continue;
+            }
+            // an assertion only closes where a step, offset, redirection, range or the end of
+            // the CFI can follow
+            ']' if in_assertion
+                && matches!(
+                    chars.peek(),
+                    None | Some('/' | '!' | ':' | '@' | '~' | ',' | ')')
+                ) =>
+            {
+                in_assertion = false;
+            }
+            '[' | ']' | '(' | ')' if in_assertion => {
+                push_warning(warnings, ParseWarning::UnescapedBracket);
+                output.push('^');
+            }
+            '[' => in_assertion = true,
+            c if c.is_whitespace() && !in_assertion => {
+                push_warning(warnings, ParseWarning::Whitespace);
+                continue;
+            }
+            _ => {}
+        }
+        output.push(c);
+    }
+    output
+}
+
+fn push_warning(warnings: &mut Vec<ParseWarning>, warning: ParseWarning) {
+    if !warnings.contains(&warning) {
+        warnings.push(warning);
+    }
+}
+
+fn undo_kobo_spans(
+    fragment: Fragment<'static>,
+    warnings: &mut Vec<ParseWarning>,
+) -> Fragment<'static> {
+    let found = warnings.len();
+    let mut undo = |path: Option<Path<'static>>| {
+        let mut tokens = tokens::flatten(&path?);
+        let mut i = 0;
+        while i < tokens.len() {
+            let Some(id) = kobo_span(&tokens[i]) else {
+                i += 1;
+                continue;
+            };
+            push_warning(warnings, ParseWarning::KoboSpan(id.clone()));
+            let first_span = id.ends_with(".1")
+                && matches!(&tokens[i], Token::Step(step) if step.size == 2)
+                && matches!(tokens.get(i + 1), Some(Token::Step(step)) if step.size == 1);
+            if first_span {
+                tokens.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        tokens::path(tokens)
+    };
+    let undone = match fragment.range {
+        None => undo(Some(fragment.path.clone())).map(Fragment::new),
+        Some(_) => {
+            let (start, end) = (undo(fragment.start()), undo(fragment.end()));
+            start
+                .zip(end)
+                .and_then(|(start, end)| Fragment::from_range(&start, &end))
+        }
+    };
+    match undone {
+        Some(undone) if warnings.len() > found => undone,
+        _ => fragment,
+    }
+}
+
+/// The id of a Kobo span selected by a step, e.g. `kobo.12.3`.
+fn kobo_span(token: &Token) -> Option<String> {
+    let Token::Step(step) = token else {
+        return None;
+    };
+    let id = step.assertion.as_ref()?.value()?;
+    let mut numbers = id.strip_prefix("kobo.")?.split('.');
+    let is_number =
+        |n: Option<&str>| n.is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    (is_number(numbers.next()) && is_number(numbers.next()) && numbers.next().is_none())
+        .then(|| id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lenient(input: &str) -> (String, Vec<ParseWarning>) {
+        let options = ParseOptions { lenient: true };
+        let (fragment, warnings) = Fragment::parse_with_options(input, &options).unwrap();
+        (fragment.to_string(), warnings)
+    }
+
+    #[test]
+    fn test_strict() {
+        let options = ParseOptions::default();
+        assert!(Fragment::parse_with_options("/6/4!/4", &options).is_err());
+        let (fragment, warnings) =
+            Fragment::parse_with_options("epubcfi(/6/4!/4)", &options).unwrap();
+        assert_eq!(fragment.to_string(), "epubcfi(/6/4!/4)");
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_conformant() {
+        let cfi = "epubcfi(/6/4[chap01ref]!/4[body 01]/10[para^]05]/3:10[yyy,0123;s=b])";
+        assert_eq!(lenient(cfi), (cfi.to_string(), vec![]));
+    }
+
+    #[test]
+    fn test_fixes() {
+        use ParseWarning::*;
+
+        for (input, expected, warnings) in [
+            (
+                "/6/4!/4/10/3:12",
+                "epubcfi(/6/4!/4/10/3:12)",
+                vec![MissingWrapper],
+            ),
+            (
+                "  epubcfi(/6/4 !/4/10 /3:12)\n",
+                "epubcfi(/6/4!/4/10/3:12)",
+                vec![Whitespace],
+            ),
+            (
+                "#epubcfi(/6/4!/4/10/3:12)#",
+                "epubcfi(/6/4!/4/10/3:12)",
+                vec![Hash],
+            ),
+            (
+                "epubcfi(%2F6%2F4%5Bchap01ref%5D!%2F4%2F10%2F3:12)",
+                "epubcfi(/6/4[chap01ref]!/4/10/3:12)",
+                vec![PercentEncoded],
+            ),
+            (
+                "epubcfi(/6/4!/4[body01]/10[para]05]/3:10)",
+                "epubcfi(/6/4!/4[body01]/10[para^]05]/3:10)",
+                vec![UnescapedBracket],
+            ),
+            (
+                "epubcfi(/6/4!/4/10[note(1)]/2[a[1]])",
+                "epubcfi(/6/4!/4/10[note^(1^)]/2[a^[1^]])",
+                vec![UnescapedBracket],
+            ),
+            (
+                "#/6/4%5Bchap01ref%5D!/4/10[para]05]/3:10 ",
+                "epubcfi(/6/4[chap01ref]!/4/10[para^]05]/3:10)",
+                vec![
+                    PercentEncoded,
+                    Whitespace,
+                    Hash,
+                    MissingWrapper,
+                    UnescapedBracket,
+                ],
+            ),
+        ] {
+            assert_eq!(
+                lenient(input),
+                (expected.to_string(), warnings),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_kobo_spans() {
+        let kobo = |id: &str| vec![ParseWarning::KoboSpan(id.to_string())];
+        assert_eq!(
+            lenient("epubcfi(/6/4!/4/10/2[kobo.5.1]/1:3)"),
+            ("epubcfi(/6/4!/4/10/1:3)".to_string(), kobo("kobo.5.1"))
+        );
+        assert_eq!(
+            lenient("epubcfi(/6/4!/4/10/4[kobo.5.2]/1:3)"),
+            (
+                "epubcfi(/6/4!/4/10/4[kobo.5.2]/1:3)".to_string(),
+                kobo("kobo.5.2")
+            )
+        );
+        assert_eq!(
+            lenient("epubcfi(/6/4!/4/10/2[kobo.5.1],/1:3,/1:9)"),
+            ("epubcfi(/6/4!/4/10/1,:3,:9)".to_string(), kobo("kobo.5.1"))
+        );
+        // not a Kobo span id
+        let cfi = "epubcfi(/6/4!/4/10/2[kobo.five]/1:3)";
+        assert_eq!(lenient(cfi), (cfi.to_string(), vec![]));
+    }
+
+    #[test]
+    fn test_unfixable() {
+        let options = ParseOptions { lenient: true };
+        for input in ["", "epubcfi()", "6/4", "epubcfi(/6/4[para]05)"] {
+            assert!(
+                Fragment::parse_with_options(input, &options).is_err(),
+                "{}",
+                input
+            );
+        }
+    }
+}
@@ -1,5 +1,39 @@
+#[cfg(feature = "epub")]
+pub mod adobe;
+#[cfg(feature = "epub")]
+pub mod annotation;
+#[cfg(any(test, feature = "arbitrary", feature = "proptest"))]
+pub mod arbitrary;
+pub mod binary;
+pub mod builder;
+#[cfg(feature = "epub")]
+pub mod dialect;
+#[cfg(feature = "epub")]
+pub mod epub;
+mod lenient;
+#[cfg(feature = "epub")]
+pub mod link;
+#[cfg(feature = "epub")]
+pub mod nav;
 mod parsers;
+#[cfg(feature = "epub")]
+pub mod progress;
+#[cfg(feature = "epub")]
+pub mod readium;
+#[cfg(feature = "resolve")]
+pub mod resolve;
+#[cfg(feature = "resolve")]
+pub mod selector;
 pub mod syntax;
+mod tokens;
+pub mod url;
+#[cfg(feature = "epub")]
+pub mod validate;
+#[cfg(feature = "epub")]
+pub mod xpointer;
+
+pub use lenient::{ParseOptions, ParseWarning};
+pub use parsers::ParseError;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -0,0 +1,181 @@
+//! Conversion between CFIs and links to elements by id, e.g. `text/chapter01.xhtml#note12`.
+//!
+//! Internal links, footnote references and the entries of a table of contents point to an element
+//! of a content document by its id. [`from_href`] converts such a link to a CFI that selects the
+//! element and asserts its id, e.g. `epubcfi(/6/4!/4/10[note12])`, and [`to_href`] links to the
+//! nearest element with an id that contains the location of a CFI.
+
+use std::{
+    borrow::Cow,
+    fmt,
+    io::{Read, Seek},
+};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use roxmltree::Node;
+
+use crate::{
+    epub::{self, Epub},
+    resolve::{Document, Target},
+    syntax::{Assertion, Fragment},
+    url::FRAGMENT,
+};
+
+/// Converts a link to a CFI. The `href` is relative to the package document, or a path within the
+/// container. Without a fragment identifier the CFI selects the `body` of the content document.
+pub fn from_href<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    href: &str,
+) -> Result<Fragment<'static>, Error> {
+    let (path, id) = split_href(href);
+    let target = epub
+        .href_for_path(path)
+        .ok_or_else(|| epub::Error::NotInSpine(path.to_string()))?;
+    let source = epub.read_href(&target)?;
+    let document = Document::parse(&source).map_err(epub::Error::from)?;
+    let element = link_element(&document, id.as_deref())
+        .ok_or_else(|| Error::MissingElement(href.to_string()))?;
+
+    let mut steps = document.steps(Target::Element(element));
+    if let (Some(step), Some(id)) = (steps.last_mut(), id) {
+        step.assertion = Some(Assertion::new(None, Some(id.into_owned())));
+    }
+    Ok(Fragment::new(epub.content_path(&target, steps, None)?))
+}
+
+/// Splits a link into its path and its percent-decoded fragment identifier.
+pub(crate) fn split_href(href: &str) -> (&str, Option<Cow<'_, str>>) {
+    match href.split_once('#') {
+        Some((path, id)) => (path, Some(percent_decode_str(id).decode_utf8_lossy())),
+        None => (href, None),
+    }
+}
+
+/// The element of a content document with an id, or its `body` without one.
+pub(crate) fn link_element<'a, 'input>(
+    document: &'a Document<'input>,
+    id: Option<&str>,
+) -> Option<Node<'a, 'input>> {
+    let root = document.root_element();
+    match id {
+        Some(id) => root
+            .descendants()
+            .find(|node| node.attribute("id") == Some(id)),
+        None => root.children().find(|node| node.has_tag_name("body")),
+    }
+}
+
+/// Links to the nearest element with an id that contains the start of a fragment. The link is relative to the package document, and has no fragment
+/// identifier when no such element exists. The id is percent-encoded as [`from_href`] expects.
+pub fn to_href<R: Read + Seek>(epub: &mut Epub<R>, fragment: &Fragment) -> Result<String, Error> {
+    let (start, _) = epub::endpoints(fragment)?;
+    let resolved = epub.resolve(&Fragment::new(start.clone()))?;
+    let (steps, _) = epub::content_point(&start)?;
+    let source = epub.read_href(&resolved.href)?;
+    let document = Document::parse(&source).map_err(epub::Error::from)?;
+    let element = match document.walk(&steps).map_err(epub::Error::from)? {
+        Target::Element(element) => element,
+        Target::Text { parent, .. } => parent,
+    };
+    let id = element
+        .ancestors()
+        .filter(Node::is_element)
+        .find_map(|node| node.attribute("id"));
+    Ok(match id {
+        Some(id) => format!("{}#{}", resolved.href, utf8_percent_encode(id, FRAGMENT)),
+        None => resolved.href,
+    })
+}
+
+/// Errors that can occur when converting between CFIs and links.
+#[derive(Debug)]
+pub enum Error {
+    Epub(epub::Error),
+    /// The content document has no element with the id of the link.
+    MissingElement(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Epub(e) => write!(f, "{}", e),
+            Error::MissingElement(href) => write!(f, "no element for link: {}", href),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Epub(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<epub::Error> for Error {
+    fn from(e: epub::Error) -> Self {
+        Error::Epub(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::tests::{sample, with_chapters, CHAPTER01, CHAPTER02};
+
+    #[test]
+    fn test_from_href() {
+        let mut epub = sample();
+        for (href, cfi) in [
+            ("text/chapter02.xhtml#c2", "epubcfi(/6/4!/4/2[c2])"),
+            ("OEBPS/text/chapter02.xhtml#c2", "epubcfi(/6/4!/4/2[c2])"),
+            ("text/chapter01.xhtml", "epubcfi(/6/2!/4)"),
+        ] {
+            let fragment = from_href(&mut epub, href).unwrap();
+            assert_eq!(fragment.to_string(), cfi, "{}", href);
+            assert!(epub.resolve(&fragment).unwrap().mismatches.is_empty());
+        }
+        assert!(matches!(
+            from_href(&mut epub, "text/chapter02.xhtml#missing"),
+            Err(Error::MissingElement(_))
+        ));
+        assert!(matches!(
+            from_href(&mut epub, "text/chapter03.xhtml#c3"),
+            Err(Error::Epub(epub::Error::NotInSpine(_)))
+        ));
+    }
+
+    #[test]
+    fn test_to_href() {
+        let mut epub = sample();
+        for (cfi, href) in [
+            ("epubcfi(/6/4!/4/2[c2])", "text/chapter02.xhtml#c2"),
+            ("epubcfi(/6/4!/4/2/1:3)", "text/chapter02.xhtml#c2"),
+            ("epubcfi(/6/4!/4/2,/1:0,/1:3)", "text/chapter02.xhtml#c2"),
+            ("epubcfi(/6/4!/4/4/1:3)", "text/chapter02.xhtml"),
+            ("epubcfi(/6/2!/4)", "text/chapter01.xhtml"),
+        ] {
+            assert_eq!(
+                to_href(&mut epub, &cfi.parse().unwrap()).unwrap(),
+                href,
+                "{}",
+                cfi
+            );
+        }
+    }
+
+    #[test]
+    fn test_encoded_id() {
+        for (id, encoded) in [("café", "caf%C3%A9"), ("50%", "50%25"), ("a#b", "a%23b")] {
+            let chapter = CHAPTER02.replace(r#"id="c2""#, &format!(r#"id="{}""#, id));
+            let mut epub = with_chapters(CHAPTER01, &chapter);
+            let href = format!("text/chapter02.xhtml#{}", encoded);
+            let fragment = from_href(&mut epub, &href).unwrap();
+            let resolved = epub.resolve(&fragment).unwrap();
+            assert_eq!(resolved.start.node_path, "/html/body/h1", "{}", id);
+            assert!(resolved.mismatches.is_empty(), "{}", id);
+            assert_eq!(to_href(&mut epub, &fragment).unwrap(), href);
+        }
+    }
+}
@@ -0,0 +1,558 @@
+//! The navigation document of an EPUB 3 publication, and the NCX of an EPUB 2 one.
+//!
+//! A navigation document is an XHTML document with `nav` elements that link into the content
+//! documents. The `toc` nav is the table of contents, and the `page-list` nav maps the page
+//! numbers of a print edition to the locations where the pages begin, e.g.
+//! `<a href="chapter01.xhtml#page12">12</a>`. EPUB 2 publications have the table of contents in
+//! the `navMap` of an NCX document instead.
+//!
+//! Entries are ordered by the position of their locations in the publication, so that the entry
+//! or page a CFI falls in is the last one that begins at or before it.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{Read, Seek},
+};
+
+use roxmltree::Node;
+
+use crate::{
+    epub::{Epub, Error},
+    link::{link_element, split_href},
+    resolve::{Document, Target},
+    syntax::{Fragment, Step},
+    tokens::{self, Token},
+};
+
+/// The namespace of the `epub:type` attribute.
+const OPS_NAMESPACE: &str = "http://www.idpf.org/2007/ops";
+
+/// A page of a print edition.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page {
+    /// The page number, e.g. `12` or `xiv`.
+    pub label: String,
+    /// The location where the page begins.
+    pub fragment: Fragment<'static>,
+    /// The index of the content document in the spine and the position of the location in its
+    /// text, which order pages.
+    order: (usize, usize),
+}
+
+/// The pages of the `page-list` nav, in the order of the navigation document.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PageList {
+    pages: Vec<Page>,
+}
+
+impl PageList {
+    /// Reads the page list of the navigation document. The list is empty when the publication
+    /// has no navigation document or it has no `page-list` nav. Pages whose link does not lead
+    /// to an element of a content document in the spine are left out.
+    pub fn read<R: Read + Seek>(epub: &mut Epub<R>) -> Result<Self, Error> {
+        let Some(nav) = epub.package().nav().map(|item| item.href.clone()) else {
+            return Ok(Self::default());
+        };
+        let source = epub.read_href(&nav)?;
+        let document = Document::parse(&source)?;
+        let links: Vec<(String, String)> = document
+            .root_element()
+            .descendants()
+            .filter(|node| node.has_tag_name("nav") && has_type(node, "page-list"))
+            .flat_map(|nav| nav.descendants().filter(|node| node.has_tag_name("a")))
+            .filter_map(|a| Some((label(a), a.attribute("href")?.to_string())))
+            .collect();
+        let mut targets = Targets::default();
+        let mut pages = vec![];
+        for (label, href) in links {
+            if let Some(Link { fragment, order }) = targets.link(epub, &nav, &href)? {
+                pages.push(Page {
+                    label,
+                    fragment,
+                    order,
+                });
+            }
+        }
+        Ok(Self { pages })
+    }
+
+    pub fn pages(&self) -> &[Page] {
+        &self.pages
+    }
+
+    /// The page that a fragment starts on: the last page that begins at or before its start.
+    /// `None` when it starts before the first page.
+    pub fn page<R: Read + Seek>(
+        &self,
+        epub: &mut Epub<R>,
+        fragment: &Fragment,
+    ) -> Result<Option<&Page>, Error> {
+        let resolved = epub.resolve(fragment)?;
+        let index = spine_index(epub, &resolved.href)
+            .ok_or_else(|| Error::NotInSpine(resolved.href.clone()))?;
+        let order = (index, resolved.start.position);
+        Ok(self
+            .pages
+            .iter()
+            .filter(|page| page.order <= order)
+            .max_by_key(|page| page.order))
+    }
+
+    /// The location where the page with `label` begins.
+    pub fn fragment(&self, label: &str) -> Option<&Fragment<'static>> {
+        self.pages
+            .iter()
+            .find(|page| page.label == label)
+            .map(|page| &page.fragment)
+    }
+}
+
+/// An entry of the table of contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TocEntry {
+    pub label: String,
+    /// The location the entry links to. `None` for a heading without a link, or with a link that
+    /// does not lead to an element of a content document in the spine.
+    pub fragment: Option<Fragment<'static>>,
+    pub children: Vec<TocEntry>,
+    /// The order of the location, or of the first location of the children for an entry without
+    /// one.
+    order: Option<(usize, usize)>,
+}
+
+/// The table of contents of the `toc` nav, or of the NCX when there is no navigation document.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Toc {
+    entries: Vec<TocEntry>,
+}
+
+impl Toc {
+    /// Reads the table of contents. It is empty when the publication has neither a navigation
+    /// document nor an NCX.
+    pub fn read<R: Read + Seek>(epub: &mut Epub<R>) -> Result<Self, Error> {
+        let package = epub.package();
+        let (base, ncx) = match (package.nav(), package.ncx()) {
+            (Some(nav), _) => (nav.href.clone(), false),
+            (None, Some(ncx)) => (ncx.href.clone(), true),
+            (None, None) => return Ok(Self::default()),
+        };
+        let source = epub.read_href(&base)?;
+        let document = Document::parse(&source)?;
+        let root = document.root_element();
+        let items: Vec<Item> = match ncx {
+            false => root
+                .descendants()
+                .filter(|node| node.has_tag_name("nav") && has_type(node, "toc"))
+                .flat_map(|nav| nav.children().filter(|node| node.has_tag_name("ol")))
+                .flat_map(nav_items)
+                .collect(),
+            true => root
+                .children()
+                .filter(|node| node.has_tag_name("navMap"))
+                .flat_map(ncx_items)
+                .collect(),
+        };
+        let mut targets = Targets::default();
+        let mut entries = vec![];
+        for item in items {
+            entries.push(entry(epub, &mut targets, &base, item)?);
+        }
+        Ok(Self { entries })
+    }
+
+    /// The top level entries.
+    pub fn entries(&self) -> &[TocEntry] {
+        &self.entries
+    }
+
+    /// The deepest entry that contains the start of a fragment. An entry contains the positions
+    /// from its own location up to the location of the next entry at its level. `None` when the
+    /// start comes before the first entry.
+    pub fn entry<R: Read + Seek>(
+        &self,
+        epub: &mut Epub<R>,
+        fragment: &Fragment,
+    ) -> Result<Option<&TocEntry>, Error> {
+        let resolved = epub.resolve(fragment)?;
+        let index = spine_index(epub, &resolved.href)
+            .ok_or_else(|| Error::NotInSpine(resolved.href.clone()))?;
+        let order = (index, resolved.start.position);
+        let mut found = None;
+        let mut entries = self.entries.as_slice();
+        while let Some(entry) = containing(entries, order) {
+            found = Some(entry);
+            entries = &entry.children;
+        }
+        Ok(found)
+    }
+}
+
+/// The last of `entries` that begins at or before `order`.
+fn containing(entries: &[TocEntry], order: (usize, usize)) -> Option<&TocEntry> {
+    entries
+        .iter()
+        .filter(|entry| entry.order.is_some_and(|begin| begin <= order))
+        .max_by_key(|entry| entry.order)
+}
+
+/// An entry of the table of contents as written, before its link is followed.
+struct Item {
+    label: String,
+    href: Option<String>,
+    children: Vec<Item>,
+}
+
+/// The entries of a nav `ol`, each an `li` with an `a` or a `span` and an optional nested `ol`.
+fn nav_items(ol: Node) -> Vec<Item> {
+    ol.children()
+        .filter(|node| node.has_tag_name("li"))
+        .map(|li| {
+            let heading = li
+                .children()
+                .find(|node| node.has_tag_name("a") || node.has_tag_name("span"));
+            Item {
+                label: heading.map(label).unwrap_or_default(),
+                href: heading
+                    .and_then(|heading| heading.attribute("href"))
+                    .map(str::to_string),
+                children: li
+                    .children()
+                    .filter(|node| node.has_tag_name("ol"))
+                    .flat_map(nav_items)
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+/// The `navPoint`s of an NCX `navMap` or `navPoint`.
+fn ncx_items(parent: Node) -> Vec<Item> {
+    parent
+        .children()
+        .filter(|node| node.has_tag_name("navPoint"))
+        .map(|point| Item {
+            label: point
+                .children()
+                .find(|node| node.has_tag_name("navLabel"))
+                .map(label)
+                .unwrap_or_default(),
+            href: point
+                .children()
+                .find(|node| node.has_tag_name("content"))
+                .and_then(|content| content.attribute("src"))
+                .map(str::to_string),
+            children: ncx_items(point),
+        })
+        .collect()
+}
+
+fn entry<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    targets: &mut Targets,
+    base: &str,
+    item: Item,
+) -> Result<TocEntry, Error> {
+    let link = match &item.href {
+        Some(href) => targets.link(epub, base, href)?,
+        None => None,
+    };
+    let mut children = vec![];
+    for child in item.children {
+        children.push(entry(epub, targets, base, child)?);
+    }
+    let (fragment, order) = match link {
+        Some(Link { fragment, order }) => (Some(fragment), Some(order)),
+        None => (None, children.iter().filter_map(|child| child.order).min()),
+    };
+    Ok(TocEntry {
+        label: item.label,
+        fragment,
+        children,
+        order,
+    })
+}
+
+/// Whether the `epub:type` of a node includes `value`.
+fn has_type(node: &Node, value: &str) -> bool {
+    node.attribute((OPS_NAMESPACE, "type"))
+        .is_some_and(|types| types.split_whitespace().any(|t| t == value))
+}
+
+/// The text of an element, with whitespace collapsed.
+fn label(element: Node) -> String {
+    let text: String = element
+        .descendants()
+        .filter(Node::is_text)
+        .filter_map(|node| node.text())
+        .collect();
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn spine_index<R: Read + Seek>(epub: &Epub<R>, href: &str) -> Option<usize> {
+    let package = epub.package();
+    package.spine().iter().position(|itemref| {
+        package
+            .item(&itemref.idref)
+            .is_some_and(|item| item.href == href)
+    })
+}
+
+/// The location that a link leads to.
+struct Link {
+    fragment: Fragment<'static>,
+    /// The index of the content document in the spine and the position of the location in its
+    /// text.
+    order: (usize, usize),
+}
+
+/// The elements that links lead to, read once from each content document however many links
+/// lead into it.
+#[derive(Default)]
+struct Targets {
+    /// The targets of each content document by `href`, or `None` when it is not in the spine.
+    documents: HashMap<String, Option<DocumentTargets>>,
+}
+
+/// The elements that links can lead to in a content document.
+struct DocumentTargets {
+    /// The index of the content document in the spine.
+    index: usize,
+    /// The package document steps that select the content document's spine `itemref`.
+    spine_steps: Vec<Step<'static>>,
+    /// The steps and text position of the `body`.
+    body: Option<Element>,
+    /// The steps and text position of the first element with each id.
+    ids: HashMap<String, Element>,
+}
+
+/// The steps that select an element of a content document, and the position of its text.
+type Element = (Vec<Step<'static>>, usize);
+
+impl Targets {
+    /// The location that a link in the file at `base` leads to: the element with the id of its
+    /// fragment identifier, or the `body` of the content document without one. `None` when the
+    /// link does not lead to an element of a content document in the spine.
+    fn link<R: Read + Seek>(
+        &mut self,
+        epub: &mut Epub<R>,
+        base: &str,
+        href: &str,
+    ) -> Result<Option<Link>, Error> {
+        let Some(target) = epub.link_href(base, href) else {
+            return Ok(None);
+        };
+        let document = match self.documents.entry(target) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let document = DocumentTargets::read(epub, entry.key())?;
+                entry.insert(document)
+            }
+        };
+        let Some(document) = document else {
+            return Ok(None);
+        };
+        let element = match split_href(href).1 {
+            Some(id) => document.ids.get(id.as_ref()),
+            None => document.body.as_ref(),
+        };
+        let Some((steps, position)) = element else {
+            return Ok(None);
+        };
+        let mut tokens: Vec<_> = document
+            .spine_steps
+            .iter()
+            .cloned()
+            .map(Token::Step)
+            .collect();
+        tokens.push(Token::Redirect);
+        tokens.extend(steps.iter().cloned().map(Token::Step));
+        let path = tokens::path(tokens).ok_or(Error::NoContentDocument)?;
+        Ok(Some(Link {
+            fragment: Fragment::new(path),
+            order: (document.index, *position),
+        }))
+    }
+}
+
+impl DocumentTargets {
+    /// Reads the content document at `href`. `None` when it is not in the spine.
+    fn read<R: Read + Seek>(epub: &mut Epub<R>, href: &str) -> Result<Option<Self>, Error> {
+        let Some(index) = spine_index(epub, href) else {
+            return Ok(None);
+        };
+        let spine_steps = epub.spine_steps(href)?;
+        let source = epub.read_href(href)?;
+        let document = Document::parse(&source)?;
+        let element = |node| (document.steps(Target::Element(node)), document.span(node).0);
+        let mut ids = HashMap::new();
+        for node in document.root_element().descendants() {
+            if let Some(id) = node.attribute("id") {
+                ids.entry(id.to_string()).or_insert_with(|| element(node));
+            }
+        }
+        Ok(Some(Self {
+            index,
+            spine_steps,
+            body: link_element(&document, None).map(element),
+            ids,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::epub::tests::{sample, with_files, CHAPTER01, CHAPTER02, PACKAGE};
+
+    const NCX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+<navMap>
+  <navPoint id="p1"><navLabel><text>Chapter 1</text></navLabel>
+    <content src="text/chapter01.xhtml"/></navPoint>
+  <navPoint id="p2"><navLabel><text>Part 2</text></navLabel>
+    <content src="text/chapter02.xhtml"/>
+    <navPoint id="p3"><navLabel><text>Chapter  2</text></navLabel>
+      <content src="text/chapter02.xhtml#c2"/></navPoint>
+    <navPoint id="p4"><navLabel><text>Missing</text></navLabel>
+      <content src="text/chapter03.xhtml"/></navPoint>
+  </navPoint>
+</navMap>
+</ncx>"#;
+
+    fn lookup(epub: &mut Epub<Cursor<Vec<u8>>>, toc: &Toc, cfi: &str) -> Option<String> {
+        toc.entry(epub, &cfi.parse().unwrap())
+            .unwrap()
+            .map(|entry| entry.label.clone())
+    }
+
+    #[test]
+    fn test_read() {
+        let mut epub = sample();
+        let page_list = PageList::read(&mut epub).unwrap();
+        let pages: Vec<_> = page_list
+            .pages()
+            .iter()
+            .map(|page| (page.label.as_str(), page.fragment.to_string()))
+            .collect();
+        assert_eq!(
+            pages,
+            [
+                ("xi", "epubcfi(/6/2!/4)".to_string()),
+                ("1", "epubcfi(/6/4!/4/2)".to_string()),
+            ]
+        );
+        assert_eq!(
+            page_list.fragment("1").unwrap().to_string(),
+            "epubcfi(/6/4!/4/2)"
+        );
+        assert_eq!(page_list.fragment("2"), None);
+    }
+
+    #[test]
+    fn test_page() {
+        let mut epub = sample();
+        let page_list = PageList::read(&mut epub).unwrap();
+        for (cfi, label) in [
+            ("epubcfi(/6/2!/4/4/1:8)", Some("xi")),
+            ("epubcfi(/6/2!/4)", Some("xi")),
+            // the title in the head of the second chapter still belongs to the previous page
+            ("epubcfi(/6/4!/2/2/1:3)", Some("xi")),
+            ("epubcfi(/6/4!/4/2/1:0)", Some("1")),
+            ("epubcfi(/6/4!/4/4,/1:4,/2/1:3)", Some("1")),
+            ("epubcfi(/6/2!/2/2/1:0)", None),
+        ] {
+            let page = page_list
+                .page(&mut epub, &cfi.parse().unwrap())
+                .unwrap()
+                .map(|page| page.label.as_str());
+            assert_eq!(page, label, "{}", cfi);
+        }
+    }
+
+    #[test]
+    fn test_toc() {
+        let mut epub = sample();
+        let toc = Toc::read(&mut epub).unwrap();
+        let entries: Vec<_> = toc
+            .entries()
+            .iter()
+            .map(|entry| {
+                (
+                    entry.label.as_str(),
+                    entry.fragment.as_ref().unwrap().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("Chapter 1", "epubcfi(/6/2!/4)".to_string()),
+                ("Chapter 2", "epubcfi(/6/4!/4/2)".to_string()),
+            ]
+        );
+        for (cfi, label) in [
+            ("epubcfi(/6/2!/4/4/1:8)", Some("Chapter 1")),
+            ("epubcfi(/6/4!/4/4,/1:4,/2/1:3)", Some("Chapter 2")),
+            ("epubcfi(/6/2!/2/2/1:0)", None),
+        ] {
+            assert_eq!(lookup(&mut epub, &toc, cfi).as_deref(), label, "{}", cfi);
+        }
+    }
+
+    #[test]
+    fn test_ncx() {
+        let package = PACKAGE.replace(r#" properties="nav""#, "").replace(
+            "</manifest>",
+            r#"<item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/></manifest>"#,
+        );
+        let mut epub = with_files(&[
+            ("OEBPS/content.opf", &package),
+            ("OEBPS/toc.ncx", NCX),
+            ("OEBPS/text/chapter01.xhtml", CHAPTER01),
+            ("OEBPS/text/chapter02.xhtml", CHAPTER02),
+        ]);
+        let toc = Toc::read(&mut epub).unwrap();
+        let part = &toc.entries()[1];
+        assert_eq!(part.label, "Part 2");
+        assert_eq!(
+            part.fragment.as_ref().unwrap().to_string(),
+            "epubcfi(/6/4!/4)"
+        );
+        assert_eq!(part.children[0].label, "Chapter 2");
+        assert_eq!(part.children[1].fragment, None);
+
+        for (cfi, label) in [
+            ("epubcfi(/6/2!/4/4/1:8)", "Chapter 1"),
+            ("epubcfi(/6/4!/4/4/1:2)", "Chapter 2"),
+            // the head of the second chapter comes before its body
+            ("epubcfi(/6/4!/2/2/1:3)", "Chapter 1"),
+        ] {
+            assert_eq!(lookup(&mut epub, &toc, cfi).unwrap(), label, "{}", cfi);
+        }
+        assert!(PageList::read(&mut epub).unwrap().pages().is_empty());
+    }
+
+    #[test]
+    fn test_percent_encoded_id() {
+        let package = PACKAGE.replace(r#" properties="nav""#, "").replace(
+            "</manifest>",
+            r#"<item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/></manifest>"#,
+        );
+        let ncx = NCX.replace("chapter02.xhtml#c2", "chapter02.xhtml#caf%C3%A9");
+        let chapter = CHAPTER02.replace(r#"id="c2""#, r#"id="café""#);
+        let mut epub = with_files(&[
+            ("OEBPS/content.opf", &package),
+            ("OEBPS/toc.ncx", &ncx),
+            ("OEBPS/text/chapter01.xhtml", CHAPTER01),
+            ("OEBPS/text/chapter02.xhtml", &chapter),
+        ]);
+        let toc = Toc::read(&mut epub).unwrap();
+        let chapter = &toc.entries()[1].children[0];
+        assert_eq!(
+            chapter.fragment.as_ref().unwrap().to_string(),
+            "epubcfi(/6/4!/4/2)"
+        );
+    }
+}
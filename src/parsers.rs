@@ -1,31 +1,46 @@
-use core::panic;
+use std::{borrow::Cow, fmt, str::FromStr};
 
 use nom::{
     branch::alt,
     bytes::complete::tag,
-    character::complete::{alphanumeric1, digit1, u32, u8},
-    combinator::{map, opt},
-    multi::{many1, separated_list1},
-    number::complete::float,
-    sequence::{delimited, preceded, separated_pair, tuple},
+    character::complete::{digit1, u32},
+    combinator::{all_consuming, map, map_opt, opt, recognize, success},
+    error::ErrorKind,
+    multi::{many0, separated_list1},
+    sequence::{delimited, pair, preceded, separated_pair, tuple},
     IResult,
 };
 
+use crate::syntax::SPECIAL_CHARACTERS;
 use crate::syntax::*;
 
-fn offset(input: &str) -> IResult<&str, Offset> {
+fn offset(input: &str) -> IResult<&str, Offset<'_>> {
     alt((temporal_offset, spatial_offset, character_offset))(input)
 }
 
-fn character_offset(input: &str) -> IResult<&str, Offset> {
+fn character_offset(input: &str) -> IResult<&str, Offset<'_>> {
     let (input, point) = preceded(tag(":"), u32)(input)?;
     let (input, assertion) = opt(assertion)(input)?;
     Ok((input, CharacterOffset::new(point, assertion).to_offset()))
 }
 
-fn spatial_offset(input: &str) -> IResult<&str, Offset> {
+/// A non-negative decimal `number`, e.g. `3.5`. Unlike nom's `float`, this rejects exponents and
+/// `nan` or `inf`, which have no CFI syntax, as well as values too large for an `f32`.
+fn number(input: &str) -> IResult<&str, f32> {
+    map_opt(
+        recognize(pair(digit1, opt(pair(tag("."), digit1)))),
+        |number: &str| {
+            number
+                .parse()
+                .ok()
+                .filter(|number: &f32| number.is_finite())
+        },
+    )(input)
+}
+
+fn spatial_offset(input: &str) -> IResult<&str, Offset<'_>> {
     let (input, (start, end)) =
-        preceded(tag("@"), separated_pair(float, tag(":"), opt(float)))(input)?;
+        preceded(tag("@"), separated_pair(number, tag(":"), opt(number)))(input)?;
     let (input, maybe_assertion) = opt(assertion)(input)?;
     Ok((
         input,
@@ -33,10 +48,10 @@ fn spatial_offset(input: &str) -> IResult<&str, Offset> {
     ))
 }
 
-fn temporal_offset(input: &str) -> IResult<&str, Offset> {
-    let (input, offset) = preceded(tag("~"), float)(input)?;
+fn temporal_offset(input: &str) -> IResult<&str, Offset<'_>> {
+    let (input, offset) = preceded(tag("~"), number)(input)?;
     let (input, maybe_spatial_range) =
-        opt(preceded(tag("@"), separated_pair(float, tag(":"), float)))(input)?;
+        opt(preceded(tag("@"), separated_pair(number, tag(":"), number)))(input)?;
     let (input, maybe_assertion) = opt(assertion)(input)?;
     Ok((
         input,
@@ -47,96 +62,198 @@ fn temporal_offset(input: &str) -> IResult<&str, Offset> {
 /// A `step` starts with a slash, followed by an `integer` and an optional `assertion`.
 ///
 /// See [Step] for more details.
-pub fn step(input: &str) -> IResult<&str, Step> {
-    let (input, step_size) = preceded(tag("/"), u8)(input)?;
+pub fn step(input: &str) -> IResult<&str, Step<'_>> {
+    let (input, step_size) = preceded(tag("/"), u32)(input)?;
     let (input, maybe_assertion) = opt(assertion)(input)?;
     Ok((input, Step::new(step_size, maybe_assertion)))
 }
 
-fn assertion(input: &str) -> IResult<&str, Assertion> {
-    let (input, (params, value)) = delimited(tag("["), params_or_value, tag("]"))(input)?;
+/// An `assertion` is either a list of parameters, e.g. `[lang=en;role=note]`, or an optional id
+/// or text location assertion followed by optional parameters, e.g. `[para05]`, `[yyy,0123;s=b]`
+/// or `[;s=a]`.
+///
+/// See [Assertion] for more details.
+fn assertion(input: &str) -> IResult<&str, Assertion<'_>> {
+    delimited(
+        tag("["),
+        alt((
+            map(parameters, |parameters| {
+                Assertion::from_cow(Some(parameters), None)
+            }),
+            map_opt(
+                tuple((
+                    opt(value),
+                    opt(preceded(tag(","), value)),
+                    many0(preceded(tag(";"), parameter)),
+                )),
+                |(value, after, parameters)| {
+                    if value.is_none() && after.is_none() && parameters.is_empty() {
+                        return None;
+                    }
+                    let parameters = (!parameters.is_empty()).then(|| parameters.concat());
+                    let mut assertion = Assertion::from_cow(parameters, value);
+                    assertion.after = after;
+                    Some(assertion)
+                },
+            ),
+        )),
+        tag("]"),
+    )(input)
+}
+
+type Parameters<'a> = Vec<(Cow<'a, str>, Cow<'a, str>)>;
+
+/// A `parameter` is a key followed by one or more comma-separated values, e.g. `x=a,b`, giving one
+/// pair per value.
+fn parameter(input: &str) -> IResult<&str, Parameters<'_>> {
+    let (input, (key, values)) =
+        separated_pair(value_no_space, tag("="), separated_list1(tag(","), value))(input)?;
     Ok((
         input,
-        Assertion::new(
-            params.map(|p| {
-                p.iter()
-                    .map(|&pair| {
-                        let (k, v) = pair;
-                        (k.to_string(), v.to_string())
-                    })
-                    .collect()
-            }),
-            value.map(|s| s.to_string()),
-        ),
+        values
+            .into_iter()
+            .map(|value| (key.clone(), value))
+            .collect(),
     ))
 }
 
-fn parameter(input: &str) -> IResult<&str, (&str, &str)> {
-    separated_pair(alphanumeric1, tag("="), alphanumeric1)(input)
+fn parameters(input: &str) -> IResult<&str, Parameters<'_>> {
+    map(separated_list1(tag(";"), parameter), |parameters| {
+        parameters.concat()
+    })(input)
 }
 
-fn parameter1(input: &str) -> IResult<&str, Vec<(&str, &str)>> {
-    separated_list1(tag(";"), parameter)(input)
+fn value(input: &str) -> IResult<&str, Cow<'_, str>> {
+    escaped_value(input, true)
 }
 
-fn params_or_value(input: &str) -> IResult<&str, (Option<Vec<(&str, &str)>>, Option<&str>)> {
-    alt((
-        map(parameter1, |params| (Some(params), None)),
-        map(digit1, |value| (None, Some(value))),
-    ))(input)
+fn value_no_space(input: &str) -> IResult<&str, Cow<'_, str>> {
+    escaped_value(input, false)
 }
 
-fn local_path(input: &str) -> IResult<&str, LocalPath> {
-    let (input, steps) = many1(step)(input)?;
-    let (input, other) = alt((
-        map(redirected_path, |p| (Some(p), None)),
-        map(opt(offset), |o| (None, o)),
-    ))(input)?;
-
-    match other {
-        (Some(p), None) => Ok((input, LocalPath::new_with_redirected_path(steps, p))),
-        (None, o) => Ok((input, LocalPath::new_with_offset(steps, o))),
-        _ => panic!("Unrecoverable state with local_path paser"), // todo: handle with nom::Err::Failure
+/// A non-empty run of characters up to the next unescaped special character. The value borrows
+/// from `input` unless it contains escapes.
+fn escaped_value(input: &str, allow_space: bool) -> IResult<&str, Cow<'_, str>> {
+    let error = |input| nom::Err::Error(nom::error::Error::new(input, ErrorKind::Escaped));
+    let mut chars = input.char_indices();
+    let mut escaped = false;
+    let mut end = input.len();
+    while let Some((i, c)) = chars.next() {
+        if c == '^' {
+            match chars.next() {
+                Some((_, c)) if SPECIAL_CHARACTERS.contains(c) => escaped = true,
+                _ => return Err(error(&input[i..])),
+            }
+        } else if SPECIAL_CHARACTERS.contains(c) || (c == ' ' && !allow_space) {
+            end = i;
+            break;
+        }
+    }
+    if end == 0 {
+        return Err(error(input));
+    }
+    let (value, input) = input.split_at(end);
+    if !escaped {
+        return Ok((input, Cow::Borrowed(value)));
+    }
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(if c == '^' {
+            chars.next().unwrap_or(c)
+        } else {
+            c
+        });
     }
+    Ok((input, Cow::Owned(unescaped)))
 }
 
-fn redirected_path(input: &str) -> IResult<&str, RedirectedPath> {
-    let (input, (maybe_path, maybe_offset)) = preceded(tag("!"), path_or_offset)(input)?;
-    Ok((
-        input,
-        RedirectedPath::new(Box::new(maybe_offset), Box::new(maybe_path)),
-    ))
+fn local_path(input: &str) -> IResult<&str, LocalPath<'_>> {
+    let (input, steps) = many0(step)(input)?;
+    let (input, terminal) = alt((
+        map(redirected_path, Terminal::Redirect),
+        map(offset, Terminal::Offset),
+        success(Terminal::None),
+    ))(input)?;
+    Ok((input, LocalPath::new(steps, terminal)))
 }
 
-fn path_or_offset(input: &str) -> IResult<&str, (Option<Path>, Option<Offset>)> {
-    alt((
-        map(path, |p| (Some(p), None)),
-        map(offset, |o| (None, Some(o))),
-    ))(input)
+fn redirected_path(input: &str) -> IResult<&str, RedirectedPath<'_>> {
+    preceded(
+        tag("!"),
+        alt((
+            map(path, |p| RedirectedPath::Path(Box::new(p))),
+            map(offset, RedirectedPath::Offset),
+        )),
+    )(input)
 }
 
-fn path(input: &str) -> IResult<&str, Path> {
+fn path(input: &str) -> IResult<&str, Path<'_>> {
     let (input, (step, local)) = tuple((step, local_path))(input)?;
     Ok((input, Path::new(step, local)))
 }
 
-fn range(input: &str) -> IResult<&str, Range> {
+fn range(input: &str) -> IResult<&str, Range<'_>> {
     let (input, (start, end)) =
         preceded(tag(","), separated_pair(local_path, tag(","), local_path))(input)?;
     Ok((input, Range::new(start, end)))
 }
 
-fn fragment(input: &str) -> IResult<&str, Fragment> {
-    let (input, path) = preceded(
+fn fragment(input: &str) -> IResult<&str, Fragment<'_>> {
+    let (input, (path, maybe_range)) = preceded(
         tag("epubcfi"),
-        delimited(
-            tag("("),
-            // tuple(Path::from_str, opt(Range::from_str)),
-            path,
-            tag(")"),
-        ),
+        delimited(tag("("), tuple((path, opt(range))), tag(")")),
     )(input)?;
-    Ok((input, Fragment::new(path)))
+    match maybe_range {
+        Some(range) => Ok((input, Fragment::new_with_range(path, range))),
+        None => Ok((input, Fragment::new(path))),
+    }
+}
+
+/// The error returned when a string is not a valid CFI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    position: usize,
+}
+
+impl ParseError {
+    /// The byte position in the input at which parsing failed.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid CFI at position {}", self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl<'a> Fragment<'a> {
+    /// Parses a complete `epubcfi(...)` expression, borrowing assertion parameters and values
+    /// from `input`. Trailing input is an error.
+    pub fn parse(input: &'a str) -> Result<Self, ParseError> {
+        match all_consuming(fragment)(input) {
+            Ok((_, fragment)) => Ok(fragment),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(ParseError {
+                position: input.len() - e.input.len(),
+            }),
+            Err(nom::Err::Incomplete(_)) => Err(ParseError {
+                position: input.len(),
+            }),
+        }
+    }
+}
+
+impl FromStr for Fragment<'static> {
+    type Err = ParseError;
+
+    /// Parses a complete `epubcfi(...)` expression into a fragment that owns its data.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Fragment::parse(s).map(Fragment::into_owned)
+    }
 }
 
 #[cfg(test)]
@@ -227,45 +344,97 @@ mod tests {
     fn test_parser_parameter() {
         let (input, parsed) = parameter("id=section1").unwrap();
         assert_eq!("", input);
-        assert_eq!(("id", "section1"), parsed);
+        assert_eq!(vec![("id".into(), "section1".into())], parsed);
+
+        let (input, parsed) = parameter("x=a,b c]").unwrap();
+        assert_eq!("]", input);
+        assert_eq!(
+            vec![("x".into(), "a".into()), ("x".into(), "b c".into())],
+            parsed
+        );
     }
 
     #[test]
-    fn test_parser_parameter1() {
-        let (input, parsed) = parameter1("id=section1;class=image").unwrap();
+    fn test_parser_parameters() {
+        let (input, parsed) = parameters("id=section1;class=image").unwrap();
         assert_eq!("", input);
-        assert_eq!(vec![("id", "section1"), ("class", "image")], parsed);
+        assert_eq!(
+            vec![
+                ("id".into(), "section1".into()),
+                ("class".into(), "image".into())
+            ],
+            parsed
+        );
     }
 
     #[test]
-    fn test_parser_params_or_value() {
-        let (input, (maybe_params, maybe_value)) = params_or_value("8").unwrap();
+    fn test_parser_value() {
+        let (input, parsed) = value("para05]").unwrap();
+        assert_eq!("]", input);
+        assert!(matches!(parsed, Cow::Borrowed("para05")));
+
+        let (input, parsed) = value("para^]05^^,x").unwrap();
+        assert_eq!(",x", input);
+        assert_eq!("para]05^", parsed);
+
+        let (input, parsed) = value_no_space("a b").unwrap();
+        assert_eq!(" b", input);
+        assert_eq!("a", parsed);
+
+        assert!(value("]").is_err());
+        assert!(value("^a").is_err());
+        assert!(value("a^").is_err());
+    }
+
+    #[test]
+    fn test_parser_assertion() {
+        assert!(assertion("[]").is_err());
+        assert!(assertion("[,]").is_err());
+        assert!(assertion("[a=b;c]").is_err());
+
+        let (input, parsed) = assertion("[8]").unwrap();
         assert_eq!("", input);
-        assert_eq!(None, maybe_params);
-        assert_eq!(Some("8"), maybe_value);
+        assert_eq!(Assertion::new(None, Some("8".to_string())), parsed);
 
         // numbers are placed first to confirm that they do not parse as digits
-        let (input, (maybe_params, maybe_value)) =
-            params_or_value("1key=1value;2key=2value").unwrap();
-        assert_eq!("", input);
+        let (_, parsed) = assertion("[1key=1value;2key=2value]").unwrap();
         assert_eq!(
-            Some(vec![("1key", "1value"), ("2key", "2value")]),
-            maybe_params
+            Assertion::new(
+                Some(vec![
+                    ("1key".to_string(), "1value".to_string()),
+                    ("2key".to_string(), "2value".to_string())
+                ]),
+                None
+            ),
+            parsed
         );
-        assert_eq!(None, maybe_value);
+
+        let (_, parsed) = assertion("[yyy,0123;s=b]").unwrap();
+        assert_eq!(Some("yyy"), parsed.value());
+        assert_eq!(Some("0123"), parsed.after());
+        assert_eq!(Some("b"), parsed.parameter("s"));
+
+        let (_, parsed) = assertion("[,y]").unwrap();
+        assert_eq!((None, Some("y")), (parsed.value(), parsed.after()));
+
+        let (_, parsed) = assertion("[;s=a]").unwrap();
+        assert_eq!((None, Some("a")), (parsed.value(), parsed.parameter("s")));
     }
 
     #[test]
-    fn test_parser_assertion() {
-        let result = assertion("[]");
-        assert!(result.is_err());
-
-        // most of the assertion logic is tested with the params_or_value
-        // this is a sanity check
-        let (input, (maybe_params, maybe_value)) = params_or_value("8").unwrap();
-        assert_eq!("", input);
-        assert_eq!(None, maybe_params);
-        assert_eq!(Some("8"), maybe_value);
+    fn test_assertion_display_round_trip() {
+        for input in [
+            "[8]",
+            "[;lang=en;role=note]",
+            "[para^]05]",
+            "[yyy,0123;s=b]",
+            "[,y]",
+            "[x^,y;x=a,b;y=c]",
+            "[;s=a]",
+        ] {
+            let (_, parsed) = assertion(input).unwrap();
+            assert_eq!(input, parsed.to_string());
+        }
     }
 
     #[test]
@@ -274,29 +443,23 @@ mod tests {
             redirected_path("!/4/1"),
             Ok((
                 "",
-                RedirectedPath::new(
-                    Box::new(None),
-                    Box::new(Some(Path::new(
-                        Step::new(4, None),
-                        LocalPath::new_with_offset(vec![Step::new(1, None)], None)
-                    )))
-                )
+                RedirectedPath::Path(Box::new(Path::new(
+                    Step::new(4, None),
+                    LocalPath::new_with_offset(vec![Step::new(1, None)], None)
+                )))
             ))
         );
         assert_eq!(
             redirected_path("!/4/1:10"),
             Ok((
                 "",
-                RedirectedPath::new(
-                    Box::new(None),
-                    Box::new(Some(Path::new(
-                        Step::new(4, None),
-                        LocalPath::new_with_offset(
-                            vec![Step::new(1, None)],
-                            Some(CharacterOffset::new(10, None).to_offset())
-                        )
-                    )))
-                )
+                RedirectedPath::Path(Box::new(Path::new(
+                    Step::new(4, None),
+                    LocalPath::new_with_offset(
+                        vec![Step::new(1, None)],
+                        Some(CharacterOffset::new(10, None).to_offset())
+                    )
+                )))
             ))
         );
     }
@@ -322,6 +485,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parser_local_path_without_steps() {
+        assert_eq!(
+            local_path(":5").unwrap(),
+            (
+                "",
+                LocalPath::new_with_offset(vec![], Some(CharacterOffset::new(5, None).to_offset()))
+            )
+        );
+        assert_eq!(
+            fragment("epubcfi(/6/4!/4/10/3,:1,:5)")
+                .unwrap()
+                .1
+                .to_string(),
+            "epubcfi(/6/4!/4/10/3,:1,:5)"
+        );
+    }
+
     #[test]
     fn test_parser_path() {
         assert_eq!(
@@ -380,6 +561,114 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parser_fragment_range() {
+        assert_eq!(
+            fragment("epubcfi(/6/4!/4/10,/2/1:1,/3:4)").unwrap(),
+            (
+                "",
+                Fragment::new_with_range(
+                    Path::new(
+                        Step::new(6, None),
+                        LocalPath::new_with_redirected_path(
+                            vec![Step::new(4, None)],
+                            RedirectedPath::Path(Box::new(Path::new(
+                                Step::new(4, None),
+                                LocalPath::new_with_offset(vec![Step::new(10, None)], None)
+                            )))
+                        )
+                    ),
+                    Range::new(
+                        LocalPath::new_with_offset(
+                            vec![Step::new(2, None), Step::new(1, None)],
+                            Some(CharacterOffset::new(1, None).to_offset())
+                        ),
+                        LocalPath::new_with_offset(
+                            vec![Step::new(3, None)],
+                            Some(CharacterOffset::new(4, None).to_offset())
+                        )
+                    )
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_fragment_from_str() {
+        let fragment: Fragment = "epubcfi(/6/300!/4/2:10)".parse().unwrap();
+        assert_eq!(fragment.path().step, Step::new(6, None));
+        assert_eq!(fragment.path().local_path.steps, vec![Step::new(300, None)]);
+
+        let error = "epubcfi(/6/4)trailing".parse::<Fragment>().unwrap_err();
+        assert_eq!(error.position(), 13);
+        assert!("/6/4".parse::<Fragment>().is_err());
+    }
+
+    #[test]
+    fn test_fragment_malformed() {
+        for input in [
+            "",
+            "epubcfi(",
+            "epubcfi()",
+            "epubcfi(/)",
+            "epubcfi(/6/4!)",
+            "epubcfi(/6/4!!/2)",
+            "epubcfi(/6/4:)",
+            "epubcfi(/6/4:3/2)",
+            "epubcfi(/6/4@)",
+            "epubcfi(/6/4~)",
+            "epubcfi(/6/4[)",
+            "epubcfi(/6/4[lang=])",
+            "epubcfi(/6/4,/2)",
+            "epubcfi(/6/4,/2,/4,/6)",
+            "epubcfi(/99999999999)",
+            "epubcfi(/6/4:99999999999)",
+            "epubcfi(/6/é)",
+            "epubcfi(/6@nan:1)",
+            "epubcfi(/6~inf)",
+            "epubcfi(/6~1e5)",
+            "epubcfi(/6@-1:1)",
+            "epubcfi(/6~1000000000000000000000000000000000000000)",
+        ] {
+            let error = Fragment::parse(input).unwrap_err();
+            assert!(error.position() <= input.len(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_fragment_parse_borrowed() {
+        let input = String::from("epubcfi(/6/4!/4/2:10[;lang=en])");
+        let fragment = Fragment::parse(&input).unwrap();
+        let offset = fragment.path().offset().unwrap();
+        assert!(matches!(
+            offset,
+            Offset::Character(CharacterOffset { assertion: Some(assertion), .. })
+                if matches!(
+                    assertion.parameters(),
+                    Some([(Cow::Borrowed("lang"), Cow::Borrowed("en"))])
+                )
+        ));
+
+        let owned: Fragment<'static> = fragment.into_owned();
+        drop(input);
+        assert_eq!(owned.to_string(), "epubcfi(/6/4!/4/2:10[;lang=en])");
+    }
+
+    #[test]
+    fn test_fragment_display_round_trip() {
+        for cfi in [
+            "epubcfi(/6/2)",
+            "epubcfi(/6/2[2])",
+            "epubcfi(/6/2!/4/1:5)",
+            "epubcfi(/6/4!/4/10,/2/1:1,/3:4)",
+            "epubcfi(/6/4/2:10[;lang=en;id=a1])",
+            "epubcfi(/2/4/8@3.5:7.2)",
+            "epubcfi(/3/1!/7/2~2.7@0.5:1.5)",
+        ] {
+            assert_eq!(cfi.parse::<Fragment>().unwrap().to_string(), cfi);
+        }
+    }
+
     #[test]
     fn test_parser_fragment_complex() {
         assert_eq!(
@@ -390,59 +679,95 @@ mod tests {
                     Step::new(6, None),
                     LocalPath::new_with_redirected_path(
                         vec![Step::new(2, None)],
-                        RedirectedPath::new(
-                            Box::new(None),
-                            Box::new(Some(Path::new(
-                                Step::new(4, None),
-                                LocalPath::new_with_offset(
-                                    vec![Step::new(1, None)],
-                                    Some(CharacterOffset::new(5, None).to_offset())
-                                )
-                            )))
-                        )
+                        RedirectedPath::Path(Box::new(Path::new(
+                            Step::new(4, None),
+                            LocalPath::new_with_offset(
+                                vec![Step::new(1, None)],
+                                Some(CharacterOffset::new(5, None).to_offset())
+                            )
+                        )))
                     )
                 ))
             )
         );
-        // assert_eq!(
-        //     fragment("epubcfi(/4[lang=en]/2[role=section]/6/3!/5:10)").unwrap(),
-        //     (
-        //         "",
-        //         Fragment::new(Path::new(
-        //             Step::new(6, None),
-        //             LocalPath::new(vec![Step::new(2, None)], RedirectedPath::new(None, None))
-        //         ))
-        //     )
-        // );
-        // assert_eq!(
-        //     fragment("epubcfi(/2/4/8[role=note]@3.5:7.2)").unwrap(),
-        //     (
-        //         "",
-        //         Fragment::new(Path::new(
-        //             Step::new(6, None),
-        //             LocalPath::new(vec![Step::new(2, None)], RedirectedPath::new(None, None))
-        //         ))
-        //     )
-        // );
-        // assert_eq!(
-        //     fragment("epubcfi(/3/1!/7[lang=fr]/2~2.7)").unwrap(),
-        //     (
-        //         "",
-        //         Fragment::new(Path::new(
-        //             Step::new(6, None),
-        //             LocalPath::new(vec![Step::new(2, None)], RedirectedPath::new(None, None))
-        //         ))
-        //     )
-        // );
-        // assert_eq!(
-        //     fragment("epubcfi(/5/6[role=chapter]/2[lang=es]/3[role=section];id=sec2)").unwrap(),
-        //     (
-        //         "",
-        //         Fragment::new(Path::new(
-        //             Step::new(6, None),
-        //             LocalPath::new(vec![Step::new(2, None)], RedirectedPath::new(None, None))
-        //         ))
-        //     )
-        // );
+        let parameter = |key: &str, value: &str| {
+            Some(Assertion::new(
+                Some(vec![(key.to_string(), value.to_string())]),
+                None,
+            ))
+        };
+        assert_eq!(
+            fragment("epubcfi(/4[lang=en]/2[role=section]/6/3!/5:10)").unwrap(),
+            (
+                "",
+                Fragment::new(Path::new(
+                    Step::new(4, parameter("lang", "en")),
+                    LocalPath::new_with_redirected_path(
+                        vec![
+                            Step::new(2, parameter("role", "section")),
+                            Step::new(6, None),
+                            Step::new(3, None)
+                        ],
+                        RedirectedPath::Path(Box::new(Path::new(
+                            Step::new(5, None),
+                            LocalPath::new_with_offset(
+                                vec![],
+                                Some(CharacterOffset::new(10, None).to_offset())
+                            )
+                        )))
+                    )
+                ))
+            )
+        );
+        assert_eq!(
+            fragment("epubcfi(/2/4/8[role=note]@3.5:7.2)").unwrap(),
+            (
+                "",
+                Fragment::new(Path::new(
+                    Step::new(2, None),
+                    LocalPath::new_with_offset(
+                        vec![Step::new(4, None), Step::new(8, parameter("role", "note"))],
+                        Some(SpatialOffset::new(3.5, Some(7.2), None).to_offset())
+                    )
+                ))
+            )
+        );
+        assert_eq!(
+            fragment("epubcfi(/3/1!/7[lang=fr]/2~2.7)").unwrap(),
+            (
+                "",
+                Fragment::new(Path::new(
+                    Step::new(3, None),
+                    LocalPath::new_with_redirected_path(
+                        vec![Step::new(1, None)],
+                        RedirectedPath::Path(Box::new(Path::new(
+                            Step::new(7, parameter("lang", "fr")),
+                            LocalPath::new_with_offset(
+                                vec![Step::new(2, None)],
+                                Some(TemporalOffset::new(2.7, None, None).to_offset())
+                            )
+                        )))
+                    )
+                ))
+            )
+        );
+        // parameters belong inside the assertion brackets
+        assert!(
+            Fragment::parse("epubcfi(/5/6[role=chapter]/2[lang=es]/3[role=section];id=sec2)")
+                .is_err()
+        );
+        let parsed =
+            Fragment::parse("epubcfi(/5/6[role=chapter]/2[lang=es]/3[role=section;id=sec2])")
+                .unwrap();
+        assert_eq!(
+            parsed.path.local_path.steps[2].assertion,
+            Some(Assertion::new(
+                Some(vec![
+                    ("role".to_string(), "section".to_string()),
+                    ("id".to_string(), "sec2".to_string())
+                ]),
+                None
+            ))
+        );
     }
 }
@@ -0,0 +1,252 @@
+//! Reading progression through a content document and through the whole publication.
+//!
+//! Progression is measured in characters of the text of each `body`, as indexed by
+//! [`Resolved::text`], so that a position maps to the same fraction on every device regardless of
+//! pagination. The text of the `head`, such as the `title`, is not counted. Content documents
+//! in the spine that are marked `linear="no"`, such as footnotes or answer keys, can be left out
+//! of the publication's length.
+//!
+//! [`Resolved::text`]: crate::epub::Resolved::text
+
+use std::io::{Read, Seek};
+
+use crate::{
+    epub::{Epub, Error},
+    resolve::Document,
+    syntax::Fragment,
+};
+
+/// The progression of a position, each from 0 to 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// The `href` of the content document, relative to the package document.
+    pub href: String,
+    /// The progression through the content document.
+    pub progression: f64,
+    /// The progression through the publication. A position in a content document that is not
+    /// counted is at the progression of the start of that document.
+    pub total_progression: f64,
+}
+
+/// Which spine items count towards the length of the publication.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Spine {
+    /// Every item in the spine.
+    #[default]
+    All,
+    /// Only the items that are not marked `linear="no"`.
+    Linear,
+}
+
+/// The progression of the start of a fragment.
+pub fn progress<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    fragment: &Fragment,
+    spine: Spine,
+) -> Result<Progress, Error> {
+    let resolved = epub.resolve(fragment)?;
+    let extents = spine_extents(epub, spine)?;
+    let index = extents
+        .iter()
+        .position(|extent| extent.href == resolved.href)
+        .ok_or_else(|| Error::NotInSpine(resolved.href.clone()))?;
+    let source = epub.read_href(&resolved.href)?;
+    let (start, end) = measured(&Document::parse(&source)?);
+    // a position in the head counts as the start of the body
+    let position = resolved.start.position.clamp(start, end) - start;
+    let preceding: usize = extents[..index].iter().map(|extent| extent.length).sum();
+    let total: usize = extents.iter().map(|extent| extent.length).sum();
+    // a document that is not counted has no length
+    let counted = match extents[index].length {
+        0 => 0,
+        _ => position,
+    };
+    Ok(Progress {
+        href: resolved.href,
+        progression: ratio(position, end - start),
+        total_progression: ratio(preceding + counted, total),
+    })
+}
+
+/// Builds a fragment for the position at a progression, from 0 to 1, through the publication.
+/// A position at the boundary of two content documents is placed at the start of the second.
+pub fn from_progression<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    total_progression: f64,
+    spine: Spine,
+) -> Result<Fragment<'static>, Error> {
+    let extents = spine_extents(epub, spine)?;
+    let total: usize = extents.iter().map(|extent| extent.length).sum();
+    let mut position = (total_progression.clamp(0.0, 1.0) * total as f64).round() as usize;
+    let mut counted = extents
+        .into_iter()
+        .filter(|extent| extent.length > 0)
+        .peekable();
+    while let Some(extent) = counted.next() {
+        if position < extent.length || counted.peek().is_none() {
+            let position = extent.start + position.min(extent.length);
+            return epub.fragment(&extent.href, position, None);
+        }
+        position -= extent.length;
+    }
+    Err(Error::NoContentDocument)
+}
+
+/// The `[start, end)` span of the text of a content document that progression is measured over:
+/// the text of its `body`, or all of its text when it has none.
+pub(crate) fn measured(document: &Document) -> (usize, usize) {
+    document
+        .body_span()
+        .unwrap_or((0, document.text().chars().count()))
+}
+
+/// The measured text of a content document in the spine.
+struct Extent {
+    href: String,
+    /// The position in [`Document::text`] where the measured text starts.
+    start: usize,
+    /// The length of the measured text, which is 0 for a document that is not counted.
+    length: usize,
+}
+
+fn spine_extents<R: Read + Seek>(epub: &mut Epub<R>, spine: Spine) -> Result<Vec<Extent>, Error> {
+    let items: Vec<(String, bool)> = epub
+        .package()
+        .spine()
+        .iter()
+        .filter_map(|itemref| {
+            let item = epub.package().item(&itemref.idref)?;
+            Some((item.href.clone(), spine == Spine::All || itemref.linear))
+        })
+        .collect();
+    let mut extents = vec![];
+    for (href, counted) in items {
+        let (start, end) = match counted {
+            true => {
+                let source = epub.read_href(&href)?;
+                measured(&Document::parse(&source)?)
+            }
+            false => (0, 0),
+        };
+        extents.push(Extent {
+            href,
+            start,
+            length: end - start,
+        });
+    }
+    Ok(extents)
+}
+
+fn ratio(part: usize, whole: usize) -> f64 {
+    match whole {
+        0 => 0.0,
+        whole => part as f64 / whole as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::tests::{sample, with_chapters, with_package, CHAPTER01, CHAPTER02, PACKAGE};
+
+    fn measure(chapter: &str) -> (usize, usize) {
+        let (start, end) = measured(&Document::parse(chapter).unwrap());
+        (start, end - start)
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut epub = sample();
+        let ((start1, first), (start2, second)) = (measure(CHAPTER01), measure(CHAPTER02));
+        let total = (first + second) as f64;
+
+        let fragment = epub.fragment("text/chapter02.xhtml", start2, None).unwrap();
+        let at = progress(&mut epub, &fragment, Spine::All).unwrap();
+        assert_eq!(at.href, "text/chapter02.xhtml");
+        assert_eq!(at.progression, 0.0);
+        assert_eq!(at.total_progression, first as f64 / total);
+
+        let fragment = epub
+            .fragment("text/chapter01.xhtml", start1 + 10, Some(start1 + 20))
+            .unwrap();
+        let at = progress(&mut epub, &fragment, Spine::All).unwrap();
+        assert_eq!(at.progression, 10.0 / first as f64);
+        assert_eq!(at.total_progression, 10.0 / total);
+
+        let fragment = epub
+            .fragment("text/chapter02.xhtml", start2 + second, None)
+            .unwrap();
+        let at = progress(&mut epub, &fragment, Spine::All).unwrap();
+        assert_eq!((at.progression, at.total_progression), (1.0, 1.0));
+
+        // the title in the head is before the start of the body
+        let fragment = epub.fragment("text/chapter01.xhtml", 0, None).unwrap();
+        let at = progress(&mut epub, &fragment, Spine::All).unwrap();
+        assert_eq!((at.progression, at.total_progression), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_non_linear() {
+        let package = PACKAGE.replace(r#"id="chap01ref""#, r#"id="chap01ref" linear="no""#);
+        let mut epub = with_package(&package, CHAPTER01, CHAPTER02);
+        let ((start1, _), (start2, second)) = (measure(CHAPTER01), measure(CHAPTER02));
+
+        let fragment = epub
+            .fragment("text/chapter02.xhtml", start2 + 10, None)
+            .unwrap();
+        let at = progress(&mut epub, &fragment, Spine::Linear).unwrap();
+        assert_eq!(at.total_progression, 10.0 / second as f64);
+        let fragment = epub
+            .fragment("text/chapter01.xhtml", start1 + 10, None)
+            .unwrap();
+        let at = progress(&mut epub, &fragment, Spine::Linear).unwrap();
+        assert_eq!(at.total_progression, 0.0);
+        assert!(at.progression > 0.0);
+
+        let fragment = from_progression(&mut epub, 0.0, Spine::Linear).unwrap();
+        assert_eq!(
+            fragment,
+            epub.fragment("text/chapter02.xhtml", start2, None).unwrap()
+        );
+        let fragment = from_progression(&mut epub, 0.0, Spine::All).unwrap();
+        assert_eq!(fragment.to_string(), "epubcfi(/6/2!/4/2/1:0)");
+    }
+
+    #[test]
+    fn test_from_progression() {
+        let mut epub = sample();
+        let total = (measure(CHAPTER01).1 + measure(CHAPTER02).1) as f64;
+        for total_progression in [0.0, 0.1, 0.452, 0.5, 0.9, 1.0] {
+            let fragment = from_progression(&mut epub, total_progression, Spine::All).unwrap();
+            let at = progress(&mut epub, &fragment, Spine::All).unwrap();
+            assert!(
+                (at.total_progression - total_progression).abs() <= 0.5 / total,
+                "{} {}",
+                total_progression,
+                fragment
+            );
+        }
+        assert_eq!(
+            from_progression(&mut epub, 2.0, Spine::All).unwrap(),
+            from_progression(&mut epub, 1.0, Spine::All).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        let mut epub = with_chapters("<html><body><p>unclosed</body></html>", CHAPTER02);
+        let fragment = "epubcfi(/6/4!/4/2/1:0)".parse().unwrap();
+        assert!(matches!(
+            progress(&mut epub, &fragment, Spine::All),
+            Err(Error::Resolve(_))
+        ));
+        assert!(matches!(
+            from_progression(&mut epub, 0.5, Spine::All),
+            Err(Error::Resolve(_))
+        ));
+        // a document that is not counted is not read
+        let package = PACKAGE.replace(r#"id="chap01ref""#, r#"id="chap01ref" linear="no""#);
+        let mut epub = with_package(&package, "<html><body><p>unclosed</body></html>", CHAPTER02);
+        assert!(progress(&mut epub, &fragment, Spine::Linear).is_ok());
+    }
+}
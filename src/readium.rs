@@ -0,0 +1,468 @@
+//! Conversion between CFIs and the Locator objects of the Readium toolkits.
+//!
+//! A [`Locator`] identifies a location by the `href` of a resource, its progression through the
+//! resource and the publication, and a quote of the text around it. Readium keeps the CFI of a
+//! location within the content document in `locations.partialCfi`, e.g.
+//! `/4[body01]/10[para05]/3:10`, without the package document steps that select the spine item.
+//!
+//! With the `serde` feature, the types serialize to and from Readium's Locator JSON.
+
+use std::io::{Read, Seek};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    epub::{Epub, Error},
+    progress::{measured, progress, Spine},
+    resolve::{char_slice, find_quote, Document, Target},
+    syntax::{Fragment, Path, RedirectedPath, Terminal},
+    tokens::{self, Token},
+};
+
+/// Characters of text quoted on either side of a location in [`Text::before`] and
+/// [`Text::after`].
+const TEXT_CONTEXT: usize = 50;
+
+/// A location in a publication, as used by the Readium toolkits.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Locator {
+    /// The path of the resource within the container, e.g. `OEBPS/chapter01.xhtml`.
+    pub href: String,
+    /// The media type of the resource.
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub media_type: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub title: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub locations: Locations,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub text: Option<Text>,
+}
+
+/// The `locations` of a [`Locator`].
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "camelCase")
+)]
+pub struct Locations {
+    /// Fragment identifiers of the location within the resource, e.g. an element id.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub fragments: Vec<String>,
+    /// The progression through the resource, from 0 to 1.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub progression: Option<f64>,
+    /// The progression through the whole publication, from 0 to 1.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub total_progression: Option<f64>,
+    /// The index of the location in the publication's list of positions.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub position: Option<u32>,
+    /// The CFI of the location within the content document, without the `epubcfi(...)` wrapper.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub partial_cfi: Option<String>,
+}
+
+/// The `text` of a [`Locator`]: the text covered by the location and the text around it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Text {
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub before: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub highlight: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub after: Option<String>,
+}
+
+/// Converts a fragment into a Locator for the content document it points into.
+///
+/// Progressions are measured in characters of text, so `totalProgression` reads every content
+/// document in the spine.
+pub fn to_locator<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    fragment: &Fragment,
+) -> Result<Locator, Error> {
+    let resolved = epub.resolve(fragment)?;
+    let media_type = epub
+        .package()
+        .manifest()
+        .iter()
+        .find(|item| item.href == resolved.href)
+        .map(|item| item.media_type.clone())
+        .unwrap_or_default();
+    let progress = progress(epub, fragment, Spine::All)?;
+    let source = epub.read_href(&resolved.href)?;
+    let (body, body_end) = measured(&Document::parse(&source)?);
+    // the text around the location stops at the ends of the body
+    let start = resolved.start.position;
+    let end = resolved.end.as_ref().unwrap_or(&resolved.start).position;
+    let text = Text {
+        before: non_empty(char_slice(
+            &resolved.text,
+            start.saturating_sub(TEXT_CONTEXT).max(body),
+            start,
+        )),
+        highlight: non_empty(resolved.covered_text()),
+        after: non_empty(char_slice(
+            &resolved.text,
+            end,
+            (end + TEXT_CONTEXT).min(body_end),
+        )),
+    };
+    Ok(Locator {
+        href: resolved.path.clone(),
+        media_type,
+        title: None,
+        locations: Locations {
+            progression: Some(progress.progression),
+            total_progression: Some(progress.total_progression),
+            partial_cfi: partial_cfi(fragment),
+            ..Locations::default()
+        },
+        text: Some(text),
+    })
+}
+
+/// Converts a Locator into a fragment. The location is taken from the first of these that the
+/// Locator has and that can be resolved in the resource:
+///
+/// 1. `locations.partialCfi`, or a CFI in `locations.fragments`;
+/// 2. an element id in `locations.fragments`;
+/// 3. `text.highlight`, at the occurrence that best matches `text.before` and `text.after`;
+/// 4. `locations.progression`.
+///
+/// Otherwise the fragment points to the start of the resource.
+pub fn from_locator<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    locator: &Locator,
+) -> Result<Fragment<'static>, Error> {
+    let href = epub
+        .href_for_path(&locator.href)
+        .ok_or_else(|| Error::NotInSpine(locator.href.clone()))?;
+    let spine_steps = epub.spine_steps(&href)?;
+    let source = epub.read_href(&href)?;
+    let document = Document::parse(&source)?;
+
+    let locations = &locator.locations;
+    let cfis = locations.partial_cfi.iter().chain(
+        locations
+            .fragments
+            .iter()
+            .filter(|fragment| fragment.starts_with("epubcfi(")),
+    );
+    let spine: Vec<_> = spine_steps.into_iter().map(Token::Step).collect();
+    for cfi in cfis {
+        if let Some(fragment) = from_partial_cfi(&document, &spine, cfi) {
+            return Ok(fragment);
+        }
+    }
+    for id in &locations.fragments {
+        let id = id.trim_start_matches('#');
+        let element = document
+            .root_element()
+            .descendants()
+            .find(|node| node.attribute("id") == Some(id));
+        if let Some(element) = element {
+            let mut tokens = spine.clone();
+            tokens.push(Token::Redirect);
+            tokens.extend(
+                document
+                    .steps(Target::Element(element))
+                    .into_iter()
+                    .map(Token::Step),
+            );
+            if let Some(path) = tokens::path(tokens) {
+                return Ok(Fragment::new(path));
+            }
+        }
+    }
+
+    // the highlight and progression are within the body, not the title in the head
+    let (body, body_end) = measured(&document);
+    let text = char_slice(document.text(), body, body_end);
+    let quote = locator.text.as_ref().and_then(|quote| {
+        let highlight = quote.highlight.as_deref()?;
+        let before = quote.before.as_deref().unwrap_or_default();
+        let after = quote.after.as_deref().unwrap_or_default();
+        find_quote(text, highlight, before, after)
+    });
+    let (start, end) = match (quote, locations.progression) {
+        (Some((start, end)), _) => (body + start, Some(body + end)),
+        (None, Some(progression)) => {
+            let length = (body_end - body) as f64;
+            let position = (progression.clamp(0.0, 1.0) * length).round() as usize;
+            (body + position, None)
+        }
+        (None, None) => (body, None),
+    };
+    epub.fragment(&href, start, end)
+}
+
+/// The CFI of the fragment within its content document, e.g. `/4/10,/2/1:1,/3:4`.
+fn partial_cfi(fragment: &Fragment) -> Option<String> {
+    let spine = fragment.spine_item()?;
+    let content = |path: Path<'_>| {
+        let local_path = path.relative_to(&spine)?;
+        match local_path.terminal {
+            Terminal::Redirect(RedirectedPath::Path(path)) if local_path.steps.is_empty() => {
+                Some(path.into_owned())
+            }
+            _ => None,
+        }
+    };
+    let start = content(fragment.start()?)?;
+    let partial = match fragment.range {
+        Some(_) => Fragment::from_range(&start, &content(fragment.end()?)?)?,
+        None => Fragment::new(start),
+    };
+    let mut cfi = partial.path.to_string();
+    if let Some(range) = &partial.range {
+        cfi.push_str(&range.to_string());
+    }
+    Some(cfi)
+}
+
+/// Joins a CFI within a content document, with or without the `epubcfi(...)` wrapper, to the
+/// steps of its spine item. Returns `None` when it does not parse or resolve.
+fn from_partial_cfi(
+    document: &Document,
+    spine: &[Token<'static>],
+    cfi: &str,
+) -> Option<Fragment<'static>> {
+    let cfi = match cfi.starts_with("epubcfi(") {
+        true => cfi.to_string(),
+        false => format!("epubcfi({})", cfi),
+    };
+    let partial = Fragment::parse(&cfi).ok()?;
+    let full = |path: Path<'_>| {
+        if path.spine_item().is_some() {
+            return None;
+        }
+        let steps = std::iter::once(&path.step).chain(&path.local_path.steps);
+        document.resolve(steps, path.local_path.offset()).ok()?;
+        let mut tokens = spine.to_vec();
+        tokens.push(Token::Redirect);
+        tokens::push_path(&mut tokens, &path.into_owned());
+        tokens::path(tokens)
+    };
+    let start = full(partial.start()?)?;
+    match partial.range {
+        Some(_) => Fragment::from_range(&start, &full(partial.end()?)?),
+        None => Some(Fragment::new(start)),
+    }
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::tests::{sample, CHAPTER02};
+
+    /// A fragment covering the first occurrence of `text` in chapter two.
+    fn covering(epub: &mut Epub<impl Read + Seek>, text: &str) -> Fragment<'static> {
+        let resolved = epub.resolve(&"epubcfi(/6/4!/4)".parse().unwrap()).unwrap();
+        let start = resolved.text[..resolved.text.find(text).unwrap()]
+            .chars()
+            .count();
+        let end = start + text.chars().count();
+        epub.fragment("text/chapter02.xhtml", start, Some(end))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_to_locator() {
+        let mut epub = sample();
+        let fragment = covering(&mut epub, "torrents");
+        let locator = to_locator(&mut epub, &fragment).unwrap();
+        assert_eq!(locator.href, "OEBPS/text/chapter02.xhtml");
+        assert_eq!(locator.media_type, "application/xhtml+xml");
+        assert_eq!(
+            locator.locations.partial_cfi.as_deref(),
+            Some("/4/4/2/1,:0,:8")
+        );
+        let text = locator.text.unwrap();
+        assert_eq!(text.highlight.as_deref(), Some("torrents"));
+        assert!(text.before.unwrap().ends_with("The rain fell in "));
+        assert!(text.after.unwrap().starts_with(" all night."));
+        let progression = locator.locations.progression.unwrap();
+        let total_progression = locator.locations.total_progression.unwrap();
+        assert!(0.5 < progression && progression < 1.0);
+        assert!(progression < total_progression && total_progression < 1.0);
+
+        let locator = to_locator(&mut epub, &"epubcfi(/6/2!/4/4/1:0)".parse().unwrap()).unwrap();
+        assert_eq!(locator.locations.partial_cfi.as_deref(), Some("/4/4/1:0"));
+        assert_eq!(locator.text.unwrap().highlight, None);
+        assert!(locator.locations.total_progression < locator.locations.progression);
+
+        // the title in the head is not text before the start of the body
+        let heading = "epubcfi(/6/4!/4/2/1,:0,:9)".parse().unwrap();
+        let locator = to_locator(&mut epub, &heading).unwrap();
+        let text = locator.text.as_ref().unwrap();
+        assert_eq!(text.before, None);
+        assert_eq!(text.highlight.as_deref(), Some("Chapter 2"));
+        let locator = Locator {
+            href: locator.href,
+            text: Some(Text {
+                highlight: Some("Chapter 2".to_string()),
+                ..Text::default()
+            }),
+            ..Locator::default()
+        };
+        assert_eq!(from_locator(&mut epub, &locator).unwrap(), heading);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut epub = sample();
+        for fragment in [
+            covering(&mut epub, "torrents"),
+            covering(&mut epub, "rain fell in torrents"),
+            epub.fragment("text/chapter01.xhtml", 12, None).unwrap(),
+            "epubcfi(/6/4!/4[body]/2[c2])".parse().unwrap(),
+        ] {
+            let locator = to_locator(&mut epub, &fragment).unwrap();
+            assert_eq!(from_locator(&mut epub, &locator).unwrap(), fragment);
+        }
+    }
+
+    #[test]
+    fn test_from_locator_fallbacks() {
+        let mut epub = sample();
+        let href = "OEBPS/text/chapter02.xhtml".to_string();
+        let covered = |epub: &mut Epub<_>, locator: &Locator| {
+            let fragment = from_locator(epub, locator).unwrap();
+            epub.resolve(&fragment).unwrap()
+        };
+
+        // an unresolvable partial CFI falls back to the text
+        let locator = Locator {
+            href: format!("/{}", href),
+            locations: Locations {
+                partial_cfi: Some("/4/20/1:0".to_string()),
+                ..Locations::default()
+            },
+            text: Some(Text {
+                highlight: Some("all".to_string()),
+                before: Some("torrents ".to_string()),
+                ..Text::default()
+            }),
+            ..Locator::default()
+        };
+        assert_eq!(covered(&mut epub, &locator).covered_text(), "all");
+
+        let locator = Locator {
+            href: href.clone(),
+            locations: Locations {
+                fragments: vec!["c2".to_string()],
+                ..Locations::default()
+            },
+            ..Locator::default()
+        };
+        assert_eq!(
+            covered(&mut epub, &locator).start.node_path,
+            "/html/body/h1"
+        );
+
+        let locator = Locator {
+            href: href.clone(),
+            locations: Locations {
+                fragments: vec!["epubcfi(/4/4/1:4)".to_string()],
+                ..Locations::default()
+            },
+            ..Locator::default()
+        };
+        assert_eq!(
+            from_locator(&mut epub, &locator).unwrap().to_string(),
+            "epubcfi(/6/4!/4/4/1:4)"
+        );
+
+        let progression = |epub: &mut Epub<_>, progression| {
+            let locator = Locator {
+                href: href.clone(),
+                locations: Locations {
+                    progression: Some(progression),
+                    ..Locations::default()
+                },
+                ..Locator::default()
+            };
+            covered(epub, &locator).start.position
+        };
+        let (body, end) = measured(&Document::parse(CHAPTER02).unwrap());
+        assert_eq!(progression(&mut epub, 0.0), body);
+        assert_eq!(progression(&mut epub, 1.0), end);
+
+        let locator = Locator {
+            href: "OEBPS/missing.xhtml".to_string(),
+            ..Locator::default()
+        };
+        assert!(matches!(
+            from_locator(&mut epub, &locator),
+            Err(Error::NotInSpine(_))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json() {
+        let mut epub = sample();
+        let fragment = covering(&mut epub, "torrents");
+        let locator = to_locator(&mut epub, &fragment).unwrap();
+        let json = serde_json::to_value(&locator).unwrap();
+        assert_eq!(json["type"], "application/xhtml+xml");
+        assert_eq!(json["locations"]["partialCfi"], "/4/4/2/1,:0,:8");
+        assert!(json["locations"]["totalProgression"].is_f64());
+        assert_eq!(json["text"]["highlight"], "torrents");
+        assert!(json.get("title").is_none());
+
+        let locator: Locator = serde_json::from_str(
+            r#"{
+                "href": "/OEBPS/text/chapter02.xhtml",
+                "type": "application/xhtml+xml",
+                "title": "Chapter 2",
+                "locations": { "progression": 0.5, "totalProgression": 0.75, "position": 4 },
+                "text": { "highlight": "torrents" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(locator.title.as_deref(), Some("Chapter 2"));
+        assert_eq!(locator.locations.position, Some(4));
+        assert_eq!(from_locator(&mut epub, &locator).unwrap(), fragment);
+    }
+}
@@ -0,0 +1,632 @@
+//! Resolution of CFI steps against an XML document.
+//!
+//! A CFI step with an even index selects an element child, and a step with an odd index selects
+//! the run of character data between two element children. For example, given
+//! `<p>one<b>two</b>three</p>`, the children of `p` are `/1` ("one"), `/2` (`b`) and `/3`
+//! ("three"). The walk always starts at the document element, so `/4` in a content document
+//! selects `body`, the second element child of `html`.
+//!
+//! A [`Document`] also flattens every text node into a single string so that resolved locations
+//! can be compared, and the text between two locations extracted, using character positions.
+
+use std::fmt;
+
+use roxmltree::{Node, ParsingOptions};
+
+use crate::syntax::{CharacterOffset, Offset, Step};
+
+/// An XML document, such as an EPUB package or content document, that CFI steps can be
+/// resolved against.
+pub struct Document<'input> {
+    xml: roxmltree::Document<'input>,
+    text: String,
+    /// The `[start, end)` character span of every node in `text`, indexed by node id.
+    spans: Vec<(usize, usize)>,
+}
+
+impl<'input> Document<'input> {
+    pub fn parse(source: &'input str) -> Result<Self, Error> {
+        let options = ParsingOptions {
+            allow_dtd: true,
+            ..ParsingOptions::default()
+        };
+        let xml = roxmltree::Document::parse_with_options(source, options).map_err(Error::Xml)?;
+        let mut text = String::new();
+        let mut spans = vec![(0, 0); xml.descendants().count()];
+        index(xml.root(), &mut text, &mut 0, &mut spans);
+        Ok(Self { xml, text, spans })
+    }
+
+    /// The concatenated text of every text node in the document, in document order.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The text between two character positions of [`Document::text`].
+    pub fn text_between(&self, start: usize, end: usize) -> &str {
+        char_slice(&self.text, start, end)
+    }
+
+    /// The `[start, end)` character span in [`Document::text`] of the `body` element of an XHTML
+    /// document, which leaves out the text of its `head`.
+    pub fn body_span(&self) -> Option<(usize, usize)> {
+        let root = self.root_element();
+        let body = root.children().find(|node| node.has_tag_name("body"))?;
+        Some(self.span(body))
+    }
+
+    /// The steps, starting at the document element, and character offset that select
+    /// `position` in [`Document::text`].
+    pub fn locate(&self, position: usize, side: Side) -> Result<(Vec<Step<'static>>, u32), Error> {
+        let length = self.spans[0].1;
+        let out_of_range = || Error::PositionOutOfRange { position, length };
+        let texts = || self.xml.descendants().filter(Node::is_text);
+        let inside = |node: &Node| {
+            let (start, end) = self.span(*node);
+            match side {
+                Side::Start => start <= position && position < end,
+                Side::End => start < position && position <= end,
+            }
+        };
+        let touching = |node: &Node| {
+            let (start, end) = self.span(*node);
+            start <= position && position <= end
+        };
+        let node = texts()
+            .find(inside)
+            .or_else(|| texts().find(touching))
+            .ok_or_else(out_of_range)?;
+        let parent = node.parent_element().ok_or_else(out_of_range)?;
+        let target = Target::Text {
+            parent,
+            index: node.prev_siblings().filter(Node::is_element).count(),
+        };
+        let (start, _) = self.extent(target);
+        Ok((self.steps(target), (position - start) as u32))
+    }
+
+    /// Resolves `steps`, starting at the document element, and an optional terminating offset.
+    pub fn resolve<'s>(
+        &self,
+        steps: impl IntoIterator<Item = &'s Step<'s>>,
+        offset: Option<&Offset<'_>>,
+    ) -> Result<Location, Error> {
+        let target = self.walk(steps)?;
+        let (start, length) = self.extent(target);
+        let offset = match offset {
+            Some(Offset::Character(character)) => Some(character.start_at_point),
+            _ => None,
+        };
+        let position = match offset {
+            Some(offset) if offset as usize > length => {
+                return Err(Error::OffsetOutOfRange { offset, length })
+            }
+            Some(offset) => start + offset as usize,
+            None => start,
+        };
+        Ok(Location {
+            node_path: node_path(target),
+            offset,
+            position,
+        })
+    }
+
+    pub(crate) fn root_element<'a>(&'a self) -> Node<'a, 'input> {
+        self.xml.root_element()
+    }
+
+    /// Checks the id assertions of `steps`, e.g. `/4[body01]`, against the elements they select.
+    pub fn verify<'s>(
+        &self,
+        steps: impl IntoIterator<Item = &'s Step<'s>>,
+    ) -> Result<Vec<AssertionMismatch>, Error> {
+        let mut mismatches = vec![];
+        self.walk_with(steps, |step, target| {
+            let expected = step.assertion.as_ref().and_then(|a| a.value());
+            if let (Some(expected), Target::Element(element)) = (expected, target) {
+                let actual = element.attribute("id");
+                if actual != Some(expected) {
+                    mismatches.push(AssertionMismatch {
+                        kind: AssertionKind::Id,
+                        node_path: node_path(target),
+                        expected: expected.to_string(),
+                        actual: actual.map(str::to_string),
+                    });
+                }
+            }
+        })?;
+        Ok(mismatches)
+    }
+
+    /// Checks the text location assertion of a character offset, e.g. `:4[yyy,0123]`, against the
+    /// text before and after the location it resolved to.
+    pub fn verify_text(
+        &self,
+        location: &Location,
+        offset: &CharacterOffset,
+    ) -> Vec<AssertionMismatch> {
+        let Some(assertion) = &offset.assertion else {
+            return vec![];
+        };
+        let position = location.position;
+        let expected = [
+            (AssertionKind::TextBefore, assertion.value()),
+            (AssertionKind::TextAfter, assertion.after()),
+        ];
+        expected
+            .into_iter()
+            .filter_map(|(kind, expected)| {
+                let expected = expected?;
+                let length = expected.chars().count();
+                let actual = match kind {
+                    AssertionKind::TextBefore => {
+                        char_slice(&self.text, position.saturating_sub(length), position)
+                    }
+                    _ => char_slice(&self.text, position, position + length),
+                };
+                (actual != expected).then(|| AssertionMismatch {
+                    kind,
+                    node_path: location.node_path.clone(),
+                    expected: expected.to_string(),
+                    actual: Some(actual.to_string()),
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn walk<'a, 's>(
+        &'a self,
+        steps: impl IntoIterator<Item = &'s Step<'s>>,
+    ) -> Result<Target<'a, 'input>, Error> {
+        self.walk_with(steps, |_, _| {})
+    }
+
+    /// Walks `steps`, calling `visit` with each step and the node it selects.
+    fn walk_with<'a, 's>(
+        &'a self,
+        steps: impl IntoIterator<Item = &'s Step<'s>>,
+        mut visit: impl FnMut(&Step<'_>, Target<'a, 'input>),
+    ) -> Result<Target<'a, 'input>, Error> {
+        let mut target = Target::Element(self.root_element());
+        for (depth, step) in steps.into_iter().enumerate() {
+            let missing = Error::MissingChild {
+                depth,
+                index: step.size,
+            };
+            target = match target {
+                Target::Element(parent) => child(parent, step.size).ok_or(missing)?,
+                Target::Text { .. } => return Err(missing),
+            };
+            visit(step, target);
+        }
+        Ok(target)
+    }
+
+    /// The steps, starting at the document element, that select `target`.
+    pub(crate) fn steps(&self, target: Target) -> Vec<Step<'static>> {
+        let (mut node, mut steps) = match target {
+            Target::Element(element) => (element, vec![]),
+            Target::Text { parent, index } => (parent, vec![Step::new(2 * index as u32 + 1, None)]),
+        };
+        while let Some(parent) = node.parent_element() {
+            let index = node.prev_siblings().filter(Node::is_element).count();
+            steps.push(Step::new(2 * index as u32, None));
+            node = parent;
+        }
+        steps.reverse();
+        steps
+    }
+
+    /// The `[start, end)` character span of a node in [`Document::text`].
+    pub(crate) fn span(&self, node: Node) -> (usize, usize) {
+        self.spans[node.id().get_usize()]
+    }
+
+    /// The start position and length, in characters, of the text covered by `target`.
+    fn extent(&self, target: Target) -> (usize, usize) {
+        match target {
+            Target::Element(element) => {
+                let (start, end) = self.span(element);
+                (start, end - start)
+            }
+            Target::Text { parent, index } => {
+                let mut elements = 0;
+                let mut start = self.span(parent).0;
+                let mut end = start;
+                for node in parent.children() {
+                    if node.is_element() {
+                        elements += 1;
+                        if elements > index {
+                            break;
+                        }
+                        start = self.span(node).1;
+                        end = start;
+                    } else if elements == index {
+                        end = self.span(node).1;
+                    }
+                }
+                (start, end - start)
+            }
+        }
+    }
+}
+
+/// A node selected by a sequence of steps.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Target<'a, 'input> {
+    Element(Node<'a, 'input>),
+    /// The `index`th run of character data of `parent`, where `0` is the text before the first
+    /// element child.
+    Text {
+        parent: Node<'a, 'input>,
+        index: usize,
+    },
+}
+
+/// Which end of a range a position is located for.
+///
+/// A position between two text nodes is at the end of one and the start of the other. The start
+/// of a range is placed in the text that follows it and the end in the text that precedes it, so
+/// that neither selects a node that the range does not cover.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Start,
+    End,
+}
+
+/// The result of resolving a CFI against a [`Document`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// An XPath-like description of the target node, e.g. `/html/body/p[3]/text()[1]`.
+    pub node_path: String,
+    /// The character offset given by the CFI, if any.
+    pub offset: Option<u32>,
+    /// The position of the location in [`Document::text`], in characters.
+    pub position: usize,
+}
+
+/// An assertion that does not hold for the node its step or offset selects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssertionMismatch {
+    pub kind: AssertionKind,
+    /// An XPath-like description of the node, as in [`Location::node_path`].
+    pub node_path: String,
+    /// The id or text asserted by the CFI.
+    pub expected: String,
+    /// The element's actual id, if it has one, or the actual text.
+    pub actual: Option<String>,
+}
+
+/// What an [`AssertionMismatch`] asserts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AssertionKind {
+    /// The id of the element a step selects, e.g. `/4[body01]`.
+    Id,
+    /// The text before a character offset, e.g. `yyy` in `:0[yyy,0123]`.
+    TextBefore,
+    /// The text after a character offset, e.g. `0123` in `:0[yyy,0123]`.
+    TextAfter,
+}
+
+impl fmt::Display for AssertionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (expected, side) = match self.kind {
+            AssertionKind::Id => ("id", ""),
+            AssertionKind::TextBefore => ("text", " before the offset"),
+            AssertionKind::TextAfter => ("text", " after the offset"),
+        };
+        write!(
+            f,
+            "{}: expected {} {:?}{}, ",
+            self.node_path, expected, self.expected, side
+        )?;
+        match &self.actual {
+            Some(actual) => write!(f, "found {:?}", actual),
+            None => write!(f, "found no id"),
+        }
+    }
+}
+
+/// Errors that can occur when resolving a CFI against a [`Document`].
+#[derive(Debug)]
+pub enum Error {
+    /// The document is not well-formed XML.
+    Xml(roxmltree::Error),
+    /// The step at `depth` selects a child that does not exist.
+    MissingChild { depth: usize, index: u32 },
+    /// A character offset is past the end of the text it applies to.
+    OffsetOutOfRange { offset: u32, length: usize },
+    /// A text position is past the end of the document's text.
+    PositionOutOfRange { position: usize, length: usize },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Xml(e) => write!(f, "malformed document: {}", e),
+            Error::MissingChild { depth, index } => {
+                write!(f, "step {} (/{}) selects a missing child", depth + 1, index)
+            }
+            Error::OffsetOutOfRange { offset, length } => write!(
+                f,
+                "character offset {} is past the end of the text ({} characters)",
+                offset, length
+            ),
+            Error::PositionOutOfRange { position, length } => write!(
+                f,
+                "position {} is past the end of the text ({} characters)",
+                position, length
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Xml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the slice of `text` between two character positions, clamped to its length.
+pub(crate) fn char_slice(text: &str, start: usize, end: usize) -> &str {
+    let byte = |position: usize| {
+        text.char_indices()
+            .nth(position)
+            .map_or(text.len(), |(byte, _)| byte)
+    };
+    let start = byte(start);
+    &text[start..byte(end).max(start)]
+}
+
+/// The character positions of the occurrence of `exact` in `text` that best matches the text
+/// expected before and after it. Returns `None` when `exact` is empty or does not occur.
+#[cfg(feature = "epub")]
+pub(crate) fn find_quote(
+    text: &str,
+    exact: &str,
+    prefix: &str,
+    suffix: &str,
+) -> Option<(usize, usize)> {
+    if exact.is_empty() {
+        return None;
+    }
+    let mut best = None;
+    for (index, _) in text.match_indices(exact) {
+        let score = text[..index].ends_with(prefix) as u8
+            + text[index + exact.len()..].starts_with(suffix) as u8;
+        if best.is_none_or(|(_, best)| score > best) {
+            best = Some((index, score));
+        }
+    }
+    let (index, _) = best?;
+    let start = text[..index].chars().count();
+    Some((start, start + exact.chars().count()))
+}
+
+fn index(node: Node, text: &mut String, count: &mut usize, spans: &mut [(usize, usize)]) {
+    let start = *count;
+    if node.is_text() {
+        let content = node.text().unwrap_or_default();
+        text.push_str(content);
+        *count += content.chars().count();
+    }
+    for child in node.children() {
+        index(child, text, count, spans);
+    }
+    spans[node.id().get_usize()] = (start, *count);
+}
+
+fn child<'a, 'input>(parent: Node<'a, 'input>, index: u32) -> Option<Target<'a, 'input>> {
+    let mut elements = parent.children().filter(Node::is_element);
+    match index as usize {
+        0 => None,
+        index if index % 2 == 0 => elements.nth(index / 2 - 1).map(Target::Element),
+        index => (index / 2 <= elements.count()).then_some(Target::Text {
+            parent,
+            index: index / 2,
+        }),
+    }
+}
+
+fn node_path(target: Target) -> String {
+    match target {
+        Target::Element(element) => element_path(element),
+        Target::Text { parent, index } => {
+            let mut elements = 0;
+            let mut texts = 0;
+            for node in parent.children() {
+                if node.is_element() {
+                    elements += 1;
+                } else if node.is_text() && elements < index {
+                    texts += 1;
+                }
+            }
+            format!("{}/text()[{}]", element_path(parent), texts + 1)
+        }
+    }
+}
+
+pub(crate) fn element_path(element: Node) -> String {
+    let mut segments: Vec<String> = element
+        .ancestors()
+        .filter(Node::is_element)
+        .map(|node| {
+            let same_name = |sibling: &Node| sibling.tag_name() == node.tag_name();
+            let position = node.prev_siblings().filter(same_name).count();
+            if position == 1 && node.next_siblings().skip(1).filter(same_name).count() == 0 {
+                node.tag_name().name().to_string()
+            } else {
+                format!("{}[{}]", node.tag_name().name(), position)
+            }
+        })
+        .collect();
+    segments.push(String::new());
+    segments.reverse();
+    segments.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::{Assertion, CharacterOffset, ToOffset};
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>Chapter</title></head>
+<body><p>first</p><p id="second">one <b>two</b> three<!-- note --> four</p></body>
+</html>"#;
+
+    fn steps(sizes: &[u32]) -> Vec<Step<'static>> {
+        sizes.iter().map(|&size| Step::new(size, None)).collect()
+    }
+
+    #[test]
+    fn test_resolve_element() {
+        let document = Document::parse(CHAPTER).unwrap();
+        let location = document.resolve(&steps(&[4, 4]), None).unwrap();
+        assert_eq!(location.node_path, "/html/body/p[2]");
+        assert_eq!(
+            document.text_between(location.position, location.position + 3),
+            "one"
+        );
+    }
+
+    #[test]
+    fn test_body_span() {
+        let document = Document::parse(CHAPTER).unwrap();
+        let (start, end) = document.body_span().unwrap();
+        assert_eq!(document.text_between(start, end), "firstone two three four");
+        assert_eq!(Document::parse("<svg/>").unwrap().body_span(), None);
+    }
+
+    #[test]
+    fn test_resolve_text_with_offset() {
+        let document = Document::parse(CHAPTER).unwrap();
+        let offset = CharacterOffset::new(1, None).to_offset();
+        let location = document.resolve(&steps(&[4, 4, 3]), Some(&offset)).unwrap();
+        assert_eq!(location.node_path, "/html/body/p[2]/text()[2]");
+        assert_eq!(location.offset, Some(1));
+        assert_eq!(
+            document.text_between(location.position, location.position + 3),
+            "thr"
+        );
+
+        // the text chunk spans the comment
+        let offset = CharacterOffset::new(7, None).to_offset();
+        let location = document.resolve(&steps(&[4, 4, 3]), Some(&offset)).unwrap();
+        assert_eq!(
+            document.text_between(location.position, location.position + 4),
+            "four"
+        );
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let document = Document::parse(CHAPTER).unwrap();
+        assert!(matches!(
+            document.resolve(&steps(&[4, 6]), None),
+            Err(Error::MissingChild { depth: 1, index: 6 })
+        ));
+        assert!(matches!(
+            document.resolve(&steps(&[4, 2, 1, 2]), None),
+            Err(Error::MissingChild { depth: 3, index: 2 })
+        ));
+        let offset = CharacterOffset::new(6, None).to_offset();
+        assert!(matches!(
+            document.resolve(&steps(&[4, 2, 1]), Some(&offset)),
+            Err(Error::OffsetOutOfRange {
+                offset: 6,
+                length: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn test_verify() {
+        let document = Document::parse(CHAPTER).unwrap();
+        let mut path = steps(&[4, 2, 1]);
+        assert_eq!(document.verify(&path).unwrap(), vec![]);
+
+        path[1].assertion = Some(Assertion::new(None, Some("2".to_string())));
+        assert_eq!(
+            document.verify(&path).unwrap(),
+            vec![AssertionMismatch {
+                kind: AssertionKind::Id,
+                node_path: "/html/body/p[1]".to_string(),
+                expected: "2".to_string(),
+                actual: None,
+            }]
+        );
+        path[1] = Step::new(4, Some(Assertion::new(None, Some("second".to_string()))));
+        assert_eq!(document.verify(&path).unwrap(), vec![]);
+        assert!(document.verify(&steps(&[8])).is_err());
+    }
+
+    #[test]
+    fn test_verify_text() {
+        let document = Document::parse(CHAPTER).unwrap();
+        let path = steps(&[4, 4, 3]);
+        let verify = |before: &str, after: &str| {
+            let assertion = Assertion::text_location(
+                Some(before.to_string()).filter(|before| !before.is_empty()),
+                Some(after.to_string()).filter(|after| !after.is_empty()),
+            );
+            let offset = CharacterOffset::new(0, Some(assertion));
+            let location = document.resolve(&path, Some(&offset.to_offset())).unwrap();
+            document.verify_text(&location, &offset)
+        };
+        assert_eq!(verify("two", " three"), vec![]);
+        assert_eq!(verify("one two", ""), vec![]);
+        assert_eq!(verify("", " three four"), vec![]);
+        assert_eq!(
+            verify("WRONG", " three"),
+            vec![AssertionMismatch {
+                kind: AssertionKind::TextBefore,
+                node_path: "/html/body/p[2]/text()[2]".to_string(),
+                expected: "WRONG".to_string(),
+                actual: Some("e two".to_string()),
+            }]
+        );
+        let mismatches = verify("two", "TEXT");
+        assert_eq!(mismatches[0].kind, AssertionKind::TextAfter);
+        assert_eq!(mismatches[0].actual.as_deref(), Some(" thr"));
+        assert_eq!(
+            mismatches[0].to_string(),
+            "/html/body/p[2]/text()[2]: expected text \"TEXT\" after the offset, found \" thr\""
+        );
+    }
+
+    #[test]
+    fn test_locate() {
+        let document = Document::parse(CHAPTER).unwrap();
+        let position = document.text().find("two").unwrap();
+        assert_eq!(
+            document.locate(position, Side::Start).unwrap(),
+            (steps(&[4, 4, 2, 1]), 0)
+        );
+        assert_eq!(
+            document.locate(position, Side::End).unwrap(),
+            (steps(&[4, 4, 1]), 4)
+        );
+        let (path, offset) = document.locate(position + 2, Side::End).unwrap();
+        let location = document
+            .resolve(&path, Some(&CharacterOffset::new(offset, None).to_offset()))
+            .unwrap();
+        assert_eq!(location.position, position + 2);
+        assert!(matches!(
+            document.locate(1000, Side::Start),
+            Err(Error::PositionOutOfRange { position: 1000, .. })
+        ));
+    }
+
+    #[test]
+    fn test_char_slice() {
+        assert_eq!(char_slice("héllo", 1, 3), "él");
+        assert_eq!(char_slice("héllo", 3, 100), "lo");
+        assert_eq!(char_slice("héllo", 4, 2), "");
+    }
+}
@@ -0,0 +1,687 @@
+//! Conversion between content document paths and XPath expressions or CSS selectors.
+//!
+//! A [`LocalPath`] into a content document, e.g. `/4/10/3:12`, converts to an expression that
+//! selects a single node and a character offset into that node's text:
+//!
+//! - XPath expressions select elements or text nodes, e.g. `/html/body/p[5]/text()[2]` with an
+//!   offset into that text node. The subset read back is absolute paths of child steps, with an
+//!   optional leading `//`, name tests or `*`, `text()`, and `[n]` or `[@id='...']` predicates.
+//! - CSS selectors only select elements, so the offset counts every character of the element's
+//!   text, e.g. `#chap01 > p:nth-of-type(5)`. The subset read back is compound selectors of a
+//!   type or `*`, an `#id` and an `:nth-of-type(n)` or `:nth-child(n)`, joined by `>`. The first
+//!   must either have an id or select the document element.
+//!
+//! Names are compared without namespace prefixes, as HTML tools write them.
+
+use std::fmt;
+
+use roxmltree::Node;
+
+use crate::{
+    resolve::{self, element_path, Document, Side, Target},
+    syntax::{CharacterOffset, LocalPath, Offset, ToOffset},
+};
+
+/// An expression that selects a node of a content document, and an optional character offset
+/// into the node's text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeOffset {
+    pub expression: String,
+    pub offset: Option<u32>,
+}
+
+/// Converts a path into a content document to an XPath expression. A path that selects text
+/// converts to the text node that its offset falls in, and the offset into that node.
+pub fn to_xpath(document: &Document, path: &LocalPath) -> Result<NodeOffset, Error> {
+    let location = document.resolve(&path.steps, character_offset(path)?)?;
+    let offset = location.offset;
+    let (parent, index) = match document.walk(&path.steps)? {
+        Target::Element(element) => {
+            return Ok(NodeOffset {
+                expression: element_path(element),
+                offset,
+            })
+        }
+        Target::Text { parent, index } => (parent, index),
+    };
+    let mut remaining = offset.unwrap_or_default() as usize;
+    for node in run(parent, index) {
+        let chars = text_length(node);
+        if remaining <= chars {
+            let position = parent
+                .children()
+                .filter(Node::is_text)
+                .position(|text| text == node)
+                .unwrap_or_default();
+            return Ok(NodeOffset {
+                expression: format!("{}/text()[{}]", element_path(parent), position + 1),
+                offset: offset.map(|_| remaining as u32),
+            });
+        }
+        remaining -= chars;
+    }
+    // there is no text node to select, so select the text's element
+    Ok(NodeOffset {
+        expression: element_path(parent),
+        offset: Some((location.position - document.span(parent).0) as u32),
+    })
+}
+
+/// Converts an XPath expression and an optional character offset into the selected node's text
+/// to a path into the content document.
+pub fn from_xpath(
+    document: &Document,
+    xpath: &str,
+    offset: Option<u32>,
+) -> Result<LocalPath<'static>, Error> {
+    let syntax = || Error::Syntax(xpath.to_string());
+    let (descendant, steps) = xpath
+        .strip_prefix("//")
+        .map(|rest| (true, rest))
+        .or_else(|| xpath.strip_prefix('/').map(|rest| (false, rest)))
+        .ok_or_else(syntax)?;
+    let steps = split(steps, '/')
+        .ok_or_else(syntax)?
+        .into_iter()
+        .map(XPathStep::parse)
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(syntax)?;
+    if steps
+        .iter()
+        .rev()
+        .skip(1)
+        .any(|step| step.name == NameTest::Text)
+    {
+        return Err(syntax());
+    }
+
+    let root = document.root_element();
+    let mut node: Option<Node> = None;
+    for step in &steps {
+        let matches = |node: &Node| step.matches(*node);
+        node = Some(match node {
+            // the position counts among the matching children of each node's parent, so `//p[2]`
+            // selects every `p` that is the second `p` of its parent
+            None if descendant => {
+                let matching = root.descendants().filter(matches).filter(|node| {
+                    step.position.is_none_or(|position| {
+                        node.prev_siblings().skip(1).filter(matches).count() + 1 == position
+                    })
+                });
+                single(matching, None, xpath)?
+            }
+            None => single([root].into_iter().filter(matches), step.position, xpath)?,
+            Some(parent) => single(parent.children().filter(matches), step.position, xpath)?,
+        });
+    }
+    local_path(document, node.ok_or_else(syntax)?, offset)
+}
+
+/// Converts a path into a content document to a CSS selector for the element it selects, or the
+/// element containing the text it selects. The offset counts the characters of the element's
+/// text before the path's location.
+pub fn to_css(document: &Document, path: &LocalPath) -> Result<NodeOffset, Error> {
+    let location = document.resolve(&path.steps, character_offset(path)?)?;
+    let (element, offset) = match document.walk(&path.steps)? {
+        Target::Element(element) => (element, location.offset),
+        Target::Text { parent, .. } => {
+            let offset = location.position - document.span(parent).0;
+            (parent, Some(offset as u32))
+        }
+    };
+    let root = document.root_element();
+    let mut compounds = vec![];
+    for node in element.ancestors().filter(Node::is_element) {
+        let id = node.attribute("id").filter(|id| {
+            is_identifier(id)
+                && root
+                    .descendants()
+                    .filter(|node| node.attribute("id") == Some(id))
+                    .count()
+                    == 1
+        });
+        if let Some(id) = id {
+            compounds.push(format!("#{}", id));
+            break;
+        }
+        let name = node.tag_name().name();
+        match node.parent().map_or(1, |parent| {
+            parent.children().filter(|n| same_name(*n, node)).count()
+        }) {
+            1 => compounds.push(name.to_string()),
+            _ => {
+                let position = node.prev_siblings().filter(|n| same_name(*n, node)).count();
+                compounds.push(format!("{}:nth-of-type({})", name, position));
+            }
+        }
+    }
+    compounds.reverse();
+    Ok(NodeOffset {
+        expression: compounds.join(" > "),
+        offset,
+    })
+}
+
+/// Converts a CSS selector and an optional character offset into the selected element's text to
+/// a path into the content document.
+pub fn from_css(
+    document: &Document,
+    selector: &str,
+    offset: Option<u32>,
+) -> Result<LocalPath<'static>, Error> {
+    let compounds = split(selector, '>')
+        .and_then(|compounds| {
+            compounds
+                .into_iter()
+                .map(Compound::parse)
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| Error::Syntax(selector.to_string()))?;
+
+    let root = document.root_element();
+    let mut element: Option<Node> = None;
+    for compound in &compounds {
+        let candidates: Vec<Node> = match element {
+            None if compound.id.is_some() => root.descendants().collect(),
+            None => vec![root],
+            Some(parent) => parent.children().collect(),
+        };
+        let matching = candidates
+            .into_iter()
+            .filter(|node| compound.matches(*node));
+        element = Some(single(matching, None, selector)?);
+    }
+    let element = element.ok_or_else(|| Error::Syntax(selector.to_string()))?;
+    local_path(document, element, offset)
+}
+
+/// A name test of an XPath step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NameTest<'a> {
+    /// An element with the name, or any element for `*`.
+    Element(Option<&'a str>),
+    /// `text()`.
+    Text,
+}
+
+/// A step of an XPath expression, e.g. `p[4]` or `*[@id='c2']`.
+#[derive(Debug, PartialEq)]
+struct XPathStep<'a> {
+    name: NameTest<'a>,
+    id: Option<&'a str>,
+    position: Option<usize>,
+}
+
+impl<'a> XPathStep<'a> {
+    fn parse(step: &'a str) -> Option<Self> {
+        let (name, mut predicates) = step.split_at(step.find('[').unwrap_or(step.len()));
+        let name = match name {
+            "text()" => NameTest::Text,
+            "*" => NameTest::Element(None),
+            name => NameTest::Element(Some(local_name(name)?)),
+        };
+        let mut id = None;
+        let mut position = None;
+        while !predicates.is_empty() {
+            let (predicate, rest) = predicate(predicates)?;
+            predicates = rest;
+            let predicate = predicate.trim();
+            if is_number(predicate) {
+                position = Some(predicate.parse().ok().filter(|&n| n > 0)?);
+            } else {
+                let value = predicate.strip_prefix("@id")?.trim_start();
+                let value = value.strip_prefix('=')?.trim_start();
+                let quote = value.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+                id = Some(value[1..].strip_suffix(quote)?);
+            }
+        }
+        if name == NameTest::Text && id.is_some() {
+            return None;
+        }
+        Some(Self { name, id, position })
+    }
+
+    fn matches(&self, node: Node) -> bool {
+        let name = match self.name {
+            NameTest::Text => return node.is_text(),
+            NameTest::Element(name) => name,
+        };
+        node.is_element()
+            && name.is_none_or(|name| node.tag_name().name() == name)
+            && self.id.is_none_or(|id| node.attribute("id") == Some(id))
+    }
+}
+
+/// Which siblings an `:nth-*` pseudo-class counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Nth {
+    Child,
+    OfType,
+}
+
+/// A compound selector, e.g. `p:nth-of-type(4)` or `section#c2`.
+#[derive(Debug, PartialEq)]
+struct Compound<'a> {
+    name: Option<&'a str>,
+    id: Option<&'a str>,
+    nth: Option<(Nth, usize)>,
+}
+
+impl<'a> Compound<'a> {
+    fn parse(compound: &'a str) -> Option<Self> {
+        let compound = compound.trim();
+        if compound.is_empty() || compound.contains(char::is_whitespace) {
+            return None;
+        }
+        let end = compound.find(['#', ':']).unwrap_or(compound.len());
+        let (name, mut rest) = compound.split_at(end);
+        let name = match name {
+            "" | "*" => None,
+            // a `.` starts a class selector
+            name => Some(local_name(name).filter(|name| !name.contains('.'))?),
+        };
+        let mut id = None;
+        if let Some(after) = rest.strip_prefix('#') {
+            let end = after.find(':').unwrap_or(after.len());
+            let value = &after[..end];
+            if !is_identifier(value) {
+                return None;
+            }
+            id = Some(value);
+            rest = &after[end..];
+        }
+        let mut nth = None;
+        if !rest.is_empty() {
+            let (kind, n) = match rest.strip_prefix(":nth-of-type(") {
+                Some(n) => (Nth::OfType, n),
+                None => (Nth::Child, rest.strip_prefix(":nth-child(")?),
+            };
+            let n = n.strip_suffix(')').filter(|n| is_number(n))?;
+            nth = Some((kind, n.parse().ok().filter(|&n| n > 0)?));
+        }
+        Some(Self { name, id, nth })
+    }
+
+    fn matches(&self, node: Node) -> bool {
+        let nth = |(kind, n): (Nth, usize)| {
+            let siblings = node.prev_siblings().filter(|sibling| match kind {
+                Nth::Child => sibling.is_element(),
+                Nth::OfType => same_name(*sibling, node),
+            });
+            siblings.count() == n
+        };
+        node.is_element()
+            && self.name.is_none_or(|name| node.tag_name().name() == name)
+            && self.id.is_none_or(|id| node.attribute("id") == Some(id))
+            && self.nth.is_none_or(nth)
+    }
+}
+
+/// The character offset of a path, which must not redirect.
+fn character_offset<'p>(path: &'p LocalPath) -> Result<Option<&'p Offset<'p>>, Error> {
+    if path.redirected_path().is_some() {
+        return Err(Error::Unsupported("redirection out of a content document"));
+    }
+    match path.offset() {
+        None | Some(Offset::Character(_)) => Ok(path.offset()),
+        Some(_) => Err(Error::Unsupported("spatial or temporal offset")),
+    }
+}
+
+/// The path to a node, with an offset into its text. A text node's offset is converted to an
+/// offset into its run of character data, and an element's to the text node it falls in.
+fn local_path(
+    document: &Document,
+    node: Node,
+    offset: Option<u32>,
+) -> Result<LocalPath<'static>, Error> {
+    let (start, end) = document.span(node);
+    let length = end - start;
+    if let Some(offset) = offset.filter(|&offset| offset as usize > length) {
+        return Err(resolve::Error::OffsetOutOfRange { offset, length }.into());
+    }
+    let (steps, offset) = match (node.parent_element(), offset) {
+        (Some(parent), offset) if node.is_text() => {
+            let before: usize = node
+                .prev_siblings()
+                .skip(1)
+                .take_while(|node| !node.is_element())
+                .filter(Node::is_text)
+                .map(text_length)
+                .sum();
+            let target = Target::Text {
+                parent,
+                index: node.prev_siblings().filter(Node::is_element).count(),
+            };
+            let offset = offset.map(|offset| before as u32 + offset);
+            (document.steps(target), offset)
+        }
+        (_, Some(offset)) if length > 0 => {
+            let side = if offset as usize == length {
+                Side::End
+            } else {
+                Side::Start
+            };
+            let (steps, offset) = document.locate(start + offset as usize, side)?;
+            (steps, Some(offset))
+        }
+        _ => (document.steps(Target::Element(node)), None),
+    };
+    let offset = offset.map(|offset| CharacterOffset::new(offset, None).to_offset());
+    Ok(LocalPath::new_with_offset(steps, offset))
+}
+
+/// The text nodes in the run of character data of `parent` at `index`.
+fn run<'a, 'input>(
+    parent: Node<'a, 'input>,
+    index: usize,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    let mut elements = 0;
+    parent.children().filter(move |node| {
+        elements += node.is_element() as usize;
+        node.is_text() && elements == index
+    })
+}
+
+fn text_length(node: Node) -> usize {
+    node.text().unwrap_or_default().chars().count()
+}
+
+/// The only node of `nodes`, or the node at `position`, starting at 1.
+fn single<'a, 'input>(
+    mut nodes: impl Iterator<Item = Node<'a, 'input>>,
+    position: Option<usize>,
+    expression: &str,
+) -> Result<Node<'a, 'input>, Error> {
+    let no_match = || Error::NoMatch(expression.to_string());
+    match position {
+        Some(position) => nodes.nth(position - 1).ok_or_else(no_match),
+        None => match (nodes.next(), nodes.next()) {
+            (Some(node), None) => Ok(node),
+            (Some(_), Some(_)) => Err(Error::Ambiguous(expression.to_string())),
+            (None, _) => Err(no_match()),
+        },
+    }
+}
+
+/// The content of the predicate at the start of `input`, e.g. `4` for `[4]/p`, and the rest of
+/// `input`.
+fn predicate(input: &str) -> Option<(&str, &str)> {
+    let inner = input.strip_prefix('[')?;
+    let mut quote = None;
+    for (i, c) in inner.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ']') => return Some((&inner[..i], &inner[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits `input` at each `separator` that is not inside brackets, parentheses or quotes.
+fn split(input: &str, separator: char) -> Option<Vec<&str>> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut depth = 0usize;
+    let mut quote = None;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, c) if c == separator && depth == 0 => {
+                parts.push(&input[start..i]);
+                start = i + c.len_utf8();
+            }
+            (None, '[' | '(') => depth += 1,
+            (None, ']' | ')') => depth = depth.checked_sub(1)?,
+            _ => {}
+        }
+    }
+    parts.push(&input[start..]);
+    (quote.is_none() && depth == 0).then_some(parts)
+}
+
+fn same_name(node: Node, other: Node) -> bool {
+    node.is_element() && node.tag_name().name() == other.tag_name().name()
+}
+
+/// A name without its namespace prefix, e.g. `p` for `xhtml:p`.
+fn local_name(name: &str) -> Option<&str> {
+    let name = name.rsplit(':').next()?;
+    let valid = |c: char| c.is_alphanumeric() || matches!(c, '-' | '_' | '.');
+    (!name.is_empty() && name.chars().all(valid)).then_some(name)
+}
+
+/// Whether an id can be written as `#id` in a CSS selector without escapes.
+fn is_identifier(id: &str) -> bool {
+    let mut chars = id.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Errors that can occur when converting between paths and XPath expressions or CSS selectors.
+#[derive(Debug)]
+pub enum Error {
+    Resolve(resolve::Error),
+    /// The expression is not in the supported subset of XPath or CSS.
+    Syntax(String),
+    /// The expression selects no node.
+    NoMatch(String),
+    /// The expression selects more than one node.
+    Ambiguous(String),
+    /// The path cannot be converted, such as one with a spatial offset.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Resolve(e) => write!(f, "{}", e),
+            Error::Syntax(expression) => write!(f, "unsupported expression: {}", expression),
+            Error::NoMatch(expression) => write!(f, "{} selects no node", expression),
+            Error::Ambiguous(expression) => write!(f, "{} selects more than one node", expression),
+            Error::Unsupported(feature) => write!(f, "unsupported path: {}", feature),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Resolve(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<resolve::Error> for Error {
+    fn from(e: resolve::Error) -> Self {
+        Error::Resolve(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::Step;
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>Chapter</title></head>
+<body><section><p>a</p></section><section id="s2"><p>first</p><p>one <b>two</b> three<!-- note --> four</p></section></body>
+</html>"#;
+
+    fn path(sizes: &[u32], offset: Option<u32>) -> LocalPath<'static> {
+        let steps = sizes.iter().map(|&size| Step::new(size, None)).collect();
+        let offset = offset.map(|offset| CharacterOffset::new(offset, None).to_offset());
+        LocalPath::new_with_offset(steps, offset)
+    }
+
+    fn node_offset(expression: &str, offset: Option<u32>) -> NodeOffset {
+        NodeOffset {
+            expression: expression.to_string(),
+            offset,
+        }
+    }
+
+    #[test]
+    fn test_to_xpath() {
+        let document = Document::parse(CHAPTER).unwrap();
+        for (path, expected) in [
+            (
+                path(&[4, 4, 4, 3], Some(8)),
+                node_offset("/html/body/section[2]/p[2]/text()[3]", Some(2)),
+            ),
+            (
+                path(&[4, 4, 4, 3], Some(6)),
+                node_offset("/html/body/section[2]/p[2]/text()[2]", Some(6)),
+            ),
+            (
+                path(&[4, 4, 4, 2], None),
+                node_offset("/html/body/section[2]/p[2]/b", None),
+            ),
+            (
+                path(&[4, 2, 2, 1], Some(1)),
+                node_offset("/html/body/section[1]/p/text()[1]", Some(1)),
+            ),
+            // the empty run of text after the last paragraph
+            (
+                path(&[4, 4, 5], Some(0)),
+                node_offset("/html/body/section[2]", Some(23)),
+            ),
+        ] {
+            let xpath = to_xpath(&document, &path).unwrap();
+            assert_eq!(xpath, expected, "{}", path);
+            let round_trip = from_xpath(&document, &xpath.expression, xpath.offset).unwrap();
+            let resolve = |path: &LocalPath| {
+                document
+                    .resolve(&path.steps, path.offset())
+                    .unwrap()
+                    .position
+            };
+            assert_eq!(resolve(&round_trip), resolve(&path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_from_xpath() {
+        let document = Document::parse(CHAPTER).unwrap();
+        for (xpath, offset, expected) in [
+            ("/html/body/section[2]/p[2]", None, "/4/4/4"),
+            (
+                "/html/body/section[2]/p[2]/text()[3]",
+                Some(2),
+                "/4/4/4/3:8",
+            ),
+            ("//*[@id='s2']/p[2]/text()[2]", Some(3), "/4/4/4/3:3"),
+            ("//section[@id=\"s2\"]/p[1]", None, "/4/4/2"),
+            (
+                "/xhtml:html/xhtml:body/*[1]/p/text()",
+                Some(1),
+                "/4/2/2/1:1",
+            ),
+            // an offset into an element selects the text node it falls in
+            ("/html/body/section[2]/p[2]", Some(4), "/4/4/4/2/1:0"),
+            ("/html/body/section[2]/p[2]", Some(18), "/4/4/4/3:11"),
+        ] {
+            let path = from_xpath(&document, xpath, offset).unwrap();
+            assert_eq!(path.to_string(), expected, "{}", xpath);
+        }
+
+        let document = Document::parse(
+            "<html><body><div><p>a</p></div><div><p>b</p><p>c</p></div></body></html>",
+        )
+        .unwrap();
+        assert_eq!(
+            from_xpath(&document, "//p[2]", None).unwrap().to_string(),
+            "/2/4/4"
+        );
+        assert!(matches!(
+            from_xpath(&document, "//p[1]", None),
+            Err(Error::Ambiguous(_))
+        ));
+        assert!(matches!(
+            from_xpath(&document, "//p[3]", None),
+            Err(Error::NoMatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_css() {
+        let document = Document::parse(CHAPTER).unwrap();
+        for (path, expected) in [
+            (
+                path(&[4, 4, 4, 3], Some(8)),
+                node_offset("#s2 > p:nth-of-type(2)", Some(15)),
+            ),
+            (
+                path(&[4, 4, 4, 2], None),
+                node_offset("#s2 > p:nth-of-type(2) > b", None),
+            ),
+            (
+                path(&[4, 2, 2, 1], Some(1)),
+                node_offset("html > body > section:nth-of-type(1) > p", Some(1)),
+            ),
+        ] {
+            assert_eq!(to_css(&document, &path).unwrap(), expected, "{}", path);
+        }
+        for (selector, offset, expected) in [
+            ("#s2 > p:nth-of-type(2)", Some(15), "/4/4/4/3:8"),
+            ("#s2>p:nth-child(2)>b", None, "/4/4/4/2"),
+            (
+                "html > body > section:nth-of-type(1) > p",
+                Some(1),
+                "/4/2/2/1:1",
+            ),
+            ("section#s2 > *:nth-child(1)", Some(5), "/4/4/2/1:5"),
+        ] {
+            let path = from_css(&document, selector, offset).unwrap();
+            assert_eq!(path.to_string(), expected, "{}", selector);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let document = Document::parse(CHAPTER).unwrap();
+        let xpath = |xpath: &str, offset| from_xpath(&document, xpath, offset).unwrap_err();
+        assert!(matches!(
+            xpath("/html/body/section/p", None),
+            Error::Ambiguous(_)
+        ));
+        assert!(matches!(xpath("/html/body/div", None), Error::NoMatch(_)));
+        assert!(matches!(xpath("/html/body[2]", None), Error::NoMatch(_)));
+        for syntax in [
+            "html/body",
+            "/html/body/@class",
+            "/html/text()/p",
+            "/html[last()]",
+        ] {
+            assert!(
+                matches!(xpath(syntax, None), Error::Syntax(_)),
+                "{}",
+                syntax
+            );
+        }
+        assert!(matches!(
+            xpath("/html/body/section[1]/p", Some(2)),
+            Error::Resolve(resolve::Error::OffsetOutOfRange { .. })
+        ));
+
+        let css = |selector: &str| from_css(&document, selector, None).unwrap_err();
+        assert!(matches!(css("section > p"), Error::NoMatch(_)));
+        assert!(matches!(css("#s2 > p"), Error::Ambiguous(_)));
+        for syntax in ["#s2 p", "p.note", "p:first-child", ""] {
+            assert!(matches!(css(syntax), Error::Syntax(_)), "{}", syntax);
+        }
+    }
+}
@@ -1,3 +1,7 @@
+use std::{borrow::Cow, fmt};
+
+use crate::tokens::{self, Token};
+
 /// to a specific location within an EPUB document. The `Fragment` includes the main `Path`, which
 /// is essential for navigating through the document structure, and optionally a `Range` that
 /// specifies a span within the document.
@@ -17,14 +21,112 @@
 /// - **range**: An optional component specifying a start and end path to define a `Range` within the
 ///   document.
 /// - **")"**: This character marks the end of the CFI fragment.
-#[derive(Debug, PartialEq)]
-pub struct Fragment {
-    path: Path,
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragment<'a> {
+    /// When the fragment is a range, this is the path shared by both ends.
+    pub path: Path<'a>,
+    pub range: Option<Range<'a>>,
+}
+
+impl<'a> Fragment<'a> {
+    pub fn new(path: Path<'a>) -> Self {
+        Self { path, range: None }
+    }
+
+    pub fn new_with_range(path: Path<'a>, range: Range<'a>) -> Self {
+        Self {
+            path,
+            range: Some(range),
+        }
+    }
+
+    /// The main `Path`. When the fragment is a range, this is the path shared by both ends.
+    pub fn path(&self) -> &Path<'a> {
+        &self.path
+    }
+
+    pub fn range(&self) -> Option<&Range<'a>> {
+        self.range.as_ref()
+    }
+
+    /// Builds a range from the full paths to its start and end. The range's main path is as long
+    /// as possible, so `/6/4!/4/10/3:1` and `/6/4!/4/10/3:5` become `/6/4!/4/10/3,:1,:5`.
+    ///
+    /// Returns `None` when `start` and `end` have different first steps.
+    pub fn from_range(start: &Path<'a>, end: &Path<'a>) -> Option<Fragment<'a>> {
+        let mut start = tokens::flatten(start);
+        let mut end = tokens::flatten(end);
+        // each end keeps at least one token, and does not share a trailing redirection
+        let mut common = tokens::common_length(&start, &end).min(start.len().min(end.len()) - 1);
+        if let Some(Token::Redirect) = common.checked_sub(1).map(|last| &start[last]) {
+            common -= 1;
+        }
+        let start_point = tokens::local_path(start.split_off(common))?;
+        let end_point = tokens::local_path(end.split_off(common))?;
+        let path = tokens::path(start)?;
+        Some(Fragment::new_with_range(
+            path,
+            Range::new(start_point, end_point),
+        ))
+    }
+
+    /// The deepest path that contains both this fragment and `other`, ignoring offsets. Passing
+    /// the fragment itself gives the deepest path containing the whole of it.
+    ///
+    /// Returns `None` when the fragments have different first steps.
+    pub fn common_ancestor(&self, other: &Fragment<'_>) -> Option<Path<'a>> {
+        [self.end()?, other.start()?, other.end()?]
+            .iter()
+            .try_fold(self.start()?, |ancestor, path| {
+                ancestor.common_ancestor(path)
+            })
+    }
+
+    /// The full path to the start of a range, or the path of a point.
+    ///
+    /// Returns `None` when the main path ends in an offset and is followed by a range, which no
+    /// valid CFI does.
+    pub fn start(&self) -> Option<Path<'a>> {
+        self.join(self.range.as_ref().map(Range::start_point))
+    }
+
+    /// The full path to the end of a range, or the path of a point.
+    ///
+    /// Returns `None` under the same conditions as [`start`](Fragment::start).
+    pub fn end(&self) -> Option<Path<'a>> {
+        self.join(self.range.as_ref().map(Range::end_point))
+    }
+
+    /// The path to the spine item that the fragment's content document is referenced from. See
+    /// [`Path::spine_item`].
+    pub fn spine_item(&self) -> Option<Path<'a>> {
+        self.path.spine_item()
+    }
+
+    fn join(&self, local_path: Option<&LocalPath<'a>>) -> Option<Path<'a>> {
+        let mut tokens = tokens::flatten(&self.path);
+        if let Some(local_path) = local_path {
+            tokens::push_local_path(&mut tokens, local_path);
+        }
+        tokens::path(tokens)
+    }
+
+    /// Converts the fragment into one that owns all of its data.
+    pub fn into_owned(self) -> Fragment<'static> {
+        Fragment {
+            path: self.path.into_owned(),
+            range: self.range.map(Range::into_owned),
+        }
+    }
 }
 
-impl Fragment {
-    pub fn new(path: Path) -> Self {
-        Self { path }
+impl fmt::Display for Fragment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "epubcfi({}", self.path)?;
+        if let Some(range) = &self.range {
+            write!(f, "{}", range)?;
+        }
+        write!(f, ")")
     }
 }
 
@@ -56,35 +158,214 @@ impl Fragment {
 /// - **`/4/2!/6/3:5`**: This path starts at the fourth child element, moves to its second child,
 ///   and then redirects to another path starting from its sixth child, finally moving to the third
 ///   child with an offset of 5.
-#[derive(Debug, PartialEq)]
-pub struct Path {
+#[derive(Clone, Debug, PartialEq)]
+pub struct Path<'a> {
     /// The intial step in the path, indicating the starting point.
-    pub step: Step,
-    pub local_path: LocalPath,
+    pub step: Step<'a>,
+    pub local_path: LocalPath<'a>,
 }
 
-impl Path {
-    pub fn new(step: Step, local_path: LocalPath) -> Self {
+impl<'a> Path<'a> {
+    pub fn new(step: Step<'a>, local_path: LocalPath<'a>) -> Self {
         Self { step, local_path }
     }
+
+    /// The terminal offset, including one directly following a redirection.
+    pub fn offset(&self) -> Option<&Offset<'a>> {
+        let mut path = self;
+        loop {
+            match &path.local_path.terminal {
+                Terminal::Redirect(RedirectedPath::Path(redirected)) => path = redirected,
+                Terminal::Redirect(RedirectedPath::Offset(offset)) | Terminal::Offset(offset) => {
+                    return Some(offset)
+                }
+                Terminal::None => return None,
+            }
+        }
+    }
+
+    /// The path without its terminal offset. An offset directly following a redirection is
+    /// removed along with the redirection, so `/6/4!:5` becomes `/6/4`.
+    pub fn without_offset(&self) -> Path<'a> {
+        self.edit(|_| Some(())).unwrap_or_else(|| self.clone())
+    }
+
+    /// The path to the parent of the element or text the path points to. Any offset is removed,
+    /// and a redirection is crossed back to the element it was made from, so the parent of
+    /// `/6/4!/4` is `/6/4`. Returns `None` for a path with a single step.
+    pub fn parent(&self) -> Option<Path<'a>> {
+        self.edit(|tokens| {
+            match tokens.pop() {
+                Some(Token::Step(_)) => {}
+                _ => return None,
+            }
+            if let Some(Token::Redirect) = tokens.last() {
+                tokens.pop();
+            }
+            Some(())
+        })
+    }
+
+    /// The path to the child with the given index, e.g. `/6/4` becomes `/6/4/2` for `2`.
+    pub fn child(&self, index: u32) -> Path<'a> {
+        self.append(Step::new(index, None))
+    }
+
+    /// The path with a step appended. Any offset is removed first, keeping a redirection that it
+    /// followed, so `/6/4!:5` becomes `/6/4!/2` when `/2` is appended.
+    pub fn append(&self, step: Step<'a>) -> Path<'a> {
+        let mut tokens = tokens::flatten(self);
+        if let Some(Token::Offset(_)) = tokens.last() {
+            tokens.pop();
+        }
+        tokens.push(Token::Step(step));
+        tokens::path(tokens).unwrap_or_else(|| self.clone())
+    }
+
+    /// The path to the next sibling element. Any offset is removed. From a text chunk (an odd
+    /// step), this is the element that follows it.
+    pub fn next_sibling(&self) -> Option<Path<'a>> {
+        self.edit(|tokens| match tokens.pop() {
+            Some(Token::Step(step)) => {
+                let size = step
+                    .size
+                    .checked_add(if step.size % 2 == 0 { 2 } else { 1 })?;
+                tokens.push(Token::Step(Step::new(size, None)));
+                Some(())
+            }
+            _ => None,
+        })
+    }
+
+    /// The path to the previous sibling element. Any offset is removed. From a text chunk (an odd
+    /// step), this is the element that precedes it. Returns `None` for the first element.
+    pub fn previous_sibling(&self) -> Option<Path<'a>> {
+        self.edit(|tokens| match tokens.pop() {
+            Some(Token::Step(step)) => {
+                let size = step
+                    .size
+                    .checked_sub(if step.size % 2 == 0 { 2 } else { 1 })?;
+                (size >= 2).then(|| tokens.push(Token::Step(Step::new(size, None))))
+            }
+            _ => None,
+        })
+    }
+
+    /// The path truncated to its first `steps` steps, counting steps on both sides of any
+    /// redirection. Any offset is removed when steps are. Returns `None` for zero steps.
+    pub fn truncate(&self, steps: usize) -> Option<Path<'a>> {
+        let mut tokens = tokens::flatten(self);
+        let mut count = 0;
+        let length = tokens.iter().position(|token| {
+            if let Token::Step(_) = token {
+                count += 1;
+            }
+            count > steps
+        });
+        if let Some(length) = length {
+            tokens.truncate(length);
+            tokens::strip_offset(&mut tokens);
+        }
+        tokens::path(tokens)
+    }
+
+    /// The path up to its first redirection, e.g. `/6/4` for `/6/4!/4/10/3:12`. In a CFI from a
+    /// package document, this is the spine item that the content document is referenced from.
+    /// Returns `None` when the path has no redirection.
+    pub fn spine_item(&self) -> Option<Path<'a>> {
+        let mut tokens = tokens::flatten(self);
+        let redirect = tokens
+            .iter()
+            .position(|token| matches!(token, Token::Redirect))?;
+        tokens.truncate(redirect);
+        tokens::path(tokens)
+    }
+
+    /// The deepest path that contains both this path and `other`, ignoring offsets, e.g. `/6/4!/4`
+    /// for `/6/4!/4/2/1:5` and `/6/4!/4/10`. Returns `None` when the paths have different first
+    /// steps.
+    pub fn common_ancestor(&self, other: &Path<'_>) -> Option<Path<'a>> {
+        let mut tokens = tokens::flatten(self);
+        let common = tokens::common_length(&tokens, &tokens::flatten(other));
+        tokens.truncate(common);
+        tokens::strip_offset(&mut tokens);
+        tokens::path(tokens)
+    }
+
+    /// This path expressed relative to `ancestor`, as used for the start and end of a range, e.g.
+    /// `/3:12` for `/6/4!/4/10/3:12` relative to `/6/4!/4/10`, or `!/4/10/3:12` relative to
+    /// `/6/4`. Any offset on `ancestor` is ignored.
+    ///
+    /// Returns `None` when `ancestor` is not an ancestor of this path.
+    pub fn relative_to(&self, ancestor: &Path<'_>) -> Option<LocalPath<'a>> {
+        let mut tokens = tokens::flatten(self);
+        let mut ancestor = tokens::flatten(ancestor);
+        tokens::strip_offset(&mut ancestor);
+        if tokens::common_length(&tokens, &ancestor) < ancestor.len() {
+            return None;
+        }
+        tokens::local_path(tokens.split_off(ancestor.len()))
+    }
+
+    /// Flattens the path without its offset, applies `edit`, and rebuilds it.
+    fn edit(&self, edit: impl FnOnce(&mut Vec<Token<'a>>) -> Option<()>) -> Option<Path<'a>> {
+        let mut tokens = tokens::flatten(self);
+        tokens::strip_offset(&mut tokens);
+        edit(&mut tokens)?;
+        tokens::path(tokens)
+    }
+
+    pub fn into_owned(self) -> Path<'static> {
+        Path {
+            step: self.step.into_owned(),
+            local_path: self.local_path.into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Path<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.step, self.local_path)
+    }
 }
 
 /// A `Range` in an CFI specifies a span of content within a document, defining a start and end
 /// point. This is useful for highlighting or selecting a portion of the text or content. Each end
 /// of the range is represented by a [LocalPath], and the two paths are separated by commas.
-#[derive(Debug, PartialEq)]
-pub struct Range {
-    start_point: LocalPath,
-    end_point: LocalPath,
+#[derive(Clone, Debug, PartialEq)]
+pub struct Range<'a> {
+    pub start_point: LocalPath<'a>,
+    pub end_point: LocalPath<'a>,
 }
 
-impl Range {
-    pub fn new(start_point: LocalPath, end_point: LocalPath) -> Self {
+impl<'a> Range<'a> {
+    pub fn new(start_point: LocalPath<'a>, end_point: LocalPath<'a>) -> Self {
         Self {
             start_point,
             end_point,
         }
     }
+
+    pub fn start_point(&self) -> &LocalPath<'a> {
+        &self.start_point
+    }
+
+    pub fn end_point(&self) -> &LocalPath<'a> {
+        &self.end_point
+    }
+
+    pub fn into_owned(self) -> Range<'static> {
+        Range {
+            start_point: self.start_point.into_owned(),
+            end_point: self.end_point.into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Range<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ",{},{}", self.start_point, self.end_point)
+    }
 }
 
 /// A `Step` is a fundamental part of the `Path` in a CFI, which navigates through the
@@ -123,30 +404,170 @@ impl Range {
 /// - **`/2[lang=en]`**: Selects the second child element and ensures it has a `lang` attribute
 ///   with a value of "en".
 ///
-#[derive(Debug, PartialEq)]
-pub struct Step {
-    pub size: u8,
-    pub assertion: Option<Assertion>,
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step<'a> {
+    pub size: u32,
+    pub assertion: Option<Assertion<'a>>,
 }
 
-impl Step {
-    pub fn new(size: u8, assertion: Option<Assertion>) -> Self {
+impl<'a> Step<'a> {
+    pub fn new(size: u32, assertion: Option<Assertion<'a>>) -> Self {
         Self { size, assertion }
     }
+
+    pub fn into_owned(self) -> Step<'static> {
+        Step {
+            size: self.size,
+            assertion: self.assertion.map(Assertion::into_owned),
+        }
+    }
+}
+
+impl fmt::Display for Step<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/{}", self.size)?;
+        if let Some(assertion) = &self.assertion {
+            write!(f, "{}", assertion)?;
+        }
+        Ok(())
+    }
 }
 
 /// An `Assertion` is part of a `Step` that provides addtional validation to ensure the correctness
 /// of the identified target element within the EPUB content. It specifies conditions that the
 /// target element must satisfy, which can include attributes, values, and other parameters.
+///
+/// ## Syntax
+///
+/// ```plaintext
+/// assertion = ( ( value , [ "," , value ] ) | ( "," , value ) ) , { ";" , parameter }
+///           | [ ";" ] , parameter , { ";" , parameter } ;
+/// parameter = value-no-space , "=" , value , { "," , value } ;
+/// ```
+///
+/// On a step, the value is the id of the element, e.g. `/4[body01]`. On a character offset, the
+/// values are a text location assertion of the text before and after the offset, e.g.
+/// `:10[yyy,0123]`. The characters `^[](),;=` are escaped with a circumflex, e.g. `[para^]05]`.
+/// A parameter with several values, e.g. `;x=a,b`, is held as one pair per value.
 #[derive(Clone, Debug, PartialEq)]
-pub struct Assertion {
-    parameters: Option<Vec<(String, String)>>,
-    value: Option<String>,
+pub struct Assertion<'a> {
+    pub parameters: Option<Vec<(Cow<'a, str>, Cow<'a, str>)>>,
+    /// The id asserted for a step, or the text expected before an offset.
+    pub value: Option<Cow<'a, str>>,
+    /// The text expected after an offset, in a text location assertion.
+    pub after: Option<Cow<'a, str>>,
 }
 
-impl Assertion {
+impl<'a> Assertion<'a> {
     pub fn new(parameters: Option<Vec<(String, String)>>, value: Option<String>) -> Self {
-        Self { parameters, value }
+        Self {
+            parameters: parameters.map(|parameters| {
+                parameters
+                    .into_iter()
+                    .map(|(key, value)| (Cow::Owned(key), Cow::Owned(value)))
+                    .collect()
+            }),
+            value: value.map(Cow::Owned),
+            after: None,
+        }
+    }
+
+    /// Creates a text location assertion of the text before and after an offset.
+    pub fn text_location(before: Option<String>, after: Option<String>) -> Self {
+        Self {
+            parameters: None,
+            value: before.map(Cow::Owned),
+            after: after.map(Cow::Owned),
+        }
+    }
+
+    /// Creates an assertion that may borrow its parameters and value from the parsed input.
+    pub fn from_cow(
+        parameters: Option<Vec<(Cow<'a, str>, Cow<'a, str>)>>,
+        value: Option<Cow<'a, str>>,
+    ) -> Self {
+        Self {
+            parameters,
+            value,
+            after: None,
+        }
+    }
+
+    pub fn parameters(&self) -> Option<&[(Cow<'a, str>, Cow<'a, str>)]> {
+        self.parameters.as_deref()
+    }
+
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+
+    pub fn after(&self) -> Option<&str> {
+        self.after.as_deref()
+    }
+
+    /// The first value of the parameter named `key`, e.g. `b` for `s` in `[;s=b]`.
+    pub fn parameter(&self, key: &str) -> Option<&str> {
+        self.parameters()?
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_ref())
+    }
+
+    pub fn into_owned(self) -> Assertion<'static> {
+        let owned = |value: Cow<'_, str>| Cow::Owned(value.into_owned());
+        Assertion {
+            parameters: self.parameters.map(|parameters| {
+                parameters
+                    .into_iter()
+                    .map(|(key, value)| (owned(key), owned(value)))
+                    .collect()
+            }),
+            value: self.value.map(owned),
+            after: self.after.map(owned),
+        }
+    }
+}
+
+impl fmt::Display for Assertion<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        if let Some(value) = &self.value {
+            write!(f, "{}", Escaped(value))?;
+        }
+        if let Some(after) = &self.after {
+            write!(f, ",{}", Escaped(after))?;
+        }
+        if let Some(parameters) = &self.parameters {
+            let mut previous = None;
+            for (key, value) in parameters {
+                // consecutive values of one parameter are written as a comma-separated list
+                if previous == Some(key) {
+                    write!(f, ",{}", Escaped(value))?;
+                    continue;
+                }
+                write!(f, ";{}={}", Escaped(key), Escaped(value))?;
+                previous = Some(key);
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+/// The characters escaped with a circumflex inside an assertion.
+pub(crate) const SPECIAL_CHARACTERS: &str = "^[](),;=";
+
+/// Writes a string with [`SPECIAL_CHARACTERS`] escaped.
+struct Escaped<'s>(&'s str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            if SPECIAL_CHARACTERS.contains(c) {
+                write!(f, "^")?;
+            }
+            write!(f, "{}", c)?;
+        }
+        Ok(())
     }
 }
 
@@ -189,27 +610,96 @@ impl Assertion {
 ///
 /// ```rust
 /// ```
-#[derive(Debug, PartialEq)]
-pub struct LocalPath {
-    pub steps: Vec<Step>,
-    pub redirected_path: Option<RedirectedPath>,
-    pub offset: Option<Option<Offset>>,
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalPath<'a> {
+    pub steps: Vec<Step<'a>>,
+    pub terminal: Terminal<'a>,
 }
 
-impl LocalPath {
-    pub fn new_with_redirected_path(steps: Vec<Step>, redirected_path: RedirectedPath) -> Self {
-        Self {
-            steps,
-            redirected_path: Some(redirected_path),
-            offset: None,
+impl<'a> LocalPath<'a> {
+    pub fn new(steps: Vec<Step<'a>>, terminal: Terminal<'a>) -> Self {
+        Self { steps, terminal }
+    }
+
+    pub fn new_with_redirected_path(
+        steps: Vec<Step<'a>>,
+        redirected_path: RedirectedPath<'a>,
+    ) -> Self {
+        Self::new(steps, Terminal::Redirect(redirected_path))
+    }
+
+    pub fn new_with_offset(steps: Vec<Step<'a>>, offset: Option<Offset<'a>>) -> Self {
+        let terminal = match offset {
+            Some(offset) => Terminal::Offset(offset),
+            None => Terminal::None,
+        };
+        Self::new(steps, terminal)
+    }
+
+    pub fn redirected_path(&self) -> Option<&RedirectedPath<'a>> {
+        match &self.terminal {
+            Terminal::Redirect(redirected_path) => Some(redirected_path),
+            _ => None,
         }
     }
 
-    pub fn new_with_offset(steps: Vec<Step>, offset: Option<Offset>) -> Self {
-        Self {
-            steps,
-            redirected_path: None,
-            offset: Some(offset),
+    /// The offset at the end of this local path, not counting one after a redirection.
+    pub fn offset(&self) -> Option<&Offset<'a>> {
+        match &self.terminal {
+            Terminal::Offset(offset) => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// The local path without its terminal offset, including one after a redirection.
+    pub fn without_offset(&self) -> LocalPath<'a> {
+        let mut tokens = vec![];
+        tokens::push_local_path(&mut tokens, self);
+        tokens::strip_offset(&mut tokens);
+        tokens::local_path(tokens).unwrap_or_else(|| self.clone())
+    }
+
+    pub fn into_owned(self) -> LocalPath<'static> {
+        LocalPath {
+            steps: self.steps.into_iter().map(Step::into_owned).collect(),
+            terminal: self.terminal.into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for LocalPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            write!(f, "{}", step)?;
+        }
+        write!(f, "{}", self.terminal)
+    }
+}
+
+/// What follows the steps of a [`LocalPath`]: nothing, an [`Offset`], or a [`RedirectedPath`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Terminal<'a> {
+    None,
+    Offset(Offset<'a>),
+    Redirect(RedirectedPath<'a>),
+}
+
+impl Terminal<'_> {
+    pub fn into_owned(self) -> Terminal<'static> {
+        match self {
+            Terminal::None => Terminal::None,
+            Terminal::Offset(offset) => Terminal::Offset(offset.into_owned()),
+            Terminal::Redirect(redirected_path) => Terminal::Redirect(redirected_path.into_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Terminal<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Terminal::None => Ok(()),
+            Terminal::Offset(offset) => write!(f, "{}", offset),
+            Terminal::Redirect(redirected_path) => write!(f, "{}", redirected_path),
         }
     }
 }
@@ -244,15 +734,43 @@ impl LocalPath {
 ///
 /// ```rust
 /// ```
-#[derive(Debug, PartialEq)]
-pub struct RedirectedPath {
-    offset: Box<Option<Offset>>,
-    path: Box<Option<Path>>,
+#[derive(Clone, Debug, PartialEq)]
+pub enum RedirectedPath<'a> {
+    /// An offset within the redirected element, e.g. `!:15`.
+    Offset(Offset<'a>),
+    /// A path within the redirected element, e.g. `!/4/2`.
+    Path(Box<Path<'a>>),
+}
+
+impl<'a> RedirectedPath<'a> {
+    pub fn offset(&self) -> Option<&Offset<'a>> {
+        match self {
+            RedirectedPath::Offset(offset) => Some(offset),
+            RedirectedPath::Path(_) => None,
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path<'a>> {
+        match self {
+            RedirectedPath::Offset(_) => None,
+            RedirectedPath::Path(path) => Some(path),
+        }
+    }
+
+    pub fn into_owned(self) -> RedirectedPath<'static> {
+        match self {
+            RedirectedPath::Offset(offset) => RedirectedPath::Offset(offset.into_owned()),
+            RedirectedPath::Path(path) => RedirectedPath::Path(Box::new(path.into_owned())),
+        }
+    }
 }
 
-impl RedirectedPath {
-    pub fn new(offset: Box<Option<Offset>>, path: Box<Option<Path>>) -> Self {
-        Self { offset, path }
+impl fmt::Display for RedirectedPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RedirectedPath::Offset(offset) => write!(f, "!{}", offset),
+            RedirectedPath::Path(path) => write!(f, "!{}", path),
+        }
     }
 }
 
@@ -263,18 +781,38 @@ impl RedirectedPath {
 ///
 /// This enum can contain a [`CharacterOffset`], [`SpatialOffset`], or a [`TemporalOffset`]. See
 /// their respective documentation for more details.
-#[derive(Debug, PartialEq)]
-pub enum Offset {
+#[derive(Clone, Debug, PartialEq)]
+pub enum Offset<'a> {
     /// A character, or colon (":"), offset
-    Character(CharacterOffset),
+    Character(CharacterOffset<'a>),
     /// A spatial offset, or at-sign ("@") offset
-    Spatial(SpatialOffset),
+    Spatial(SpatialOffset<'a>),
     /// A temporal offset, or tilde ("~") offset
-    Temporal(TemporalOffset),
+    Temporal(TemporalOffset<'a>),
+}
+
+impl Offset<'_> {
+    pub fn into_owned(self) -> Offset<'static> {
+        match self {
+            Offset::Character(offset) => Offset::Character(offset.into_owned()),
+            Offset::Spatial(offset) => Offset::Spatial(offset.into_owned()),
+            Offset::Temporal(offset) => Offset::Temporal(offset.into_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Offset<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Offset::Character(offset) => write!(f, "{}", offset),
+            Offset::Spatial(offset) => write!(f, "{}", offset),
+            Offset::Temporal(offset) => write!(f, "{}", offset),
+        }
+    }
 }
 
-pub trait ToOffset {
-    fn to_offset(&self) -> Offset;
+pub trait ToOffset<'a> {
+    fn to_offset(&self) -> Offset<'a>;
 }
 
 /// Character offset specifies an offset within an element using a colon, ":".
@@ -287,23 +825,40 @@ pub trait ToOffset {
 /// offset = ( ":" , integer ) , [ "[" , assertion , "]" ] ;
 /// ```
 #[derive(Clone, Debug, PartialEq)]
-pub struct CharacterOffset {
+pub struct CharacterOffset<'a> {
     /// Number of characters from the start of the element.
     pub start_at_point: u32,
-    pub assertion: Option<Assertion>,
+    pub assertion: Option<Assertion<'a>>,
 }
 
-impl CharacterOffset {
-    pub fn new(start_at_point: u32, assertion: Option<Assertion>) -> Self {
+impl<'a> CharacterOffset<'a> {
+    pub fn new(start_at_point: u32, assertion: Option<Assertion<'a>>) -> Self {
         Self {
             start_at_point,
             assertion,
         }
     }
+
+    pub fn into_owned(self) -> CharacterOffset<'static> {
+        CharacterOffset {
+            start_at_point: self.start_at_point,
+            assertion: self.assertion.map(Assertion::into_owned),
+        }
+    }
+}
+
+impl fmt::Display for CharacterOffset<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ":{}", self.start_at_point)?;
+        if let Some(assertion) = &self.assertion {
+            write!(f, "{}", assertion)?;
+        }
+        Ok(())
+    }
 }
 
-impl ToOffset for CharacterOffset {
-    fn to_offset(&self) -> Offset {
+impl<'a> ToOffset<'a> for CharacterOffset<'a> {
+    fn to_offset(&self) -> Offset<'a> {
         Offset::Character(self.clone())
     }
 }
@@ -319,17 +874,17 @@ impl ToOffset for CharacterOffset {
 /// offset = ( "@" , number , ":" , number ) , [ "[" , assertion , "]" ] ;
 /// ```
 #[derive(Clone, Debug, PartialEq)]
-pub struct SpatialOffset {
+pub struct SpatialOffset<'a> {
     pub start_at_point: f32,
     pub end_at_point: Option<f32>,
-    pub assertion: Option<Assertion>,
+    pub assertion: Option<Assertion<'a>>,
 }
 
-impl SpatialOffset {
+impl<'a> SpatialOffset<'a> {
     pub fn new(
         start_at_point: f32,
         end_at_point: Option<f32>,
-        assertion: Option<Assertion>,
+        assertion: Option<Assertion<'a>>,
     ) -> Self {
         Self {
             start_at_point,
@@ -337,10 +892,31 @@ impl SpatialOffset {
             assertion,
         }
     }
+
+    pub fn into_owned(self) -> SpatialOffset<'static> {
+        SpatialOffset {
+            start_at_point: self.start_at_point,
+            end_at_point: self.end_at_point,
+            assertion: self.assertion.map(Assertion::into_owned),
+        }
+    }
+}
+
+impl fmt::Display for SpatialOffset<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}:", self.start_at_point)?;
+        if let Some(end_at_point) = self.end_at_point {
+            write!(f, "{}", end_at_point)?;
+        }
+        if let Some(assertion) = &self.assertion {
+            write!(f, "{}", assertion)?;
+        }
+        Ok(())
+    }
 }
 
-impl ToOffset for SpatialOffset {
-    fn to_offset(&self) -> Offset {
+impl<'a> ToOffset<'a> for SpatialOffset<'a> {
+    fn to_offset(&self) -> Offset<'a> {
         Offset::Spatial(self.clone())
     }
 }
@@ -355,18 +931,18 @@ impl ToOffset for SpatialOffset {
 /// offset = ( "~" , number , [ "@" , number , ":" , number ] ) , [ "[" , assertion , "]" ] ;
 /// ```
 #[derive(Clone, Debug, PartialEq)]
-pub struct TemporalOffset {
+pub struct TemporalOffset<'a> {
     /// Number of characters or percentage, context-dependent.
     pub start_at: f32,
     pub spatial_range: Option<(f32, f32)>,
-    pub assertion: Option<Assertion>,
+    pub assertion: Option<Assertion<'a>>,
 }
 
-impl TemporalOffset {
+impl<'a> TemporalOffset<'a> {
     pub fn new(
         start_at: f32,
         spatial_range: Option<(f32, f32)>,
-        assertion: Option<Assertion>,
+        assertion: Option<Assertion<'a>>,
     ) -> Self {
         Self {
             start_at,
@@ -374,10 +950,163 @@ impl TemporalOffset {
             assertion,
         }
     }
+
+    pub fn into_owned(self) -> TemporalOffset<'static> {
+        TemporalOffset {
+            start_at: self.start_at,
+            spatial_range: self.spatial_range,
+            assertion: self.assertion.map(Assertion::into_owned),
+        }
+    }
+}
+
+impl fmt::Display for TemporalOffset<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "~{}", self.start_at)?;
+        if let Some((start, end)) = self.spatial_range {
+            write!(f, "@{}:{}", start, end)?;
+        }
+        if let Some(assertion) = &self.assertion {
+            write!(f, "{}", assertion)?;
+        }
+        Ok(())
+    }
 }
 
-impl ToOffset for TemporalOffset {
-    fn to_offset(&self) -> Offset {
+impl<'a> ToOffset<'a> for TemporalOffset<'a> {
+    fn to_offset(&self) -> Offset<'a> {
         Offset::Temporal(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(cfi: &str) -> Path<'static> {
+        let fragment: Fragment = cfi.parse().unwrap();
+        fragment.path().clone()
+    }
+
+    fn display(path: Option<Path>) -> Option<String> {
+        path.map(|path| path.to_string())
+    }
+
+    #[test]
+    fn test_offset() {
+        let with_offset = path("epubcfi(/6/4!/4/10/3:12)");
+        assert_eq!(with_offset.offset().unwrap().to_string(), ":12");
+        assert_eq!(with_offset.without_offset().to_string(), "/6/4!/4/10/3");
+        assert_eq!(
+            path("epubcfi(/6/4!@5:5)").without_offset().to_string(),
+            "/6/4"
+        );
+        assert!(path("epubcfi(/6/4!/4)").offset().is_none());
+    }
+
+    #[test]
+    fn test_parent_and_child() {
+        let text = path("epubcfi(/6/4[7]!/4/10/3:12)");
+        assert_eq!(display(text.parent()).unwrap(), "/6/4[7]!/4/10");
+        let body = path("epubcfi(/6/4[7]!/4)");
+        assert_eq!(display(body.parent()).unwrap(), "/6/4[7]");
+        assert_eq!(display(path("epubcfi(/6/4)").parent()).unwrap(), "/6");
+        assert_eq!(path("epubcfi(/6)").parent(), None);
+
+        assert_eq!(body.child(2).to_string(), "/6/4[7]!/4/2");
+        assert_eq!(text.child(1).to_string(), "/6/4[7]!/4/10/3/1");
+        assert_eq!(path("epubcfi(/6/4!:5)").child(2).to_string(), "/6/4!/2");
+    }
+
+    #[test]
+    fn test_siblings() {
+        let element = path("epubcfi(/6/4!/4/10[5]:3)");
+        assert_eq!(display(element.next_sibling()).unwrap(), "/6/4!/4/12");
+        assert_eq!(display(element.previous_sibling()).unwrap(), "/6/4!/4/8");
+        let text = path("epubcfi(/6/4!/4/10/3:12)");
+        assert_eq!(display(text.next_sibling()).unwrap(), "/6/4!/4/10/4");
+        assert_eq!(display(text.previous_sibling()).unwrap(), "/6/4!/4/10/2");
+        assert_eq!(path("epubcfi(/6/4!/2)").previous_sibling(), None);
+        assert_eq!(path("epubcfi(/6/4!/4/1)").previous_sibling(), None);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let text = path("epubcfi(/6/4!/4/10/3:12)");
+        assert_eq!(display(text.truncate(2)).unwrap(), "/6/4");
+        assert_eq!(display(text.truncate(3)).unwrap(), "/6/4!/4");
+        assert_eq!(display(text.truncate(5)).unwrap(), "/6/4!/4/10/3:12");
+        assert_eq!(text.truncate(0), None);
+        assert_eq!(display(text.spine_item()).unwrap(), "/6/4");
+        assert_eq!(path("epubcfi(/6/4)").spine_item(), None);
+    }
+
+    #[test]
+    fn test_fragment_start_and_end() {
+        let fragment: Fragment = "epubcfi(/6/4!/4/10,/2/1:1,/3:4)".parse().unwrap();
+        assert_eq!(display(fragment.start()).unwrap(), "/6/4!/4/10/2/1:1");
+        assert_eq!(display(fragment.end()).unwrap(), "/6/4!/4/10/3:4");
+        assert_eq!(display(fragment.spine_item()).unwrap(), "/6/4");
+        let fragment: Fragment = "epubcfi(/6/4,!/4:1,!/6)".parse().unwrap();
+        assert_eq!(display(fragment.start()).unwrap(), "/6/4!/4:1");
+        assert_eq!(display(fragment.end()).unwrap(), "/6/4!/6");
+    }
+
+    #[test]
+    fn test_common_ancestor() {
+        let a = path("epubcfi(/6/4!/4/2/1:5)");
+        let b = path("epubcfi(/6/4!/4/10)");
+        assert_eq!(display(a.common_ancestor(&b)).unwrap(), "/6/4!/4");
+        assert_eq!(display(a.common_ancestor(&a)).unwrap(), "/6/4!/4/2/1");
+        let c = path("epubcfi(/6/6!/4/2)");
+        assert_eq!(display(a.common_ancestor(&c)).unwrap(), "/6");
+        assert_eq!(a.common_ancestor(&path("epubcfi(/4/4)")), None);
+
+        let range: Fragment = "epubcfi(/6/4!/4/10,/2/1:1,/3:4)".parse().unwrap();
+        assert_eq!(
+            display(range.common_ancestor(&range)).unwrap(),
+            "/6/4!/4/10"
+        );
+        let point: Fragment = "epubcfi(/6/4!/4/12/1:0)".parse().unwrap();
+        assert_eq!(display(range.common_ancestor(&point)).unwrap(), "/6/4!/4");
+    }
+
+    #[test]
+    fn test_relative_to() {
+        let text = path("epubcfi(/6/4!/4/10/3:12)");
+        let relative = |ancestor| text.relative_to(&path(ancestor)).map(|p| p.to_string());
+        assert_eq!(relative("epubcfi(/6/4!/4/10)").unwrap(), "/3:12");
+        assert_eq!(relative("epubcfi(/6/4:3)").unwrap(), "!/4/10/3:12");
+        assert_eq!(relative("epubcfi(/6/4!/4/10/3)").unwrap(), ":12");
+        assert_eq!(relative("epubcfi(/6/4!/4/12)"), None);
+        assert_eq!(relative("epubcfi(/6/4!/4/10/3/1)"), None);
+    }
+
+    #[test]
+    fn test_from_range() {
+        let range = |start, end| {
+            Fragment::from_range(&path(start), &path(end)).map(|fragment| fragment.to_string())
+        };
+        assert_eq!(
+            range("epubcfi(/6/4!/4/10/3:1)", "epubcfi(/6/4!/4/10/3:5)").unwrap(),
+            "epubcfi(/6/4!/4/10/3,:1,:5)"
+        );
+        assert_eq!(
+            range("epubcfi(/6/4!/4/2/1:1)", "epubcfi(/6/4!/4/10/3:5)").unwrap(),
+            "epubcfi(/6/4!/4,/2/1:1,/10/3:5)"
+        );
+        assert_eq!(
+            range("epubcfi(/6/4!/2/1:1)", "epubcfi(/6/4!/4/1:5)").unwrap(),
+            "epubcfi(/6/4,!/2/1:1,!/4/1:5)"
+        );
+        assert_eq!(
+            range("epubcfi(/6/4!/2/1:1)", "epubcfi(/6/6!/4/1:5)").unwrap(),
+            "epubcfi(/6,/4!/2/1:1,/6!/4/1:5)"
+        );
+        assert_eq!(
+            range("epubcfi(/6/4!/4)", "epubcfi(/6/4!/4/10)").unwrap(),
+            "epubcfi(/6/4,!/4,!/4/10)"
+        );
+        assert_eq!(range("epubcfi(/6/4)", "epubcfi(/8/4)"), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! Paths flattened into a sequence of steps, redirections and offsets.
+
+use crate::syntax::{Assertion, LocalPath, Offset, Path, RedirectedPath, Step, Terminal};
+
+/// An item of a flattened path.
+#[derive(Clone, Debug)]
+pub(crate) enum Token<'a> {
+    Step(Step<'a>),
+    Offset(Offset<'a>),
+    Redirect,
+}
+
+impl<'a> Token<'a> {
+    pub(crate) fn assertion(&self) -> Option<&Assertion<'a>> {
+        match self {
+            Token::Step(step) => step.assertion.as_ref(),
+            Token::Offset(Offset::Character(offset)) => offset.assertion.as_ref(),
+            Token::Offset(Offset::Spatial(offset)) => offset.assertion.as_ref(),
+            Token::Offset(Offset::Temporal(offset)) => offset.assertion.as_ref(),
+            Token::Redirect => None,
+        }
+    }
+
+    pub(crate) fn set_assertion(&mut self, assertion: Assertion<'a>) -> Option<()> {
+        let slot = match self {
+            Token::Step(step) => &mut step.assertion,
+            Token::Offset(Offset::Character(offset)) => &mut offset.assertion,
+            Token::Offset(Offset::Spatial(offset)) => &mut offset.assertion,
+            Token::Offset(Offset::Temporal(offset)) => &mut offset.assertion,
+            Token::Redirect => return None,
+        };
+        *slot = Some(assertion);
+        Some(())
+    }
+}
+
+pub(crate) fn push_path<'a>(tokens: &mut Vec<Token<'a>>, path: &Path<'a>) {
+    tokens.push(Token::Step(path.step.clone()));
+    push_local_path(tokens, &path.local_path);
+}
+
+pub(crate) fn push_local_path<'a>(tokens: &mut Vec<Token<'a>>, local_path: &LocalPath<'a>) {
+    tokens.extend(local_path.steps.iter().cloned().map(Token::Step));
+    match &local_path.terminal {
+        Terminal::None => {}
+        Terminal::Offset(offset) => tokens.push(Token::Offset(offset.clone())),
+        Terminal::Redirect(redirected_path) => {
+            tokens.push(Token::Redirect);
+            match redirected_path {
+                RedirectedPath::Offset(offset) => tokens.push(Token::Offset(offset.clone())),
+                RedirectedPath::Path(path) => push_path(tokens, path),
+            }
+        }
+    }
+}
+
+/// Rebuilds a path from tokens, the inverse of [`push_path`].
+pub(crate) fn path<'a>(mut tokens: Vec<Token<'a>>) -> Option<Path<'a>> {
+    if tokens.is_empty() {
+        return None;
+    }
+    let rest = tokens.split_off(1);
+    match tokens.pop() {
+        Some(Token::Step(step)) => Some(Path::new(step, local_path(rest)?)),
+        _ => None,
+    }
+}
+
+/// Rebuilds a local path from tokens, the inverse of [`push_local_path`].
+pub(crate) fn local_path<'a>(tokens: Vec<Token<'a>>) -> Option<LocalPath<'a>> {
+    let mut steps = vec![];
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Step(step) => steps.push(step),
+            Token::Offset(offset) => {
+                return tokens
+                    .next()
+                    .is_none()
+                    .then(|| LocalPath::new_with_offset(steps, Some(offset)))
+            }
+            Token::Redirect => {
+                let rest: Vec<_> = tokens.collect();
+                let redirected_path = match rest.as_slice() {
+                    [Token::Offset(offset)] => RedirectedPath::Offset(offset.clone()),
+                    _ => RedirectedPath::Path(Box::new(path(rest)?)),
+                };
+                return Some(LocalPath::new_with_redirected_path(steps, redirected_path));
+            }
+        }
+    }
+    Some(LocalPath::new_with_offset(steps, None))
+}
+
+/// Flattens a path into tokens.
+pub(crate) fn flatten<'a>(path: &Path<'a>) -> Vec<Token<'a>> {
+    let mut tokens = vec![];
+    push_path(&mut tokens, path);
+    tokens
+}
+
+/// Removes a terminal offset, and the redirection it directly follows, if any.
+pub(crate) fn strip_offset(tokens: &mut Vec<Token>) {
+    if let Some(Token::Offset(_)) = tokens.last() {
+        tokens.pop();
+    }
+    if let Some(Token::Redirect) = tokens.last() {
+        tokens.pop();
+    }
+}
+
+/// The number of leading tokens that `a` and `b` share. Steps are compared by index only, and
+/// offsets are never shared.
+pub(crate) fn common_length(a: &[Token], b: &[Token]) -> usize {
+    a.iter()
+        .zip(b)
+        .take_while(|pair| match pair {
+            (Token::Step(a), Token::Step(b)) => a.size == b.size,
+            (Token::Redirect, Token::Redirect) => true,
+            _ => false,
+        })
+        .count()
+}
@@ -0,0 +1,189 @@
+//! CFIs in URLs and IRI fragment identifiers.
+//!
+//! A CFI is usually the fragment of a link to a publication, e.g.
+//! `book.epub#epubcfi(/6/4[chap01ref]!/4/2)`, or a query parameter, e.g.
+//! `reader?cfi=epubcfi%28%2F6%2F4%21%2F4%2F2%29`. [`parse_url_fragment`] finds and decodes the CFI
+//! in either, and [`to_url_fragment`] percent-encodes a fragment for use after the `#` of a link.
+
+use std::{borrow::Cow, fmt};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use crate::{syntax::Fragment, ParseError};
+
+/// The characters that may not appear unencoded in a URL fragment, as well as `%` and `^`, which
+/// would otherwise be read as the start of an escape.
+pub(crate) const FRAGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// Finds the CFI in a URL, a fragment identifier with or without its `#`, or a query string, and
+/// parses it. The fragment is searched before the query string. Percent-encoded characters are
+/// decoded, and in a query string a `+` is decoded as a space.
+pub fn parse_url_fragment(input: &str) -> Result<Fragment<'static>, Error> {
+    let (before, fragment) = match input.split_once('#') {
+        Some((before, fragment)) => (before, Some(fragment)),
+        None => (input, None),
+    };
+    let query = before.split_once('?').map(|(_, query)| query);
+    let whole = (fragment.is_none() && query.is_none()).then_some(input);
+    let parts = [
+        fragment.map(Cow::Borrowed),
+        query.map(|query| Cow::Owned(query.replace('+', " "))),
+        whole.map(Cow::Borrowed),
+    ];
+    for part in parts.into_iter().flatten() {
+        let decoded = percent_decode_str(&part)
+            .decode_utf8()
+            .map_err(|_| Error::InvalidEncoding)?;
+        if let Some(cfi) = find_cfi(&decoded) {
+            return Ok(Fragment::parse(cfi)?.into_owned());
+        }
+    }
+    Err(Error::NotFound)
+}
+
+/// Serializes a fragment for use after the `#` of a link, percent-encoding the characters that
+/// are not allowed there, e.g. `epubcfi(/6/4%5Bchap01ref%5D!/4/2)`.
+pub fn to_url_fragment(fragment: &Fragment) -> String {
+    utf8_percent_encode(&fragment.to_string(), FRAGMENT).to_string()
+}
+
+/// The `epubcfi(...)` expression in `input`, up to the first unescaped closing parenthesis, or to
+/// the end of `input` when there is none.
+fn find_cfi(input: &str) -> Option<&str> {
+    let start = input.find("epubcfi(")?;
+    let mut chars = input[start..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '^' => {
+                chars.next();
+            }
+            ')' => return Some(&input[start..start + i + 1]),
+            _ => {}
+        }
+    }
+    Some(&input[start..])
+}
+
+/// Errors that can occur when reading a CFI from a URL.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The URL does not contain an `epubcfi(...)` expression.
+    NotFound,
+    /// The percent-encoded characters do not decode to UTF-8.
+    InvalidEncoding,
+    Parse(ParseError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "no CFI in the URL"),
+            Error::InvalidEncoding => write!(f, "the URL is not percent-encoded UTF-8"),
+            Error::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CFI: &str = "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)";
+
+    #[test]
+    fn test_parse_url_fragment() {
+        let expected = Fragment::parse(CFI).unwrap();
+        for input in [
+            CFI,
+            "#epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)",
+            "book.epub#epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)",
+            "https://example.com/book.epub#epubcfi(/6/4%5Bchap01ref%5D!/4%5Bbody01%5D/10%5Bpara05%5D/3:10)",
+            "https://example.com/read?id=7&cfi=epubcfi%28%2F6%2F4%5Bchap01ref%5D%21%2F4%5Bbody01%5D%2F10%5Bpara05%5D%2F3%3A10%29&page=2",
+            "https://example.com/read?cfi=epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)#top",
+            "epubcfi%28%2F6%2F4%5Bchap01ref%5D%21%2F4%5Bbody01%5D%2F10%5Bpara05%5D%2F3%3A10%29",
+        ] {
+            assert_eq!(parse_url_fragment(input), Ok(expected.clone()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_url_fragment_assertions() {
+        // escaped parentheses do not end the CFI, and `+` is a space in a query string
+        let fragment =
+            parse_url_fragment("read?cfi=epubcfi(/6/4!/4/10/3:4[a+^)b,c^]])&x=1").unwrap();
+        assert_eq!(fragment.to_string(), "epubcfi(/6/4!/4/10/3:4[a ^)b,c^]])");
+        let fragment = parse_url_fragment("book.epub#epubcfi(/6/4!/4/10/3:4[a+b])").unwrap();
+        assert_eq!(fragment.to_string(), "epubcfi(/6/4!/4/10/3:4[a+b])");
+    }
+
+    #[test]
+    fn test_parse_url_fragment_errors() {
+        assert_eq!(
+            parse_url_fragment("https://example.com/book.epub"),
+            Err(Error::NotFound)
+        );
+        assert_eq!(
+            parse_url_fragment("book.epub#chapter1"),
+            Err(Error::NotFound)
+        );
+        assert_eq!(
+            parse_url_fragment("book.epub#epubcfi(%FF)"),
+            Err(Error::InvalidEncoding)
+        );
+        assert!(matches!(
+            parse_url_fragment("book.epub#epubcfi(/6/4"),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_to_url_fragment() {
+        let fragment = Fragment::parse(CFI).unwrap();
+        let encoded = to_url_fragment(&fragment);
+        assert_eq!(
+            encoded,
+            "epubcfi(/6/4%5Bchap01ref%5D!/4%5Bbody01%5D/10%5Bpara05%5D/3:10)"
+        );
+        assert_eq!(
+            parse_url_fragment(&format!("book.epub#{}", encoded)),
+            Ok(fragment)
+        );
+
+        let fragment = Fragment::parse("epubcfi(/6/4!/4/10/3:4[50% off^,#1,café;s=b])").unwrap();
+        let encoded = to_url_fragment(&fragment);
+        assert_eq!(
+            encoded,
+            "epubcfi(/6/4!/4/10/3:4%5B50%25%20off%5E,%231,caf%C3%A9;s=b%5D)"
+        );
+        assert_eq!(parse_url_fragment(&format!("#{}", encoded)), Ok(fragment));
+    }
+}
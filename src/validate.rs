@@ -0,0 +1,179 @@
+//! Batch validation of stored CFIs against a publication.
+//!
+//! Annotations store CFIs that were valid for the version of the book they were created
+//! against. When the book's content changes, a CFI may stop resolving, or resolve to an element
+//! whose id, or to text that, no longer matches the CFI's assertions. [`validate`] checks a batch
+//! of CFIs and reports what each one currently points to.
+
+use std::io::{Read, Seek};
+
+use crate::{
+    epub::{self, Epub},
+    resolve::AssertionMismatch,
+    syntax::Fragment,
+    ParseError,
+};
+
+/// The number of characters of text reported for a point CFI.
+pub const POINT_TEXT_LENGTH: usize = 32;
+
+/// Validates each CFI against `epub`, in order. The package document and each content document
+/// are parsed once for the whole batch.
+pub fn validate<R, S>(epub: &mut Epub<R>, cfis: impl IntoIterator<Item = S>) -> Report
+where
+    R: Read + Seek,
+    S: AsRef<str>,
+{
+    let cfis: Vec<S> = cfis.into_iter().collect();
+    let parsed: Vec<_> = cfis
+        .iter()
+        .map(|cfi| Fragment::parse(cfi.as_ref()))
+        .collect();
+    let fragments: Vec<_> = parsed.iter().filter_map(|p| p.as_ref().ok()).collect();
+    let mut resolved = epub.resolve_all(&fragments).into_iter();
+    let validations = cfis
+        .iter()
+        .zip(parsed)
+        .map(|(cfi, parsed)| {
+            let outcome = match parsed {
+                Err(e) => Outcome::Invalid(e),
+                Ok(_) => match resolved.next().expect("a resolution for every fragment") {
+                    Err(e) => Outcome::Unresolved(e),
+                    Ok(resolved) => Outcome::Resolved {
+                        text: match resolved.end {
+                            Some(_) => resolved.covered_text(),
+                            None => resolved.text_after(POINT_TEXT_LENGTH),
+                        }
+                        .to_string(),
+                        mismatches: resolved.mismatches,
+                    },
+                },
+            };
+            Validation {
+                cfi: cfi.as_ref().to_string(),
+                outcome,
+            }
+        })
+        .collect();
+    Report { validations }
+}
+
+/// The result of [`validate`].
+#[derive(Debug)]
+pub struct Report {
+    pub validations: Vec<Validation>,
+}
+
+impl Report {
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary {
+            total: self.validations.len(),
+            ..Summary::default()
+        };
+        for validation in &self.validations {
+            match &validation.outcome {
+                Outcome::Invalid(_) => summary.invalid += 1,
+                Outcome::Unresolved(_) => summary.unresolved += 1,
+                Outcome::Resolved { mismatches, .. } if !mismatches.is_empty() => {
+                    summary.mismatched += 1
+                }
+                Outcome::Resolved { .. } => summary.valid += 1,
+            }
+        }
+        summary
+    }
+}
+
+/// The validation of a single CFI.
+#[derive(Debug)]
+pub struct Validation {
+    pub cfi: String,
+    pub outcome: Outcome,
+}
+
+impl Validation {
+    /// Whether the CFI resolved and all of its assertions hold.
+    pub fn is_valid(&self) -> bool {
+        matches!(&self.outcome, Outcome::Resolved { mismatches, .. } if mismatches.is_empty())
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    /// The CFI could not be parsed.
+    Invalid(ParseError),
+    /// The CFI could not be resolved against the publication.
+    Unresolved(epub::Error),
+    /// The CFI resolved.
+    Resolved {
+        /// The text covered by a range, or up to [`POINT_TEXT_LENGTH`] characters following a
+        /// point.
+        text: String,
+        /// The id and text location assertions that no longer hold.
+        mismatches: Vec<AssertionMismatch>,
+    },
+}
+
+/// Aggregate counts for a [`Report`]. Each CFI is counted in exactly one of `valid`,
+/// `mismatched`, `unresolved` and `invalid`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub total: usize,
+    pub valid: usize,
+    /// CFIs that resolved but have assertions that no longer hold.
+    pub mismatched: usize,
+    pub unresolved: usize,
+    pub invalid: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{epub::tests::sample, resolve::AssertionKind};
+
+    #[test]
+    fn test_validate() {
+        let mut epub = sample();
+        let report = validate(
+            &mut epub,
+            [
+                "epubcfi(/6/4!/4/4,/1:4,/1:8)",
+                "epubcfi(/6/2!/4/4/1:3)",
+                "epubcfi(/6/4!/4/2[1]/1:0)",
+                "epubcfi(/6/4!/4/8)",
+                "/6/4!/4",
+                "epubcfi(/6/4!/4/4/1:8[rain])",
+                "epubcfi(/6/4!/4/4/1:4[,rain;s=a])",
+                "epubcfi(/6/4!/4/4/1:4[WRONG,TEXT])",
+            ],
+        );
+        assert_eq!(
+            report.summary(),
+            Summary {
+                total: 8,
+                valid: 4,
+                mismatched: 2,
+                unresolved: 1,
+                invalid: 1,
+            }
+        );
+        let validations = &report.validations;
+        assert!(matches!(
+            &validations[0].outcome,
+            Outcome::Resolved { text, .. } if text == "rain"
+        ));
+        assert!(matches!(
+            &validations[1].outcome,
+            Outcome::Resolved { text, .. } if text.starts_with("was a dark and stormy night.")
+        ));
+        assert!(!validations[2].is_valid());
+        assert!(matches!(&validations[3].outcome, Outcome::Unresolved(_)));
+        assert!(matches!(&validations[4].outcome, Outcome::Invalid(_)));
+        assert!(validations[5].is_valid() && validations[6].is_valid());
+        let Outcome::Resolved { mismatches, .. } = &validations[7].outcome else {
+            panic!("expected a resolved CFI");
+        };
+        let kinds: Vec<_> = mismatches.iter().map(|mismatch| mismatch.kind).collect();
+        assert_eq!(kinds, [AssertionKind::TextBefore, AssertionKind::TextAfter]);
+    }
+}
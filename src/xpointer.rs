@@ -0,0 +1,476 @@
+//! Conversion between CFIs and the XPointers that KOReader and CREngine store positions as.
+//!
+//! CREngine loads every spine item of a publication into a single document, with the document
+//! element of each content document replaced by a `DocFragment`. An XPointer walks that document
+//! by element name and position among the siblings of the same name, e.g.
+//! `/body/DocFragment[12]/body/div/p[4]/text().35` is the 35th character of the first text node
+//! of the fourth `p` in the twelfth spine item. As with XPath, the position is omitted when an
+//! element has no siblings of the same name, and positions start at 1.
+//!
+//! CREngine drops the whitespace-only text nodes between block elements, so `text()` positions
+//! do not count whitespace-only text nodes.
+
+use std::{
+    fmt,
+    io::{Read, Seek},
+};
+
+use roxmltree::Node;
+
+use crate::{
+    epub::{self, Epub},
+    resolve::{self, Document, Target},
+    syntax::{Fragment, Path, Step},
+};
+
+/// Converts the position of a point, or the start of a range, to an XPointer.
+pub fn to_xpointer<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    fragment: &Fragment,
+) -> Result<String, Error> {
+    let (start, _) = epub::endpoints(fragment)?;
+    point_to_xpointer(epub, &start)
+}
+
+/// Converts a fragment to the XPointers of its start and end, as KOReader stores the `pos0` and
+/// `pos1` of a highlight. Both are the same for a point.
+pub fn to_xpointer_range<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    fragment: &Fragment,
+) -> Result<(String, String), Error> {
+    let (start, end) = epub::endpoints(fragment)?;
+    Ok((
+        point_to_xpointer(epub, &start)?,
+        point_to_xpointer(epub, &end)?,
+    ))
+}
+
+/// Converts an XPointer to a CFI.
+pub fn from_xpointer<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    xpointer: &str,
+) -> Result<Fragment<'static>, Error> {
+    Ok(Fragment::new(xpointer_to_point(epub, xpointer)?))
+}
+
+/// Converts the XPointers of the start and end of a range, such as the `pos0` and `pos1` of a
+/// KOReader highlight, to a CFI range.
+pub fn from_xpointer_range<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    start: &str,
+    end: &str,
+) -> Result<Fragment<'static>, Error> {
+    let start = xpointer_to_point(epub, start)?;
+    let end = xpointer_to_point(epub, end)?;
+    Ok(epub.range(&start, &end)?)
+}
+
+/// A parsed XPointer.
+#[derive(Debug, PartialEq)]
+struct XPointer<'a> {
+    /// The position of the spine item, starting at 1.
+    doc_fragment: usize,
+    /// The name and position of each element below the `DocFragment`.
+    elements: Vec<(&'a str, usize)>,
+    /// The position of the final text node among the text children of the last element.
+    text: Option<usize>,
+    offset: Option<u32>,
+}
+
+impl<'a> XPointer<'a> {
+    fn parse(input: &'a str) -> Option<Self> {
+        let rest = input.strip_prefix("/body/")?;
+        let (path, offset) = match rest.rsplit_once('.') {
+            Some((path, offset)) if is_number(offset) => (path, Some(offset.parse().ok()?)),
+            _ => (rest, None),
+        };
+        let mut segments = path.split('/').map(segment);
+        let (doc_fragment, doc_fragment_index) = segments.next()??;
+        if doc_fragment != "DocFragment" {
+            return None;
+        }
+        let mut elements = vec![];
+        let mut text = None;
+        for segment in segments {
+            let (name, index) = segment?;
+            if text.is_some() {
+                return None;
+            }
+            match name {
+                "text()" => text = Some(index),
+                name => elements.push((name, index)),
+            }
+        }
+        Some(Self {
+            doc_fragment: doc_fragment_index,
+            elements,
+            text,
+            offset,
+        })
+    }
+}
+
+/// The name and position of a segment, e.g. `p[4]`.
+fn segment(segment: &str) -> Option<(&str, usize)> {
+    let (name, index) = match segment.split_once('[') {
+        Some((name, index)) => {
+            let index = index.strip_suffix(']').filter(|index| is_number(index))?;
+            (name, index.parse().ok()?)
+        }
+        None => (segment, 1),
+    };
+    (!name.is_empty() && index > 0).then_some((name, index))
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn point_to_xpointer<R: Read + Seek>(epub: &mut Epub<R>, path: &Path) -> Result<String, Error> {
+    let resolved = epub.resolve(&Fragment::new(path.clone()))?;
+    let (steps, offset) = epub::content_point(path)?;
+
+    let package = epub.package();
+    let spine = package.spine();
+    let index = spine
+        .iter()
+        .position(|itemref| {
+            package
+                .item(&itemref.idref)
+                .is_some_and(|item| item.href == resolved.href)
+        })
+        .ok_or_else(|| epub::Error::NotInSpine(resolved.href.clone()))?;
+    let doc_fragment = match spine.len() {
+        1 => "DocFragment".to_string(),
+        _ => format!("DocFragment[{}]", index + 1),
+    };
+    let source = epub.read_href(&resolved.href)?;
+    let document = Document::parse(&source).map_err(epub::Error::from)?;
+    let path = document_xpointer(&document, &steps, offset)?;
+    Ok(format!("/body/{}{}", doc_fragment, path))
+}
+
+fn xpointer_to_point<R: Read + Seek>(
+    epub: &mut Epub<R>,
+    xpointer: &str,
+) -> Result<Path<'static>, Error> {
+    let parsed = XPointer::parse(xpointer).ok_or_else(|| Error::Syntax(xpointer.to_string()))?;
+    let href = epub
+        .package()
+        .spine()
+        .get(parsed.doc_fragment - 1)
+        .and_then(|itemref| epub.package().item(&itemref.idref))
+        .map(|item| item.href.clone())
+        .ok_or(Error::NoDocFragment(parsed.doc_fragment))?;
+    let source = epub.read_href(&href)?;
+    let document = Document::parse(&source).map_err(epub::Error::from)?;
+    let (steps, offset) = document_point(&document, &parsed, xpointer)?;
+    Ok(epub.content_path(&href, steps, offset)?)
+}
+
+/// The XPointer, below the `DocFragment`, of the node selected by CFI steps into a content
+/// document and an optional character offset.
+fn document_xpointer(
+    document: &Document,
+    steps: &[Step],
+    offset: Option<u32>,
+) -> Result<String, Error> {
+    let (parent, index) = match document.walk(steps).map_err(epub::Error::from)? {
+        Target::Element(element) => return Ok(element_xpointer(document, element)),
+        Target::Text { parent, index } => (parent, index),
+    };
+    let offset = offset.unwrap_or_default();
+
+    // the text nodes in the run of character data between two element children
+    let mut elements = 0;
+    let run = parent.children().filter(|node| {
+        elements += node.is_element() as usize;
+        node.is_text() && elements == index
+    });
+    let mut remaining = offset as usize;
+    let mut length = 0;
+    for node in run {
+        let chars = node.text().unwrap_or_default().chars().count();
+        length += chars;
+        if remaining <= chars && !is_whitespace(node) {
+            let position = text_nodes(parent)
+                .position(|text| text == node)
+                .unwrap_or(0);
+            let text = match text_nodes(parent).count() {
+                1 => "text()".to_string(),
+                _ => format!("text()[{}]", position + 1),
+            };
+            let element = element_xpointer(document, parent);
+            return Ok(format!("{}/{}.{}", element, text, remaining));
+        }
+        remaining = remaining.saturating_sub(chars);
+    }
+    if offset as usize > length {
+        let error = resolve::Error::OffsetOutOfRange { offset, length };
+        return Err(Error::Epub(error.into()));
+    }
+    // only whitespace, which CREngine drops, so select the element instead
+    Ok(element_xpointer(document, parent))
+}
+
+/// The CFI steps into a content document and optional character offset of the node selected by
+/// an XPointer.
+fn document_point(
+    document: &Document,
+    xpointer: &XPointer,
+    input: &str,
+) -> Result<(Vec<Step<'static>>, Option<u32>), Error> {
+    let missing = || Error::MissingNode(input.to_string());
+    let mut element = document.root_element();
+    for &(name, index) in &xpointer.elements {
+        element = element
+            .children()
+            .filter(|node| node.is_element() && node.tag_name().name() == name)
+            .nth(index - 1)
+            .ok_or_else(missing)?;
+    }
+    let Some(text) = xpointer.text else {
+        return match xpointer.offset {
+            None | Some(0) => Ok((document.steps(Target::Element(element)), None)),
+            Some(_) => Err(Error::Unsupported("offset into an element")),
+        };
+    };
+    let node = text_nodes(element).nth(text - 1).ok_or_else(missing)?;
+    let offset = xpointer.offset.unwrap_or_default();
+    let length = node.text().unwrap_or_default().chars().count();
+    if offset as usize > length {
+        let error = resolve::Error::OffsetOutOfRange { offset, length };
+        return Err(Error::Epub(error.into()));
+    }
+    let index = node.prev_siblings().filter(Node::is_element).count();
+    let before: usize = node
+        .prev_siblings()
+        .skip(1)
+        .take_while(|node| !node.is_element())
+        .filter(Node::is_text)
+        .map(|node| node.text().unwrap_or_default().chars().count())
+        .sum();
+    let target = Target::Text {
+        parent: element,
+        index,
+    };
+    Ok((document.steps(target), Some(before as u32 + offset)))
+}
+
+/// The XPointer of an element below the `DocFragment`, e.g. `/body/div/p[4]`.
+fn element_xpointer(document: &Document, element: Node) -> String {
+    let root = document.root_element();
+    let mut segments: Vec<String> = element
+        .ancestors()
+        .take_while(|node| *node != root)
+        .map(|node| {
+            let same_name = |sibling: &Node| {
+                sibling.is_element() && sibling.tag_name().name() == node.tag_name().name()
+            };
+            let siblings = node
+                .parent()
+                .map_or(1, |p| p.children().filter(same_name).count());
+            let position = node.prev_siblings().filter(same_name).count();
+            match siblings {
+                1 => node.tag_name().name().to_string(),
+                _ => format!("{}[{}]", node.tag_name().name(), position),
+            }
+        })
+        .collect();
+    segments.push(String::new());
+    segments.reverse();
+    segments.join("/")
+}
+
+/// The text children of an element that CREngine keeps.
+fn text_nodes<'a, 'input>(element: Node<'a, 'input>) -> impl Iterator<Item = Node<'a, 'input>> {
+    element
+        .children()
+        .filter(|node| node.is_text() && !is_whitespace(*node))
+}
+
+fn is_whitespace(node: Node) -> bool {
+    node.text()
+        .unwrap_or_default()
+        .chars()
+        .all(char::is_whitespace)
+}
+
+/// Errors that can occur when converting between CFIs and XPointers.
+#[derive(Debug)]
+pub enum Error {
+    Epub(epub::Error),
+    /// The XPointer is not of the form `/body/DocFragment[N]/...`.
+    Syntax(String),
+    /// The publication has no spine item at the `DocFragment` position.
+    NoDocFragment(usize),
+    /// The XPointer selects a node that is not in the content document.
+    MissingNode(String),
+    /// The position cannot be converted, such as a spatial offset in a CFI.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Epub(e) => write!(f, "{}", e),
+            Error::Syntax(xpointer) => write!(f, "invalid XPointer: {}", xpointer),
+            Error::NoDocFragment(index) => write!(f, "no spine item for DocFragment[{}]", index),
+            Error::MissingNode(xpointer) => write!(f, "XPointer selects no node: {}", xpointer),
+            Error::Unsupported(feature) => write!(f, "unsupported position: {}", feature),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Epub(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<epub::Error> for Error {
+    fn from(e: epub::Error) -> Self {
+        Error::Epub(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::tests::sample;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            XPointer::parse("/body/DocFragment[12]/body/div/p[4]/text().35"),
+            Some(XPointer {
+                doc_fragment: 12,
+                elements: vec![("body", 1), ("div", 1), ("p", 4)],
+                text: Some(1),
+                offset: Some(35),
+            })
+        );
+        assert_eq!(
+            XPointer::parse("/body/DocFragment/body/p[2]"),
+            Some(XPointer {
+                doc_fragment: 1,
+                elements: vec![("body", 1), ("p", 2)],
+                text: None,
+                offset: None,
+            })
+        );
+        for invalid in [
+            "",
+            "/body/p/text().3",
+            "/body/DocFragment[0]/body",
+            "/body/DocFragment[2]/body/text()/p",
+            "/body/DocFragment[2]/body/p[x]",
+            "/body/DocFragment[2]//p",
+        ] {
+            assert_eq!(XPointer::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut epub = sample();
+        for (xpointer, cfi) in [
+            (
+                "/body/DocFragment[2]/body/p/text()[1].4",
+                "epubcfi(/6/4!/4/4/1:4)",
+            ),
+            (
+                "/body/DocFragment[2]/body/p/text()[2].1",
+                "epubcfi(/6/4!/4/4/3:1)",
+            ),
+            (
+                "/body/DocFragment[2]/body/p/em/text().3",
+                "epubcfi(/6/4!/4/4/2/1:3)",
+            ),
+            ("/body/DocFragment[1]/body/h1", "epubcfi(/6/2!/4/2)"),
+        ] {
+            let fragment = from_xpointer(&mut epub, xpointer).unwrap();
+            assert_eq!(fragment.to_string(), cfi);
+            assert_eq!(to_xpointer(&mut epub, &fragment).unwrap(), xpointer);
+        }
+
+        let fragment = from_xpointer_range(
+            &mut epub,
+            "/body/DocFragment[2]/body/p/text()[1].4",
+            "/body/DocFragment[2]/body/p/em/text().3",
+        )
+        .unwrap();
+        assert_eq!(fragment.to_string(), "epubcfi(/6/4!/4/4,/1:4,/2/1:3)");
+        assert_eq!(
+            epub.resolve(&fragment).unwrap().covered_text(),
+            "rain fell in tor"
+        );
+        assert_eq!(
+            to_xpointer_range(&mut epub, &fragment).unwrap(),
+            (
+                "/body/DocFragment[2]/body/p/text()[1].4".to_string(),
+                "/body/DocFragment[2]/body/p/em/text().3".to_string(),
+            )
+        );
+    }
+
+    #[test]
+    fn test_whitespace() {
+        let source =
+            "<html><head/><body>\n  <p>one</p>\n  <p>two<!-- note -->three</p>\n</body></html>";
+        let document = Document::parse(source).unwrap();
+        let steps = |sizes: &[u32]| -> Vec<Step> {
+            sizes.iter().map(|&size| Step::new(size, None)).collect()
+        };
+        let xpointer = XPointer::parse("/body/DocFragment/body/p[2]/text()[2].2").unwrap();
+        let (point, offset) = document_point(&document, &xpointer, "").unwrap();
+        assert_eq!((point.clone(), offset), (steps(&[4, 4, 1]), Some(5)));
+        assert_eq!(
+            document_xpointer(&document, &point, offset).unwrap(),
+            "/body/p[2]/text()[2].2"
+        );
+        // a boundary between text nodes is at the end of the first
+        assert_eq!(
+            document_xpointer(&document, &point, Some(3)).unwrap(),
+            "/body/p[2]/text()[1].3"
+        );
+        // whitespace-only text selects its element
+        assert_eq!(
+            document_xpointer(&document, &steps(&[4, 1]), Some(1)).unwrap(),
+            "/body"
+        );
+        assert!(document_xpointer(&document, &point, Some(9)).is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut epub = sample();
+        assert!(matches!(
+            from_xpointer(&mut epub, "/body/p/text().3"),
+            Err(Error::Syntax(_))
+        ));
+        assert!(matches!(
+            from_xpointer(&mut epub, "/body/DocFragment[3]/body"),
+            Err(Error::NoDocFragment(3))
+        ));
+        assert!(matches!(
+            from_xpointer(&mut epub, "/body/DocFragment[2]/body/p[2]"),
+            Err(Error::MissingNode(_))
+        ));
+        assert!(matches!(
+            from_xpointer(&mut epub, "/body/DocFragment[2]/body/h1/text().40"),
+            Err(Error::Epub(epub::Error::Resolve(_)))
+        ));
+        assert!(matches!(
+            from_xpointer_range(
+                &mut epub,
+                "/body/DocFragment[1]/body/h1",
+                "/body/DocFragment[2]/body/h1",
+            ),
+            Err(Error::Epub(epub::Error::RangeAcrossDocuments))
+        ));
+    }
+}
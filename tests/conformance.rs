@@ -0,0 +1,310 @@
+//! Conformance with the examples of the EPUB Canonical Fragment Identifiers 1.1 specification.
+//!
+//! The resolution examples are resolved against the specification's sample package document and
+//! its first chapter, in `tests/spec`.
+
+#![cfg(feature = "epub")]
+
+use std::io::{Cursor, Write};
+
+use epub_cfi::{
+    binary,
+    epub::Epub,
+    syntax::{Fragment, Offset},
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+/// CFIs that parse and serialize back unchanged.
+const VALID: &[&str] = &[
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg])",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/1:0)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/2/1:0)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/2/1:3)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05],/2/1:1,/3:4)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:4[0123,4567])",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/2/1:3[yyy])",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:0[,0123])",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:0[yyy,0123;s=b])",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:0[;s=a])",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para^]05]/3:10)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10[x^,y;x=a,b^;c])",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]@50:50)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]@27.5:)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]~23.5)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]~23.5@5.75:97.6)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3!:4)",
+];
+
+/// CFIs that parse, with the form they serialize to.
+const NORMALIZED: &[(&str, &str)] = &[
+    (
+        "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:0[s=a])",
+        "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:0[;s=a])",
+    ),
+    (
+        "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10[x=a;x=b])",
+        "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10[;x=a,b])",
+    ),
+];
+
+/// Strings that are not CFIs.
+const INVALID: &[&str] = &[
+    "",
+    "epubcfi()",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10",
+    "/6/4[chap01ref]!/4[body01]/10[para05]/3:10",
+    "epubcfi(6/4)",
+    "epubcfi(/6/4[])",
+    "epubcfi(/6/4[,])",
+    "epubcfi(/6/4[para]05])",
+    "epubcfi(/6/4[para^05])",
+    "epubcfi(/6/4[chap01ref])!/4)",
+    "epubcfi(/6/4[chap01ref]/3:10[;s=b)",
+    "epubcfi(/6/4:10/2)",
+    "epubcfi(/6/4@50)",
+    "epubcfi(/6/4~)",
+    "epubcfi(/6/4,/2)",
+    "epubcfi(/6/4[x y=z])",
+    "epubcfi(/5/6[role=chapter]/2[lang=es]/3[role=section];id=sec2)",
+];
+
+/// CFIs in the order of the locations they identify.
+const ORDERED: &[&str] = &[
+    "epubcfi(/6/2[titleref]!/4/2/1:7)",
+    "epubcfi(/6/4[chap01ref])",
+    "epubcfi(/6/4[chap01ref]!/4[body01])",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/2)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05])",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/1:0)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/1:3)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/2/1:0)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/2/1:1)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05],/2/1:1,/3:4)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05],/2/1:1,/3:10)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/2/1:3)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:4)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg])",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]@50:10)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]@10:50)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]@50:50)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]~5)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]~23.5)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]~23.5@50:10)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]~23.5@10:50)",
+    "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]/2)",
+    "epubcfi(/6/6[chap02ref]!/4/2)",
+];
+
+struct Resolution {
+    cfi: &'static str,
+    /// The node the CFI, or the start of its range, selects.
+    node_path: &'static str,
+    offset: Option<u32>,
+    /// The text covered by a range.
+    covered: &'static str,
+    /// How many id assertions do not hold.
+    mismatches: usize,
+}
+
+const RESOLUTIONS: &[Resolution] = &[
+    Resolution {
+        cfi: "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)",
+        node_path: "/html/body/p[5]/text()[2]",
+        offset: Some(10),
+        covered: "",
+        mismatches: 0,
+    },
+    Resolution {
+        cfi: "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg])",
+        node_path: "/html/body/img",
+        offset: None,
+        covered: "",
+        mismatches: 0,
+    },
+    Resolution {
+        cfi: "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/1:0)",
+        node_path: "/html/body/p[5]/text()[1]",
+        offset: Some(0),
+        covered: "",
+        mismatches: 0,
+    },
+    Resolution {
+        cfi: "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/2/1:3)",
+        node_path: "/html/body/p[5]/em/text()[1]",
+        offset: Some(3),
+        covered: "",
+        mismatches: 0,
+    },
+    Resolution {
+        cfi: "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05],/2/1:1,/3:4)",
+        node_path: "/html/body/p[5]/em/text()[1]",
+        offset: Some(1),
+        covered: "yy0123",
+        mismatches: 0,
+    },
+    Resolution {
+        cfi: "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:4[0123,4567])",
+        node_path: "/html/body/p[5]/text()[2]",
+        offset: Some(4),
+        covered: "",
+        mismatches: 0,
+    },
+    Resolution {
+        cfi: "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:0[yyy,0123;s=b])",
+        node_path: "/html/body/p[5]/text()[2]",
+        offset: Some(0),
+        covered: "",
+        mismatches: 0,
+    },
+    Resolution {
+        cfi: "epubcfi(/6/4!/4/10/3:10)",
+        node_path: "/html/body/p[5]/text()[2]",
+        offset: Some(10),
+        covered: "",
+        mismatches: 0,
+    },
+    Resolution {
+        cfi: "epubcfi(/6/4[chap01ref]!/4[body01]/10[para^]05]/3:10)",
+        node_path: "/html/body/p[5]/text()[2]",
+        offset: Some(10),
+        covered: "",
+        mismatches: 1,
+    },
+    Resolution {
+        cfi: "epubcfi(/6/4[chap02ref]!/4[body02]/16[svgimg])",
+        node_path: "/html/body/img",
+        offset: None,
+        covered: "",
+        mismatches: 2,
+    },
+];
+
+const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="EPUB/package.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+/// The specification's sample publication. Only the first chapter has content.
+fn sample() -> Epub<Cursor<Vec<u8>>> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    for (path, content) in [
+        ("mimetype", "application/epub+zip"),
+        ("META-INF/container.xml", CONTAINER),
+        ("EPUB/package.opf", include_str!("spec/package.opf")),
+        ("EPUB/chapter01.xhtml", include_str!("spec/chapter01.xhtml")),
+    ] {
+        writer.start_file(path, options).unwrap();
+        writer.write_all(content.as_bytes()).unwrap();
+    }
+    Epub::from_reader(writer.finish().unwrap()).unwrap()
+}
+
+fn parse(cfi: &str) -> Fragment<'_> {
+    Fragment::parse(cfi).unwrap_or_else(|e| panic!("{}: {}", cfi, e))
+}
+
+#[test]
+fn valid() {
+    for &cfi in VALID {
+        let fragment = parse(cfi);
+        assert_eq!(fragment.to_string(), cfi);
+        assert_eq!(
+            binary::decode(&binary::encode(&fragment)).unwrap(),
+            fragment
+        );
+    }
+    for &(cfi, normalized) in NORMALIZED {
+        assert_eq!(parse(cfi).to_string(), normalized);
+        assert_eq!(parse(cfi), parse(normalized));
+    }
+}
+
+#[test]
+fn invalid() {
+    for &cfi in INVALID {
+        assert!(Fragment::parse(cfi).is_err(), "{} should not parse", cfi);
+    }
+}
+
+#[test]
+fn ordering() {
+    let encoded: Vec<_> = ORDERED
+        .iter()
+        .map(|cfi| binary::encode(&parse(cfi)))
+        .collect();
+    for (i, pair) in encoded.windows(2).enumerate() {
+        assert!(pair[0] < pair[1], "{} < {}", ORDERED[i], ORDERED[i + 1]);
+    }
+}
+
+#[test]
+fn resolution() {
+    let mut epub = sample();
+    for resolution in RESOLUTIONS {
+        let fragment = parse(resolution.cfi);
+        let resolved = epub
+            .resolve(&fragment)
+            .unwrap_or_else(|e| panic!("{}: {}", resolution.cfi, e));
+        assert_eq!(resolved.href, "chapter01.xhtml", "{}", resolution.cfi);
+        assert_eq!(
+            resolved.start.node_path, resolution.node_path,
+            "{}",
+            resolution.cfi
+        );
+        assert_eq!(
+            resolved.start.offset, resolution.offset,
+            "{}",
+            resolution.cfi
+        );
+        assert_eq!(
+            resolved.covered_text(),
+            resolution.covered,
+            "{}",
+            resolution.cfi
+        );
+        assert_eq!(
+            resolved.mismatches.len(),
+            resolution.mismatches,
+            "{}",
+            resolution.cfi
+        );
+
+        // text location assertions hold at the resolved position
+        if let Some(Offset::Character(offset)) = fragment.path().offset() {
+            if let Some(assertion) = &offset.assertion {
+                let before = assertion.value().unwrap_or_default();
+                let after = assertion.after().unwrap_or_default();
+                assert_eq!(resolved.text_before(before.chars().count()), before);
+                assert_eq!(resolved.text_after(after.chars().count()), after);
+            }
+        }
+    }
+}
+
+#[test]
+fn resolution_errors() {
+    let mut epub = sample();
+    for cfi in [
+        // the title page is not in the sample
+        "epubcfi(/6/2[titleref]!/4/2/1:0)",
+        // no content document
+        "epubcfi(/6/4[chap01ref])",
+        // the paragraph's text has ten characters
+        "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:11)",
+        // the body has ten element children
+        "epubcfi(/6/4[chap01ref]!/4[body01]/22)",
+        // the image has no children
+        "epubcfi(/6/4[chap01ref]!/4[body01]/16[svgimg]/2)",
+    ] {
+        assert!(
+            epub.resolve(&parse(cfi)).is_err(),
+            "{} should not resolve",
+            cfi
+        );
+    }
+}